@@ -0,0 +1,667 @@
+use crate::data::Dataset;
+use crate::exec_info::{ExecutionInfo, IntervalStats, RequestStats, ScenarioStats};
+use crate::generator::ScenarioDef;
+use crate::runner::{self, Result, RunConfig};
+use crate::schedule::RateSchedule;
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hdrhistogram::serialization::{Deserializer, Serializer, V2DeflateSerializer};
+use hdrhistogram::Histogram;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use structopt::StructOpt;
+
+// Time for agents to receive the job and set up before the synchronized start
+const START_DELAY: Duration = Duration::from_secs(2);
+
+// Connecting and exchanging messages outside of the run itself
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time for agents to wind down and send their results once the run ended
+const RESULTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run the tests sent by a coordinator started with --agents
+#[derive(Debug, StructOpt)]
+pub struct AgentOpt {
+    /// Address to accept coordinators on, e.g. 0.0.0.0:7878 to accept remote ones
+    #[structopt(long = "listen", default_value = "127.0.0.1:7878")]
+    pub listen: String,
+
+    /// Secret that jobs have to carry, as given to the coordinator with --agent-token
+    #[structopt(
+        long = "token",
+        env = "FLOOD_AGENT_TOKEN",
+        hide_env_values = true,
+        default_value = ""
+    )]
+    pub token: String,
+}
+
+// Messages are JSON documents, one per line
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Message {
+    Job(Box<Job>),
+    Results(Box<Results>),
+    Error(String),
+}
+
+// Share of a run for one agent
+#[derive(Serialize, Deserialize)]
+struct Job {
+    // Must match the --token of the agent
+    token: String,
+    host: String,
+    script: String,
+    num_threads: usize,
+    num_js_threads: usize,
+    request_qsize: usize,
+    num_conn: usize,
+    schedule: RateSchedule,
+    warmup_duration: Duration,
+    duration: Duration,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    arrival_process: String,
+    think_time: String,
+    success_codes: String,
+    report_interval: Option<Duration>,
+    // Rows of every dataset dealt to this agent, by name
+    data: Vec<(String, Vec<Value>)>,
+    // Derived from --seed for every agent
+    seed: Option<u64>,
+    // Wall clock start of warm-up, microseconds since the epoch
+    start_time: u64,
+}
+
+// Histograms are V2 compressed and base64 encoded
+#[derive(Serialize, Deserialize)]
+struct EncodedStats {
+    latency_hist: String,
+    success_count: u32,
+    failure_count: u32,
+    timeout_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedInterval {
+    latency_hist: String,
+    success_count: u32,
+    failure_count: u32,
+    timeout_count: u32,
+    error_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedScenario {
+    name: String,
+    latency_hist: String,
+    passed_count: u32,
+    failed_count: u32,
+    timeout_count: u32,
+    steps: Vec<(String, EncodedStats)>,
+}
+
+// Results of one agent, see ExecutionInfo
+#[derive(Serialize, Deserialize)]
+struct Results {
+    // Wall clock end of warm-up, microseconds since the epoch
+    start_time: u64,
+    type_names: BTreeMap<u32, String>,
+    type_stats: BTreeMap<u32, EncodedStats>,
+    phase_stats: Vec<EncodedStats>,
+    scenario_stats: Vec<EncodedScenario>,
+    intervals: Vec<EncodedInterval>,
+    latency_hist: String,
+    connect_hist: String,
+    send_delay_hist: String,
+    bytes_sent: usize,
+    bytes_recv: usize,
+    request_total: u32,
+    status_counts: BTreeMap<u16, u32>,
+    success_count: u32,
+    failure_count: u32,
+    connect_error_count: u32,
+    reset_count: u32,
+    read_closed_count: u32,
+    write_closed_count: u32,
+    conn_error_count: u32,
+    parse_error_count: u32,
+    timeout_count: u32,
+    late_count: u32,
+    dropped_count: u32,
+}
+
+fn send(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    Ok(())
+}
+
+fn receive(reader: &mut BufReader<TcpStream>) -> Result<Message> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err("Connection closed".into());
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn micros_since_epoch(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+fn encode_hist(hist: &Histogram<u32>) -> Result<String> {
+    let mut buf = Vec::new();
+    V2DeflateSerializer::new()
+        .serialize(hist, &mut buf)
+        .map_err(|err| format!("Failed to serialize histogram: {:?}", err))?;
+    Ok(base64::encode(buf))
+}
+
+// Adds the encoded histogram to `hist`, which resizes as needed
+fn add_encoded_hist(hist: &mut Histogram<u32>, encoded: &str) -> Result<()> {
+    let buf = base64::decode(encoded)?;
+    let other: Histogram<u32> = Deserializer::new()
+        .deserialize(&mut &buf[..])
+        .map_err(|err| format!("Invalid histogram: {:?}", err))?;
+    hist.add(&other)
+        .map_err(|err| format!("Failed to merge histograms: {:?}", err))?;
+    Ok(())
+}
+
+fn encode_stats(stats: &RequestStats) -> Result<EncodedStats> {
+    Ok(EncodedStats {
+        latency_hist: encode_hist(&stats.latency_hist)?,
+        success_count: stats.success_count,
+        failure_count: stats.failure_count,
+        timeout_count: stats.timeout_count,
+    })
+}
+
+fn decode_stats(encoded: &EncodedStats, hist_max: u64) -> Result<RequestStats> {
+    let mut stats = RequestStats::new(hist_max);
+    add_encoded_hist(&mut stats.latency_hist, &encoded.latency_hist)?;
+    stats.success_count = encoded.success_count;
+    stats.failure_count = encoded.failure_count;
+    stats.timeout_count = encoded.timeout_count;
+    Ok(stats)
+}
+
+fn encode_results(exec_info: &ExecutionInfo) -> Result<Results> {
+    Ok(Results {
+        start_time: micros_since_epoch(exec_info.start_wall_time()),
+        type_names: exec_info.type_names.clone(),
+        type_stats: exec_info
+            .type_stats
+            .iter()
+            .map(|(&req_type, stats)| Ok((req_type, encode_stats(stats)?)))
+            .collect::<Result<_>>()?,
+        phase_stats: exec_info
+            .phase_stats
+            .iter()
+            .map(encode_stats)
+            .collect::<Result<_>>()?,
+        scenario_stats: exec_info
+            .scenario_stats
+            .iter()
+            .map(|stats| {
+                Ok(EncodedScenario {
+                    name: stats.name.clone(),
+                    latency_hist: encode_hist(&stats.latency_hist)?,
+                    passed_count: stats.passed_count,
+                    failed_count: stats.failed_count,
+                    timeout_count: stats.timeout_count,
+                    steps: stats
+                        .steps
+                        .iter()
+                        .map(|(name, step)| Ok((name.clone(), encode_stats(step)?)))
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .collect::<Result<_>>()?,
+        intervals: exec_info
+            .intervals
+            .iter()
+            .map(|stats| {
+                Ok(EncodedInterval {
+                    latency_hist: encode_hist(&stats.latency_hist)?,
+                    success_count: stats.success_count,
+                    failure_count: stats.failure_count,
+                    timeout_count: stats.timeout_count,
+                    error_count: stats.error_count,
+                })
+            })
+            .collect::<Result<_>>()?,
+        latency_hist: encode_hist(&exec_info.latency_hist)?,
+        connect_hist: encode_hist(&exec_info.connect_hist)?,
+        send_delay_hist: encode_hist(&exec_info.send_delay_hist)?,
+        bytes_sent: exec_info.bytes_sent,
+        bytes_recv: exec_info.bytes_recv,
+        request_total: exec_info.request_total,
+        status_counts: exec_info.status_counts.clone(),
+        success_count: exec_info.success_count,
+        failure_count: exec_info.failure_count,
+        connect_error_count: exec_info.connect_error_count,
+        reset_count: exec_info.reset_count,
+        read_closed_count: exec_info.read_closed_count,
+        write_closed_count: exec_info.write_closed_count,
+        conn_error_count: exec_info.conn_error_count,
+        parse_error_count: exec_info.parse_error_count,
+        timeout_count: exec_info.timeout_count,
+        late_count: exec_info.late_count,
+        dropped_count: exec_info.dropped_count,
+    })
+}
+
+// Results of one agent as if they came from an event loop of `config`
+fn decode_results(results: &Results, config: &RunConfig) -> Result<ExecutionInfo> {
+    let hist_max = (config.read_timeout + config.write_timeout).as_micros() as u64;
+    let mut exec_info = ExecutionInfo::new(hist_max);
+    exec_info.set_start_wall_time(UNIX_EPOCH + Duration::from_micros(results.start_time));
+    exec_info.set_success_codes(config.success_codes.clone());
+    if !config.arrival_process.is_closed() {
+        exec_info.set_schedule(config.schedule.clone());
+    }
+    if let Some(interval) = config.report_interval {
+        exec_info.set_report_interval(interval);
+    }
+    exec_info.type_names = results.type_names.clone();
+    for (&req_type, stats) in results.type_stats.iter() {
+        exec_info
+            .type_stats
+            .insert(req_type, decode_stats(stats, hist_max)?);
+    }
+    for (phase, encoded) in exec_info
+        .phase_stats
+        .iter_mut()
+        .zip(results.phase_stats.iter())
+    {
+        *phase = decode_stats(encoded, hist_max)?;
+    }
+    for encoded in results.scenario_stats.iter() {
+        let def = ScenarioDef {
+            name: encoded.name.clone(),
+            steps: encoded.steps.iter().map(|(name, _)| name.clone()).collect(),
+        };
+        let mut stats = ScenarioStats::new(&def, hist_max);
+        add_encoded_hist(&mut stats.latency_hist, &encoded.latency_hist)?;
+        stats.passed_count = encoded.passed_count;
+        stats.failed_count = encoded.failed_count;
+        stats.timeout_count = encoded.timeout_count;
+        for ((_, step), (_, encoded_step)) in stats.steps.iter_mut().zip(encoded.steps.iter()) {
+            *step = decode_stats(encoded_step, hist_max)?;
+        }
+        exec_info.scenario_stats.push(stats);
+    }
+    for encoded in results.intervals.iter() {
+        let mut stats = IntervalStats::new();
+        add_encoded_hist(&mut stats.latency_hist, &encoded.latency_hist)?;
+        stats.success_count = encoded.success_count;
+        stats.failure_count = encoded.failure_count;
+        stats.timeout_count = encoded.timeout_count;
+        stats.error_count = encoded.error_count;
+        exec_info.intervals.push(stats);
+    }
+    add_encoded_hist(&mut exec_info.latency_hist, &results.latency_hist)?;
+    add_encoded_hist(&mut exec_info.connect_hist, &results.connect_hist)?;
+    add_encoded_hist(&mut exec_info.send_delay_hist, &results.send_delay_hist)?;
+    exec_info.bytes_sent = results.bytes_sent;
+    exec_info.bytes_recv = results.bytes_recv;
+    exec_info.request_total = results.request_total;
+    exec_info.status_counts = results.status_counts.clone();
+    exec_info.success_count = results.success_count;
+    exec_info.failure_count = results.failure_count;
+    exec_info.connect_error_count = results.connect_error_count;
+    exec_info.reset_count = results.reset_count;
+    exec_info.read_closed_count = results.read_closed_count;
+    exec_info.write_closed_count = results.write_closed_count;
+    exec_info.conn_error_count = results.conn_error_count;
+    exec_info.parse_error_count = results.parse_error_count;
+    exec_info.timeout_count = results.timeout_count;
+    exec_info.late_count = results.late_count;
+    exec_info.dropped_count = results.dropped_count;
+    Ok(exec_info)
+}
+
+/// Splits the run of `config` among `agents` like runner::run splits it among
+/// event loops, starts them together and merges their results.
+pub fn run(config: &RunConfig, agents: &[String], token: &str) -> Result<ExecutionInfo> {
+    let num_agents = agents.len();
+    if config.num_conn < num_agents {
+        return Err(format!(
+            "Need at least one connection per agent ({} connections, {} agents)",
+            config.num_conn, num_agents
+        )
+        .into());
+    }
+    let mut streams = agents
+        .iter()
+        .map(|agent| {
+            connect(agent)
+                .map_err(|err| format!("Failed to connect to agent {}: {}", agent, err).into())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let start_time = micros_since_epoch(SystemTime::now() + START_DELAY);
+    let schedule = config.schedule.scaled(1.0 / num_agents as f64);
+    for (index, stream) in streams.iter_mut().enumerate() {
+        let num_conn =
+            config.num_conn / num_agents + usize::from(index < config.num_conn % num_agents);
+        let job = Job {
+            token: token.to_string(),
+            host: config.host.clone(),
+            script: config.script.clone(),
+            num_threads: config.num_threads,
+            num_js_threads: config.num_js_threads,
+            request_qsize: config.request_qsize,
+            num_conn,
+            schedule: schedule.clone(),
+            warmup_duration: config.warmup_duration,
+            duration: config.duration,
+            connect_timeout: config.connect_timeout,
+            read_timeout: config.read_timeout,
+            write_timeout: config.write_timeout,
+            arrival_process: config.arrival_process.to_string(),
+            think_time: config.think_time.to_string(),
+            success_codes: config.success_codes.to_string(),
+            report_interval: config.report_interval,
+            data: config
+                .data
+                .iter()
+                .map(|dataset| (dataset.name.clone(), deal_rows(dataset, index, num_agents)))
+                .collect(),
+            seed: config
+                .seed
+                .map(|seed| runner::derive_seed(seed, index as u64)),
+            start_time,
+        };
+        send(stream, &Message::Job(Box::new(job)))
+            .map_err(|err| format!("Failed to send job to agent {}: {}", agents[index], err))?;
+        info!(
+            "Agent {} runs {} connections at {:.2} QPS on average",
+            agents[index],
+            num_conn,
+            schedule.mean_rate()
+        );
+    }
+
+    // Agents run concurrently, so waiting for them in turn costs nothing
+    let run_time = START_DELAY
+        + config.warmup_duration
+        + config.duration
+        + config.read_timeout
+        + config.write_timeout
+        + RESULTS_TIMEOUT;
+    let mut merged: Option<ExecutionInfo> = None;
+    for (agent, stream) in agents.iter().zip(streams) {
+        stream.set_read_timeout(Some(run_time))?;
+        let results = match receive(&mut BufReader::new(stream)) {
+            Ok(Message::Results(results)) => results,
+            Ok(Message::Error(err)) => return Err(format!("Agent {}: {}", agent, err).into()),
+            Ok(_) => return Err(format!("Unexpected message from agent {}", agent).into()),
+            Err(err) => {
+                return Err(format!("Failed to receive results of agent {}: {}", agent, err).into())
+            }
+        };
+        let exec_info = decode_results(&results, config)
+            .map_err(|err| format!("Invalid results of agent {}: {}", agent, err))?;
+        match merged.as_mut() {
+            Some(merged) => merged.merge(exec_info),
+            None => merged = Some(exec_info),
+        }
+    }
+    Ok(merged.unwrap())
+}
+
+// Rows of `dataset` dealt out like replayed requests among event loops, so
+// that unique rows stay unique over all agents
+fn deal_rows(dataset: &Dataset, index: usize, num_agents: usize) -> Vec<Value> {
+    if dataset.rows.len() < num_agents {
+        if index == 0 {
+            warn!(
+                "Dataset {} has fewer rows than agents, every agent gets all of them",
+                dataset.name
+            );
+        }
+        return dataset.rows.clone();
+    }
+    dataset
+        .rows
+        .iter()
+        .skip(index)
+        .step_by(num_agents)
+        .cloned()
+        .collect()
+}
+
+fn run_job(job: Job) -> Result<Results> {
+    let addr = job
+        .host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Failed to resolve {}", job.host))?;
+    let config = RunConfig {
+        addr,
+        host: job.host,
+        script: job.script,
+        num_threads: job.num_threads,
+        num_js_threads: job.num_js_threads,
+        request_qsize: job.request_qsize,
+        num_conn: job.num_conn,
+        schedule: job.schedule,
+        warmup_duration: job.warmup_duration,
+        duration: job.duration,
+        connect_timeout: job.connect_timeout,
+        read_timeout: job.read_timeout,
+        write_timeout: job.write_timeout,
+        arrival_process: job.arrival_process.parse()?,
+        think_time: job.think_time.parse()?,
+        replay: None,
+        success_codes: job.success_codes.parse()?,
+        data: Arc::new(
+            job.data
+                .into_iter()
+                .map(|(name, rows)| Dataset::new(&name, rows))
+                .collect(),
+        ),
+        trace: None,
+        trace_sample_ratio: 0.0,
+        report_interval: job.report_interval,
+        start_at: Some(UNIX_EPOCH + Duration::from_micros(job.start_time)),
+        seed: job.seed,
+    };
+    let exec_info = runner::run(&config)?;
+    encode_results(&exec_info)
+}
+
+fn connect(agent: &str) -> Result<TcpStream> {
+    let addr: SocketAddr = agent
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Failed to resolve {}", agent))?;
+    let stream = TcpStream::connect_timeout(&addr, MESSAGE_TIMEOUT)?;
+    stream.set_write_timeout(Some(MESSAGE_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn serve(stream: TcpStream, token: &str) -> Result<()> {
+    // A coordinator holds up the agent for no longer than this without a job
+    stream.set_read_timeout(Some(MESSAGE_TIMEOUT))?;
+    stream.set_write_timeout(Some(MESSAGE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let job = match receive(&mut reader)? {
+        Message::Job(job) => job,
+        _ => return Err("Expected a job".into()),
+    };
+    if job.token != token {
+        send(
+            &mut reader.into_inner(),
+            &Message::Error("Invalid agent token".to_string()),
+        )?;
+        return Err("Rejected a job with an invalid token".into());
+    }
+    info!(
+        "Running {} connections at {:.2} QPS on average against {}",
+        job.num_conn,
+        job.schedule.mean_rate(),
+        job.host
+    );
+    let message = match run_job(*job) {
+        Ok(results) => Message::Results(Box::new(results)),
+        Err(err) => {
+            warn!("Job failed: {}", err);
+            Message::Error(err.to_string())
+        }
+    };
+    send(&mut reader.into_inner(), &message)
+}
+
+// Entry point of `flood agent`, serves one coordinator at a time
+pub fn main(opt: AgentOpt) -> Result<()> {
+    let listener = TcpListener::bind(&opt.listen)
+        .map_err(|err| format!("Failed to listen on {}: {}", opt.listen, err))?;
+    let addr = listener.local_addr()?;
+    if opt.token.is_empty() && !addr.ip().is_loopback() {
+        warn!(
+            "Listening on {} without a --token: anyone who can reach it can make this host \
+             send any script's traffic to any host",
+            addr
+        );
+    }
+    info!("Waiting for coordinators on {}", addr);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept coordinator: {}", err);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+        info!("Coordinator {} connected", peer);
+        if let Err(err) = serve(stream, &opt.token) {
+            warn!("Failed to serve coordinator {}: {}", peer, err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrival::ArrivalProcess;
+    use crate::client::ThinkTime;
+    use crate::http::SuccessCodes;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Answers every request with 200 and counts them
+    fn start_server(served: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let served = served.clone();
+                thread::spawn(move || {
+                    let mut pending = Vec::new();
+                    let mut buf = [0; 4096];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        pending.extend_from_slice(&buf[..n]);
+                        while let Some(end) = pending.windows(4).position(|w| w == b"\r\n\r\n") {
+                            pending.drain(..end + 4);
+                            served.fetch_add(1, Ordering::SeqCst);
+                            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                            if stream.write_all(response).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn start_agent(token: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = serve(stream, token);
+        });
+        addr.to_string()
+    }
+
+    fn config(addr: SocketAddr) -> RunConfig {
+        RunConfig {
+            addr,
+            host: addr.to_string(),
+            script: "function newRequest() { return flood.doGet({ path: '/' }); }".to_string(),
+            num_threads: 1,
+            num_js_threads: 1,
+            request_qsize: 16,
+            num_conn: 4,
+            schedule: RateSchedule::constant(200.0, Duration::from_secs(1)),
+            warmup_duration: Duration::from_secs(0),
+            duration: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            write_timeout: Duration::from_secs(1),
+            arrival_process: ArrivalProcess::Uniform,
+            think_time: ThinkTime::Fixed(Duration::from_secs(0)),
+            replay: None,
+            success_codes: SuccessCodes::default(),
+            data: Arc::new(Vec::new()),
+            trace: None,
+            trace_sample_ratio: 0.0,
+            report_interval: None,
+            start_at: None,
+            seed: None,
+        }
+    }
+
+    #[test]
+    fn merges_results_of_agents() {
+        let served = Arc::new(AtomicUsize::new(0));
+        let config = config(start_server(served.clone()));
+        let agents = vec![start_agent("secret"), start_agent("secret")];
+        let exec_info = run(&config, &agents, "secret").unwrap();
+        let served = served.load(Ordering::SeqCst) as u32;
+        // Each agent sends up to half of the 200 requests, the ones still in
+        // flight at the end are sent but not counted as finished
+        assert_eq!(exec_info.request_total, served);
+        let success_count = exec_info.success_count;
+        assert!(
+            success_count > 150 && success_count <= served,
+            "success {}",
+            success_count
+        );
+        assert_eq!(exec_info.status_counts.get(&200), Some(&success_count));
+        assert_eq!(exec_info.failure_count, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_token() {
+        let served = Arc::new(AtomicUsize::new(0));
+        let config = config(start_server(served.clone()));
+        let agents = vec![start_agent("secret")];
+        let err = match run(&config, &agents, "guess") {
+            Ok(_) => panic!("ran with an invalid token"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("Invalid agent token"), "{}", err);
+        assert_eq!(served.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::report::{self, OutputFormat};
+use crate::runner::Result;
+use crate::trace::{TraceError, TraceReader, TraceRecord};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use log::*;
+use serde::Serialize;
+use structopt::StructOpt;
+
+/// Recompute statistics from a trace saved with --trace-save-path
+#[derive(Debug, StructOpt, Serialize)]
+pub struct AnalyzeOpt {
+    /// Only requests completed at or after this time since the end of warm-up
+    #[structopt(long = "from", default_value = "0s")]
+    pub from: String,
+
+    /// Only requests completed before this time since the end of warm-up, empty for no limit
+    #[structopt(long = "to", default_value = "")]
+    pub to: String,
+
+    /// Only requests of this type
+    #[structopt(long = "type")]
+    pub req_type: Option<u32>,
+
+    /// Width of the latency-over-time buckets
+    #[structopt(long = "bucket", default_value = "1s")]
+    pub bucket: String,
+
+    /// Number of slowest requests to list
+    #[structopt(long = "outliers", default_value = "10")]
+    pub outliers: usize,
+
+    /// Format of the results (text, json or csv)
+    #[structopt(long = "output-format", default_value = "text")]
+    pub output_format: OutputFormat,
+
+    /// Trace file
+    #[structopt(name = "TRACE")]
+    pub trace_path: String,
+}
+
+// Requests completed within one bucket of the analyzed window
+pub struct Bucket {
+    pub latency_hist: Histogram<u32>,
+    pub response_count: u64,
+    pub error_count: u64,
+}
+
+pub struct Analysis {
+    // Wall clock start of the traced run, microseconds since the epoch
+    pub start_time: u64,
+    pub from: Duration,
+    pub to: Option<Duration>,
+    pub bucket: Duration,
+    // Completion time of the last request analyzed, microseconds
+    pub last_finish: u64,
+    pub request_count: u64,
+    pub latency_hist: Histogram<u32>,
+    pub status_counts: BTreeMap<u16, u64>,
+    pub error_counts: BTreeMap<String, u64>,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub buckets: Vec<Bucket>,
+    // Slowest requests, slowest first
+    pub outliers: Vec<TraceRecord>,
+}
+
+impl Bucket {
+    fn new() -> Bucket {
+        // Two significant digits keep long time series small
+        let mut latency_hist = Histogram::<u32>::new(2).unwrap();
+        latency_hist.auto(true);
+        Self {
+            latency_hist,
+            response_count: 0,
+            error_count: 0,
+        }
+    }
+}
+
+impl Analysis {
+    // Length of the analyzed window, up to the last completion if it is open-ended
+    pub fn window(&self) -> Duration {
+        let end = self
+            .to
+            .unwrap_or_else(|| Duration::from_micros(self.last_finish));
+        end.saturating_sub(self.from)
+    }
+
+    fn add(&mut self, record: TraceRecord, max_outliers: usize) {
+        let from = self.from.as_micros() as u64;
+        let index = ((record.finish - from) / self.bucket.as_micros() as u64) as usize;
+        if self.buckets.len() <= index {
+            self.buckets.resize_with(index + 1, Bucket::new);
+        }
+        let bucket = &mut self.buckets[index];
+        let latency = record.latency();
+        if record.error == TraceError::None {
+            bucket.response_count += 1;
+            if bucket.latency_hist.record(latency).is_err() {
+                warn!("Failed to record latency: {}", latency);
+            }
+            if self.latency_hist.record(latency).is_err() {
+                warn!("Failed to record latency: {}", latency);
+            }
+            *self.status_counts.entry(record.status).or_insert(0) += 1;
+        } else {
+            bucket.error_count += 1;
+            *self
+                .error_counts
+                .entry(record.error.to_string())
+                .or_insert(0) += 1;
+        }
+        self.request_count += 1;
+        self.last_finish = self.last_finish.max(record.finish);
+        self.bytes_sent += record.bytes_sent;
+        self.bytes_recv += record.bytes_recv;
+
+        if max_outliers == 0 {
+            return;
+        }
+        if self.outliers.len() == max_outliers
+            && latency <= self.outliers[max_outliers - 1].latency()
+        {
+            return;
+        }
+        let pos = self
+            .outliers
+            .iter()
+            .position(|outlier| outlier.latency() < latency)
+            .unwrap_or(self.outliers.len());
+        self.outliers.insert(pos, record);
+        self.outliers.truncate(max_outliers);
+    }
+}
+
+/// Reads the trace of `opt` record by record, only the outliers are kept.
+pub fn analyze(opt: &AnalyzeOpt) -> Result<Analysis> {
+    let from = humantime::parse_duration(&opt.from)?;
+    let to = if opt.to.is_empty() {
+        None
+    } else {
+        Some(humantime::parse_duration(&opt.to)?)
+    };
+    let bucket = humantime::parse_duration(&opt.bucket)?;
+    // Traces have microsecond timestamps
+    if bucket.as_micros() == 0 {
+        return Err("--bucket must be at least 1us".into());
+    }
+    let reader = TraceReader::open(&opt.trace_path)
+        .map_err(|err| format!("Failed to read {}: {}", opt.trace_path, err))?;
+    let mut latency_hist = Histogram::<u32>::new(3).unwrap();
+    latency_hist.auto(true);
+    let mut analysis = Analysis {
+        start_time: reader.start_time,
+        from,
+        to,
+        bucket,
+        last_finish: from.as_micros() as u64,
+        request_count: 0,
+        latency_hist,
+        status_counts: BTreeMap::new(),
+        error_counts: BTreeMap::new(),
+        bytes_sent: 0,
+        bytes_recv: 0,
+        buckets: Vec::new(),
+        outliers: Vec::new(),
+    };
+    for record in reader {
+        let record = record.map_err(|err| format!("Failed to read {}: {}", opt.trace_path, err))?;
+        let finish = Duration::from_micros(record.finish);
+        if finish < from || to.is_some_and(|to| finish >= to) {
+            continue;
+        }
+        if opt
+            .req_type
+            .is_some_and(|req_type| record.req_type != req_type)
+        {
+            continue;
+        }
+        analysis.add(record, opt.outliers);
+    }
+    Ok(analysis)
+}
+
+// Entry point of `flood analyze`
+pub fn main(opt: AnalyzeOpt) -> Result<()> {
+    let analysis = analyze(&opt)?;
+    report::write_analysis_report(&mut io::stdout().lock(), opt.output_format, &opt, &analysis)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::TraceWriter;
+    use std::env;
+    use std::fs;
+
+    // Request of `req_type` scheduled at `scheduled` and completed `latency`
+    // later, in milliseconds
+    fn record(req_type: u32, scheduled: u64, latency: u64, error: TraceError) -> TraceRecord {
+        TraceRecord {
+            req_type,
+            conn_id: 0,
+            scheduled: scheduled * 1000,
+            start: scheduled * 1000,
+            finish: (scheduled + latency) * 1000,
+            status: if error == TraceError::None { 200 } else { 0 },
+            error,
+            bytes_sent: 10,
+            bytes_recv: 100,
+        }
+    }
+
+    fn analyze_records(name: &str, records: &[TraceRecord], args: &[&str]) -> Analysis {
+        let path = env::temp_dir()
+            .join(format!("flood-{}-{}.trace", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        let writer = TraceWriter::create(&path).unwrap();
+        let mut sink = writer.sender().sink();
+        for record in records.iter() {
+            sink.push(record.clone());
+        }
+        sink.flush();
+        drop(sink);
+        writer.finish().unwrap();
+        let mut argv = vec!["analyze"];
+        argv.extend_from_slice(args);
+        argv.push(&path);
+        let analysis = analyze(&AnalyzeOpt::from_iter(&argv)).unwrap();
+        fs::remove_file(&path).unwrap();
+        analysis
+    }
+
+    #[test]
+    fn analyzes_the_window() {
+        let records = [
+            record(0, 400, 100, TraceError::None),
+            record(0, 900, 100, TraceError::None),
+            record(1, 1400, 100, TraceError::None),
+            record(0, 1900, 50, TraceError::Timeout),
+            record(0, 2000, 50, TraceError::None),
+            record(0, 2900, 100, TraceError::None),
+        ];
+        let cases: &[(&[&str], u64, Duration, Option<Duration>)] = &[
+            (&[], 6, Duration::from_secs(3), None),
+            (&["--from", "1s"], 5, Duration::from_secs(2), None),
+            (
+                &["--from", "1s", "--to", "3s"],
+                4,
+                Duration::from_secs(2),
+                Some(Duration::from_secs(3)),
+            ),
+            (
+                &["--to", "1s"],
+                1,
+                Duration::from_secs(1),
+                Some(Duration::from_secs(1)),
+            ),
+            (&["--type", "1"], 1, Duration::from_millis(1500), None),
+            (&["--from", "5s"], 0, Duration::from_secs(0), None),
+        ];
+        for (i, &(args, count, window, to)) in cases.iter().enumerate() {
+            let analysis = analyze_records(&format!("window-{}", i), &records, args);
+            assert_eq!(analysis.request_count, count, "{:?}", args);
+            assert_eq!(analysis.window(), window, "{:?}", args);
+            if to.is_some() {
+                assert_eq!(analysis.to, to);
+            }
+            assert_eq!(analysis.bytes_sent, count * 10, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn assigns_buckets() {
+        let records = [
+            record(0, 1000, 100, TraceError::None),
+            record(0, 1200, 300, TraceError::None),
+            record(0, 1499, 1, TraceError::Reset),
+            record(0, 2400, 200, TraceError::None),
+            record(0, 2500, 500, TraceError::Timeout),
+        ];
+        let analysis = analyze_records("buckets", &records, &["--from", "1s", "--bucket", "500ms"]);
+        let buckets = analysis
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.response_count, bucket.error_count))
+            .collect::<Vec<_>>();
+        // The bucket of a request is that of its completion
+        assert_eq!(buckets, vec![(1, 0), (1, 1), (0, 0), (1, 0), (0, 1)]);
+        let hist = &analysis.buckets[1].latency_hist;
+        assert!(hist.equivalent(hist.max(), 300_000));
+        assert_eq!(analysis.status_counts.get(&200), Some(&3));
+        assert_eq!(analysis.error_counts.get("reset"), Some(&1));
+        assert_eq!(analysis.error_counts.get("timeout"), Some(&1));
+    }
+
+    #[test]
+    fn keeps_slowest_outliers() {
+        let latencies = [30, 10, 50, 20, 50, 40, 5];
+        let records = latencies
+            .iter()
+            .enumerate()
+            .map(|(i, &latency)| TraceRecord {
+                conn_id: i as u64,
+                ..record(0, 100 * i as u64, latency, TraceError::None)
+            })
+            .collect::<Vec<_>>();
+        let cases: &[(&str, &[u64])] = &[
+            ("0", &[]),
+            ("1", &[2]),
+            ("3", &[2, 4, 5]),
+            ("10", &[2, 4, 5, 0, 3, 1, 6]),
+        ];
+        for (i, &(outliers, conn_ids)) in cases.iter().enumerate() {
+            let analysis = analyze_records(
+                &format!("outliers-{}", i),
+                &records,
+                &["--outliers", outliers],
+            );
+            // Slowest first, ties in the order they completed
+            let found = analysis
+                .outliers
+                .iter()
+                .map(|outlier| outlier.conn_id)
+                .collect::<Vec<_>>();
+            assert_eq!(found, conn_ids, "{}", outliers);
+        }
+    }
+}
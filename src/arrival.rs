@@ -0,0 +1,515 @@
+use crate::schedule::RateSchedule;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Serialize, Serializer};
+
+/// How send times are drawn, parsed from `name` or `name:key=value,...`.
+/// Except for `Trace`, processes follow the rate schedule: their gaps are
+/// measured in expected arrivals at the scheduled rate.
+#[derive(Clone, Debug)]
+pub enum ArrivalProcess {
+    Uniform,
+    Poisson,
+    // Every connection sends its next request once the previous one finished
+    Closed,
+    // Poisson with the rate switching between `on` and `off` times the scheduled
+    // rate, each state lasting an exponential time with the given mean
+    Mmpp {
+        on: f64,
+        off: f64,
+        on_time: Duration,
+        off_time: Duration,
+    },
+    // Heavy-tailed gaps with the same mean as Poisson ones
+    Pareto {
+        shape: f64,
+    },
+    Lognormal {
+        sigma: f64,
+    },
+    // Timestamps in seconds read from `path`, replayed `speed` times faster and
+    // repeated every `period` until the run ends if `repeat` is set
+    Trace {
+        path: String,
+        speed: f64,
+        times: Arc<Vec<f64>>,
+        period: f64,
+        repeat: bool,
+    },
+}
+
+impl ArrivalProcess {
+    // Sends once at the relative `times` of a replayed log
+    pub fn replay(path: &str, times: Vec<f64>, speed: f64) -> ArrivalProcess {
+        ArrivalProcess::Trace {
+            path: path.to_string(),
+            speed,
+            period: times.last().copied().unwrap_or(0.0),
+            times: Arc::new(times),
+            repeat: false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self, ArrivalProcess::Closed)
+    }
+
+    // Share of event loop `index` out of `num_threads`, traces are dealt round-robin
+    pub fn for_thread(&self, index: usize, num_threads: usize) -> ArrivalProcess {
+        match self {
+            ArrivalProcess::Trace {
+                path,
+                speed,
+                times,
+                period,
+                repeat,
+            } => ArrivalProcess::Trace {
+                path: path.clone(),
+                speed: *speed,
+                times: Arc::new(
+                    times
+                        .iter()
+                        .skip(index)
+                        .step_by(num_threads)
+                        .cloned()
+                        .collect(),
+                ),
+                period: *period,
+                repeat: *repeat,
+            },
+            process => process.clone(),
+        }
+    }
+}
+
+fn exp_sample(rng: &mut StdRng) -> f64 {
+    let x: f64 = 1.0 - rng.gen_range(0.0..1.0);
+    -x.ln()
+}
+
+fn normal_sample(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1.0 - rng.gen_range(0.0..1.0);
+    let u2: f64 = rng.gen_range(0.0..1.0);
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Send times of one event loop, in seconds relative to the end of warm-up.
+pub struct Arrivals {
+    process: ArrivalProcess,
+    schedule: RateSchedule,
+    // Current MMPP state and when it ends
+    mmpp_on: bool,
+    state_end: f64,
+    // Index of the next trace timestamp and when the trace started
+    trace_pos: usize,
+    trace_start: f64,
+    rng: StdRng,
+}
+
+impl Arrivals {
+    pub fn new(process: ArrivalProcess, schedule: RateSchedule, rng: StdRng) -> Arrivals {
+        Self {
+            process,
+            schedule,
+            mmpp_on: false,
+            state_end: 0.0,
+            trace_pos: 0,
+            trace_start: 0.0,
+            rng,
+        }
+    }
+
+    fn sojourn(&mut self, on: bool) -> f64 {
+        match self.process {
+            ArrivalProcess::Mmpp {
+                on_time, off_time, ..
+            } => exp_sample(&mut self.rng) * if on { on_time } else { off_time }.as_secs_f64(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// First send at or after `t`, delayed by `offset` of a gap to stagger event loops.
+    pub fn first(&mut self, t: f64, offset: f64) -> Option<f64> {
+        match self.process {
+            ArrivalProcess::Closed => None,
+            ArrivalProcess::Trace { .. } => {
+                self.trace_start = t;
+                self.trace_pos = 0;
+                self.next_trace()
+            }
+            ArrivalProcess::Mmpp {
+                on_time, off_time, ..
+            } => {
+                // Start in the stationary distribution of the two states
+                let p_on = on_time.as_secs_f64() / (on_time + off_time).as_secs_f64();
+                self.mmpp_on = self.rng.gen_range(0.0..1.0) < p_on;
+                self.state_end = t + self.sojourn(self.mmpp_on);
+                self.schedule.advance(t, offset)
+            }
+            _ => self.schedule.advance(t, offset),
+        }
+    }
+
+    /// Send following the one at `t`, None once there are no more.
+    pub fn next(&mut self, t: f64) -> Option<f64> {
+        match self.process {
+            ArrivalProcess::Uniform => self.schedule.advance(t, 1.0),
+            ArrivalProcess::Poisson => self.schedule.advance(t, exp_sample(&mut self.rng)),
+            ArrivalProcess::Closed => None,
+            ArrivalProcess::Mmpp { on, off, .. } => {
+                let mut t = t;
+                loop {
+                    let factor = if self.mmpp_on { on } else { off };
+                    if factor > 0.0 {
+                        let next = self
+                            .schedule
+                            .advance(t, exp_sample(&mut self.rng) / factor)?;
+                        if next <= self.state_end {
+                            return Some(next);
+                        }
+                    }
+                    // Arrivals are memoryless, so the draw restarts at the switch
+                    t = self.state_end;
+                    self.mmpp_on = !self.mmpp_on;
+                    self.state_end = t + self.sojourn(self.mmpp_on);
+                }
+            }
+            ArrivalProcess::Pareto { shape } => {
+                let x: f64 = 1.0 - self.rng.gen_range(0.0..1.0);
+                let scale = (shape - 1.0) / shape;
+                self.schedule.advance(t, scale / x.powf(1.0 / shape))
+            }
+            ArrivalProcess::Lognormal { sigma } => {
+                let gap = (sigma * normal_sample(&mut self.rng) - sigma * sigma / 2.0).exp();
+                self.schedule.advance(t, gap)
+            }
+            ArrivalProcess::Trace { .. } => self.next_trace(),
+        }
+    }
+
+    fn next_trace(&mut self) -> Option<f64> {
+        match &self.process {
+            ArrivalProcess::Trace {
+                speed,
+                times,
+                period,
+                repeat,
+                ..
+            } => {
+                if times.is_empty() || (!repeat && self.trace_pos >= times.len()) {
+                    return None;
+                }
+                let lap = (self.trace_pos / times.len()) as f64;
+                let time = times[self.trace_pos % times.len()];
+                self.trace_pos += 1;
+                Some(self.trace_start + (lap * period + time) / speed)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+// Timestamps relative to the first one, and the period they repeat with
+fn load_trace(path: &str) -> Result<(Vec<f64>, f64), String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let mut times = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let time = line
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid timestamp in {}: {}", path, line))?;
+        if times.last().is_some_and(|&last| time < last) {
+            return Err(format!("Timestamps in {} are not sorted", path));
+        }
+        times.push(time);
+    }
+    if times.len() < 2 {
+        return Err(format!("{} needs at least two timestamps", path));
+    }
+    let first = times[0];
+    let span = times[times.len() - 1] - first;
+    if span <= 0.0 {
+        return Err(format!("Timestamps in {} span no time", path));
+    }
+    // One mean gap separates the last timestamp from the first of the next lap
+    let period = span + span / (times.len() - 1) as f64;
+    Ok((times.iter().map(|time| time - first).collect(), period))
+}
+
+impl FromStr for ArrivalProcess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ArrivalProcess, String> {
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+        let mut values = HashMap::new();
+        for param in params.split(',').filter(|param| !param.trim().is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid parameter `{}` of {}", param, name))?;
+            values.insert(key.trim(), value.trim());
+        }
+        let mut take = |key: &str| values.remove(key);
+        let number = |key: &str, value: Option<&str>, default: f64| match value {
+            Some(value) => match value.parse::<f64>() {
+                Ok(number) if number.is_finite() && number >= 0.0 => Ok(number),
+                _ => Err(format!("Invalid `{}` of {}: {}", key, name, value)),
+            },
+            None => Ok(default),
+        };
+        let duration = |key: &str, value: Option<&str>, default: Duration| match value {
+            Some(value) => match humantime::parse_duration(value) {
+                Ok(d) if !d.is_zero() => Ok(d),
+                _ => Err(format!("Invalid `{}` of {}: {}", key, name, value)),
+            },
+            None => Ok(default),
+        };
+
+        let process = match name {
+            "uniform" => ArrivalProcess::Uniform,
+            "poisson" => ArrivalProcess::Poisson,
+            "closed" => ArrivalProcess::Closed,
+            "mmpp" => {
+                let on = number("on", take("on"), 2.0)?;
+                let off = number("off", take("off"), 0.0)?;
+                if on + off <= 0.0 {
+                    return Err("mmpp needs a positive `on` or `off` rate".to_string());
+                }
+                ArrivalProcess::Mmpp {
+                    on,
+                    off,
+                    on_time: duration("on_time", take("on_time"), Duration::from_secs(1))?,
+                    off_time: duration("off_time", take("off_time"), Duration::from_secs(1))?,
+                }
+            }
+            "pareto" => {
+                let shape = number("shape", take("shape"), 1.5)?;
+                if shape <= 1.0 {
+                    return Err("pareto needs `shape` above 1 to have a mean".to_string());
+                }
+                ArrivalProcess::Pareto { shape }
+            }
+            "lognormal" => ArrivalProcess::Lognormal {
+                sigma: number("sigma", take("sigma"), 1.0)?,
+            },
+            "trace" => {
+                let path = take("path").ok_or("trace needs a `path`")?.to_string();
+                let speed = number("speed", take("speed"), 1.0)?;
+                if speed <= 0.0 {
+                    return Err("trace needs a positive `speed`".to_string());
+                }
+                let repeat = match take("repeat") {
+                    None | Some("true") => true,
+                    Some("false") => false,
+                    Some(value) => return Err(format!("Invalid `repeat` of trace: {}", value)),
+                };
+                let (times, period) = load_trace(&path)?;
+                ArrivalProcess::Trace {
+                    path,
+                    speed,
+                    times: Arc::new(times),
+                    period,
+                    repeat,
+                }
+            }
+            _ => return Err(format!("Unknown arrival process: {}", name)),
+        };
+        if let Some(key) = values.keys().next() {
+            return Err(format!("Unknown parameter `{}` of {}", key, name));
+        }
+        Ok(process)
+    }
+}
+
+impl fmt::Display for ArrivalProcess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArrivalProcess::Uniform => write!(f, "uniform"),
+            ArrivalProcess::Poisson => write!(f, "poisson"),
+            ArrivalProcess::Closed => write!(f, "closed"),
+            ArrivalProcess::Mmpp {
+                on,
+                off,
+                on_time,
+                off_time,
+            } => write!(
+                f,
+                "mmpp:on={},off={},on_time={},off_time={}",
+                on,
+                off,
+                humantime::format_duration(*on_time),
+                humantime::format_duration(*off_time)
+            ),
+            ArrivalProcess::Pareto { shape } => write!(f, "pareto:shape={}", shape),
+            ArrivalProcess::Lognormal { sigma } => write!(f, "lognormal:sigma={}", sigma),
+            ArrivalProcess::Trace {
+                path,
+                speed,
+                repeat,
+                ..
+            } => write!(f, "trace:path={},speed={},repeat={}", path, speed, repeat),
+        }
+    }
+}
+
+impl Serialize for ArrivalProcess {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use std::env;
+
+    #[test]
+    fn parses_what_it_displays() {
+        let path = env::temp_dir().join(format!("flood-{}-arrivals.txt", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        fs::write(&path, "# seconds\n10.0\n10.5\n11.5\n").unwrap();
+        let cases = [
+            ("uniform".to_string(), "uniform".to_string()),
+            ("poisson".to_string(), "poisson".to_string()),
+            ("closed".to_string(), "closed".to_string()),
+            (
+                "mmpp".to_string(),
+                "mmpp:on=2,off=0,on_time=1s,off_time=1s".to_string(),
+            ),
+            (
+                "mmpp: on=3, off=0.5, on_time=2s, off_time=500ms".to_string(),
+                "mmpp:on=3,off=0.5,on_time=2s,off_time=500ms".to_string(),
+            ),
+            ("pareto".to_string(), "pareto:shape=1.5".to_string()),
+            (
+                "pareto:shape=2.5".to_string(),
+                "pareto:shape=2.5".to_string(),
+            ),
+            ("lognormal".to_string(), "lognormal:sigma=1".to_string()),
+            (
+                "lognormal:sigma=0.25".to_string(),
+                "lognormal:sigma=0.25".to_string(),
+            ),
+            (
+                format!("trace:path={}", path),
+                format!("trace:path={},speed=1,repeat=true", path),
+            ),
+            (
+                format!("trace:path={},speed=2.5,repeat=false", path),
+                format!("trace:path={},speed=2.5,repeat=false", path),
+            ),
+        ];
+        for (s, display) in cases.iter() {
+            let process = s.parse::<ArrivalProcess>().unwrap();
+            assert_eq!(&process.to_string(), display);
+            let reparsed = display.parse::<ArrivalProcess>().unwrap();
+            assert_eq!(&reparsed.to_string(), display);
+        }
+        let process = format!("trace:path={}", path)
+            .parse::<ArrivalProcess>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        match process {
+            ArrivalProcess::Trace { times, period, .. } => {
+                assert_eq!(*times, vec![0.0, 0.5, 1.5]);
+                assert_eq!(period, 2.25);
+            }
+            process => panic!("parsed {}", process),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_processes() {
+        let cases = [
+            ("random", "Unknown arrival process: random"),
+            ("poisson:rate=2", "Unknown parameter `rate` of poisson"),
+            ("mmpp:on", "Invalid parameter `on` of mmpp"),
+            ("mmpp:on=0", "mmpp needs a positive `on` or `off` rate"),
+            ("mmpp:on=-1", "Invalid `on` of mmpp: -1"),
+            ("mmpp:on_time=0s", "Invalid `on_time` of mmpp: 0s"),
+            (
+                "pareto:shape=1",
+                "pareto needs `shape` above 1 to have a mean",
+            ),
+            ("lognormal:sigma=inf", "Invalid `sigma` of lognormal: inf"),
+            ("trace", "trace needs a `path`"),
+        ];
+        for (s, message) in cases.iter() {
+            assert_eq!(s.parse::<ArrivalProcess>().unwrap_err(), *message);
+        }
+    }
+
+    // Arrivals over `secs` at 1000 per second
+    fn count_arrivals(process: &str, secs: u64) -> usize {
+        let schedule = RateSchedule::constant(1000.0, Duration::from_secs(secs));
+        let process = process.parse::<ArrivalProcess>().unwrap();
+        let mut arrivals = Arrivals::new(process, schedule, StdRng::seed_from_u64(42));
+        let mut count = 0;
+        let mut t = arrivals.first(0.0, 0.0);
+        // The final rate is kept after the schedule, the run ends it
+        while let Some(time) = t.filter(|&time| time < secs as f64) {
+            count += 1;
+            t = arrivals.next(time);
+        }
+        count
+    }
+
+    fn assert_mean_rate(process: &str, tolerance: f64) {
+        let count = count_arrivals(process, 100) as f64;
+        let expected = 100_000.0;
+        assert!(
+            (count - expected).abs() <= expected * tolerance,
+            "{}: {} arrivals, expected {}",
+            process,
+            count,
+            expected
+        );
+    }
+
+    #[test]
+    fn repeats_seeded_gaps() {
+        let times = |process: &str, seed| {
+            let schedule = RateSchedule::constant(100.0, Duration::from_secs(10));
+            let process = process.parse::<ArrivalProcess>().unwrap();
+            let mut arrivals = Arrivals::new(process, schedule, StdRng::seed_from_u64(seed));
+            let mut t = arrivals.first(0.0, 0.5);
+            (0..200)
+                .map(|_| {
+                    let time = t.unwrap();
+                    t = arrivals.next(time);
+                    time
+                })
+                .collect::<Vec<_>>()
+        };
+        for process in ["poisson", "mmpp", "pareto", "lognormal"].iter() {
+            assert_eq!(times(process, 1), times(process, 1), "{}", process);
+            assert_ne!(times(process, 1), times(process, 2), "{}", process);
+        }
+        assert_eq!(times("uniform", 1), times("uniform", 2));
+    }
+
+    #[test]
+    fn follows_the_scheduled_rate() {
+        assert_eq!(count_arrivals("uniform", 100), 100_000);
+        assert_mean_rate("poisson", 0.02);
+        assert_mean_rate("mmpp:on_time=10ms,off_time=10ms", 0.05);
+        // Half the time at 3x and half at 0.5x the rate make 1.75x on average
+        let count = count_arrivals("mmpp:on=3,off=0.5,on_time=10ms,off_time=10ms", 100) as f64;
+        assert!((count / 175_000.0 - 1.0).abs() <= 0.05, "{}", count);
+        assert_mean_rate("pareto", 0.1);
+        assert_mean_rate("pareto:shape=2.5", 0.02);
+        assert_mean_rate("lognormal", 0.02);
+        assert_mean_rate("lognormal:sigma=2", 0.1);
+    }
+}
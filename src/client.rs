@@ -1,53 +1,141 @@
-use crate::exec_info::ExecutionInfo;
-use crate::generator::{Generator, Request};
+use crate::arrival::{ArrivalProcess, Arrivals};
+use crate::deadline::DeadlineQueue;
+use crate::exec_info::{ConnError, ExecutionInfo, IntervalStats};
+use crate::generator::{Generator, NextStep, Origin, Request};
+use crate::http::{Response, ResponseParser};
+use crate::schedule::RateSchedule;
+use crate::trace::{TraceError, TraceRecord};
 
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::io::{self, ErrorKind, Read, Write};
 use std::net::SocketAddr;
 use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
 
 use bytes::{buf::BufMut, BytesMut};
-use httparse;
-use libc;
 use log::*;
 use mio::{unix::SourceFd, Events, Interest, Poll, Registry, Token};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Serialize, Serializer};
 use timerfd::{SetTimeFlags, TimerFd, TimerState};
 
 #[derive(PartialEq, Clone, Copy)]
 enum ConnectionState {
+    Connecting,
     Idle,
     Sending,
     Receiving,
 }
 
+// Connection ids are unique across event loops, so traces can tell them apart
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// A scenario in progress on a connection
+struct ScenarioRun {
+    session: i32,
+    index: usize,
+    step: usize,
+    // When the scenario was due to start, its latency is measured from here
+    scheduled_time: Instant,
+}
+
 struct Connection {
     state: ConnectionState,
     stream: mio::net::TcpStream,
     token: Token,
+    id: u64,
+    connect_start_time: Instant,
     req_start_time: Option<Instant>,
+    // When the request was due to be sent, latency is measured from here
+    req_scheduled_time: Option<Instant>,
+    // Sampled for the trace when sent, so that samples follow the send order
+    req_traced: bool,
     req: Option<Request>,
+    scenario: Option<ScenarioRun>,
+    // Next step of the scenario, waiting for the think time of the previous one
+    next_req: Option<Request>,
     req_write_pos: usize,
+    req_bytes_recv: usize,
     resp_buf: BytesMut,
+    resp_parser: ResponseParser,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+/// Pause of a closed-loop user between a response and its next request, either
+/// fixed (`100ms`) or exponentially distributed around a mean (`exp:100ms`).
+#[derive(Clone, Copy, Debug)]
+pub enum ThinkTime {
+    Fixed(Duration),
+    Exponential(Duration),
 }
 
-enum ArrivalProcess {
-    Uniform,
-    Poisson,
+impl ThinkTime {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            ThinkTime::Fixed(d) => d,
+            ThinkTime::Exponential(mean) => {
+                let x: f64 = 1.0 - rng.gen_range(0.0..1.0);
+                mean.mul_f64(-x.ln())
+            }
+        }
+    }
+}
+
+impl FromStr for ThinkTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ThinkTime, String> {
+        let parse = |d: &str| humantime::parse_duration(d.trim()).map_err(|err| err.to_string());
+        match s.strip_prefix("exp:") {
+            Some(mean) => Ok(ThinkTime::Exponential(parse(mean)?)),
+            None => Ok(ThinkTime::Fixed(parse(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for ThinkTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ThinkTime::Fixed(d) => write!(f, "{}", humantime::format_duration(d)),
+            ThinkTime::Exponential(mean) => write!(f, "exp:{}", humantime::format_duration(mean)),
+        }
+    }
+}
+
+impl Serialize for ThinkTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
 
 pub struct Client {
     addr: SocketAddr,
     generator: Generator,
     arrival_process: ArrivalProcess,
+    think_time: ThinkTime,
     ev_loop: Poll,
     next_token_id: usize,
     connect_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
+    arrival_offset: f64,
     connections: HashMap<Token, Connection>,
     idle_connections: VecDeque<Token>,
+    deadlines: DeadlineQueue,
+    // Scheduled send times of requests waiting for an idle connection
+    backlog: VecDeque<Instant>,
+    // Closed-loop connections waiting for their think time to end
+    thinking: DeadlineQueue,
+    progress: Option<Sender<(usize, IntervalStats)>>,
+    // Draws think times, and seeds the arrivals of every run
+    rng: StdRng,
 }
 
 impl Connection {
@@ -58,19 +146,27 @@ impl Connection {
         read_timeout: Duration,
         write_timeout: Duration,
     ) -> io::Result<Connection> {
-        let stream = std::net::TcpStream::connect_timeout(addr, connect_timeout)?;
-        stream.set_nonblocking(true)?;
-        stream.set_read_timeout(Some(read_timeout))?;
-        stream.set_write_timeout(Some(write_timeout))?;
-        let mio_stream = mio::net::TcpStream::from_std(stream);
+        let stream = mio::net::TcpStream::connect(*addr)?;
+        let now = Instant::now();
         Ok(Self {
-            state: ConnectionState::Idle,
-            stream: mio_stream,
-            token: token,
+            state: ConnectionState::Connecting,
+            stream,
+            token,
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            connect_start_time: now,
             req_start_time: None,
+            req_scheduled_time: None,
+            req_traced: false,
             req: None,
+            scenario: None,
+            next_req: None,
             req_write_pos: 0,
+            req_bytes_recv: 0,
             resp_buf: BytesMut::with_capacity(4096),
+            resp_parser: ResponseParser::new(),
+            read_timeout,
+            write_timeout,
+            deadline: Some(now + connect_timeout),
         })
     }
 
@@ -78,6 +174,27 @@ impl Connection {
         self.state
     }
 
+    pub fn keep_alive(&self) -> bool {
+        self.resp_parser.keep_alive()
+    }
+
+    // Deadline for the current connect, send or receive phase
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn req_type(&self) -> Option<u32> {
+        self.req.as_ref().map(|req| req.req_type)
+    }
+
+    pub fn req_scheduled_time(&self) -> Option<Instant> {
+        self.req_scheduled_time
+    }
+
+    pub fn req_think_time(&self) -> Option<Duration> {
+        self.req.as_ref().and_then(|req| req.think_time)
+    }
+
     pub fn register(&mut self, registry: &Registry, interests: Interest) -> io::Result<()> {
         registry.register(&mut self.stream, self.token, interests)
     }
@@ -92,14 +209,28 @@ impl Connection {
 
     pub fn state_transition(&mut self, registry: Option<&mio::Registry>) -> io::Result<()> {
         match self.state {
+            ConnectionState::Connecting => {
+                self.state = ConnectionState::Idle;
+                self.deadline = None;
+                Ok(())
+            }
             ConnectionState::Idle => {
                 self.state = ConnectionState::Sending;
                 self.req_write_pos = 0;
-                self.req_start_time = Some(Instant::now());
+                self.req_bytes_recv = 0;
+                let now = Instant::now();
+                self.req_start_time = Some(now);
+                self.deadline = Some(now + self.write_timeout);
+                let req = self.req.as_ref();
+                self.resp_parser.reset(
+                    req.is_some_and(|req| req.is_head),
+                    req.is_some_and(|req| req.origin.is_some()) || self.scenario.is_some(),
+                );
                 Ok(())
             }
             ConnectionState::Sending => {
                 self.state = ConnectionState::Receiving;
+                self.deadline = Some(Instant::now() + self.read_timeout);
                 self.reregister(registry.unwrap(), Interest::READABLE)
             }
             ConnectionState::Receiving => {
@@ -107,20 +238,101 @@ impl Connection {
                 self.req = None;
                 self.resp_buf.clear();
                 self.req_start_time = None;
+                self.req_scheduled_time = None;
+                self.deadline = None;
                 self.reregister(registry.unwrap(), Interest::WRITABLE)
             }
         }
     }
 
+    // Trace the request in flight, if any, as finished now
+    pub fn trace(&self, exec_info: &mut ExecutionInfo, status: u16, error: TraceError) {
+        let (req, scheduled_time, start_time) = match (
+            self.req.as_ref(),
+            self.req_scheduled_time,
+            self.req_start_time,
+        ) {
+            (Some(req), Some(scheduled_time), Some(start_time)) => {
+                (req, scheduled_time, start_time)
+            }
+            _ => return,
+        };
+        if !self.req_traced {
+            return;
+        }
+        let record = TraceRecord {
+            req_type: req.req_type,
+            conn_id: self.id,
+            scheduled: exec_info.trace_timestamp(scheduled_time),
+            start: exec_info.trace_timestamp(start_time),
+            finish: exec_info.trace_timestamp(Instant::now()),
+            status,
+            error,
+            bytes_sent: self.req_write_pos as u64,
+            bytes_recv: self.req_bytes_recv as u64,
+        };
+        exec_info.trace(record);
+    }
+
+    // Count a connection error, failing the request in flight
+    pub fn fail(&self, exec_info: &mut ExecutionInfo, kind: ConnError) {
+        exec_info.connection_error(kind);
+        self.trace(exec_info, 0, kind.into());
+    }
+
+    // The completed response, if the script wants to see it
+    pub fn take_response(&mut self) -> Option<(Origin, Response)> {
+        let origin = self.req.as_mut()?.origin.take()?;
+        Some((origin, self.resp_parser.take_response()))
+    }
+
+    // The scenario in progress and the response to its current step
+    fn take_scenario(&mut self) -> Option<(ScenarioRun, Response)> {
+        let run = self.scenario.take()?;
+        Some((run, self.resp_parser.take_response()))
+    }
+
+    // Whether the connection is between two steps of a scenario
+    pub fn in_scenario(&self) -> bool {
+        self.scenario.is_some()
+    }
+
+    pub fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        self.stream.take_error()
+    }
+
+    // Returns true once the non-blocking connect has completed
+    pub fn finish_connect(&mut self, exec_info: &mut ExecutionInfo) -> io::Result<bool> {
+        assert!(self.state == ConnectionState::Connecting);
+        if let Some(err) = self.stream.take_error()? {
+            exec_info.connection_error(ConnError::Connect);
+            return Err(err);
+        }
+        match self.stream.peer_addr() {
+            Ok(_) => {
+                exec_info.connection_established(self.connect_start_time, Instant::now());
+                Ok(true)
+            }
+            Err(err) if err.kind() == ErrorKind::NotConnected => Ok(false),
+            Err(err) => {
+                exec_info.connection_error(ConnError::Connect);
+                Err(err)
+            }
+        }
+    }
+
     pub fn do_request(
         &mut self,
-        generator: &mut Generator,
+        req: Request,
         exec_info: &mut ExecutionInfo,
+        scheduled_time: Instant,
     ) -> io::Result<bool> {
         assert!(self.state == ConnectionState::Idle);
-        self.req = Some(generator.get());
+        self.req = Some(req);
+        self.req_scheduled_time = Some(scheduled_time);
+        self.req_traced = exec_info.should_trace(scheduled_time);
         self.state_transition(None)?;
-        exec_info.new_request(self.req_start_time.unwrap());
+        exec_info.new_request(scheduled_time, self.req_start_time.unwrap());
         self.write_request(exec_info)
     }
 
@@ -128,21 +340,20 @@ impl Connection {
         assert!(self.state == ConnectionState::Sending);
         let data = &self.req.as_ref().unwrap().input;
         assert!(self.req_write_pos < data.len());
-        loop {
+        while self.req_write_pos < data.len() {
             match self.stream.write(data.slice(self.req_write_pos..).as_ref()) {
                 Ok(nwrite) => {
                     self.req_write_pos += nwrite;
                     exec_info.inc_bytes_send(nwrite);
-                    break;
                 }
-                Err(err) => {
-                    if err.kind() == ErrorKind::Interrupted {
-                        continue;
-                    } else {
-                        exec_info.connection_error();
+                Err(err) => match err.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        self.fail(exec_info, ConnError::from_io(&err));
                         return Err(err);
                     }
-                }
+                },
             }
         }
         Ok(self.req_write_pos == data.len())
@@ -151,55 +362,55 @@ impl Connection {
     pub fn recv_response(&mut self, exec_info: &mut ExecutionInfo) -> io::Result<bool> {
         assert!(self.state == ConnectionState::Receiving);
         let mut buf = [0; 4096];
-        loop {
+        let mut eof = false;
+        while !self.resp_parser.is_done() {
             match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
                 Ok(nread) => {
                     exec_info.inc_bytes_recv(nread);
+                    self.req_bytes_recv += nread;
                     while self.resp_buf.remaining_mut() < nread {
                         self.resp_buf.reserve(self.resp_buf.len());
                     }
                     self.resp_buf.put_slice(&buf[0..nread]);
-                    if nread < buf.len() {
-                        break;
+                    if let Err(err) = self.resp_parser.parse(&mut self.resp_buf) {
+                        exec_info.parse_error();
+                        self.trace(exec_info, 0, TraceError::Parse);
+                        return Err(io::Error::other(format!("HTTP parsing failed: {}", err)));
                     }
                 }
-                Err(err) => {
-                    let errno = err.raw_os_error().expect("No errno, WTF??");
-                    if errno == libc::EAGAIN {
-                        break;
-                    } else if errno == libc::EINTR {
-                        continue;
-                    } else {
-                        exec_info.connection_error();
+                Err(err) => match err.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        self.fail(exec_info, ConnError::from_io(&err));
                         return Err(err);
                     }
-                }
+                },
             }
         }
 
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut req = httparse::Response::new(&mut headers);
-        match req.parse(&self.resp_buf[..]) {
-            Ok(result) => {
-                if result.is_partial() {
-                    return Ok(false);
-                }
-            }
-            Err(err) => {
-                exec_info.parse_error();
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    format!("HTTP parsing failed: {}", err),
-                ));
-            }
+        if eof && !self.resp_parser.parse_eof() {
+            self.fail(exec_info, ConnError::ReadClosed);
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before response completed",
+            ));
         }
-
-        let req_type = self.req.as_ref().unwrap().req_type;
-        if req.code.unwrap() == 200 {
-            exec_info.request_finished(req_type, self.req_start_time.unwrap(), Instant::now());
-        } else {
-            exec_info.request_failed(req_type, self.req_start_time.unwrap(), Instant::now());
+        if !self.resp_parser.is_done() {
+            return Ok(false);
         }
+
+        exec_info.request_finished(
+            self.req.as_ref().unwrap().req_type,
+            self.resp_parser.status(),
+            self.req_scheduled_time.unwrap(),
+            Instant::now(),
+        );
+        self.trace(exec_info, self.resp_parser.status(), TraceError::None);
         Ok(true)
     }
 }
@@ -207,16 +418,23 @@ impl Connection {
 impl Client {
     pub fn new(addr: &SocketAddr, generator: Generator) -> Client {
         Self {
-            addr: addr.clone(),
-            generator: generator,
-            arrival_process: ArrivalProcess::Uniform,
+            addr: *addr,
+            generator,
+            arrival_process: ArrivalProcess::Poisson,
+            think_time: ThinkTime::Fixed(Duration::ZERO),
             ev_loop: Poll::new().expect("Failed to create event loop"),
             next_token_id: 0,
             connect_timeout: Duration::from_secs(1),
             read_timeout: Duration::from_secs(1),
             write_timeout: Duration::from_secs(1),
+            arrival_offset: 0.0,
             connections: HashMap::<Token, Connection>::new(),
             idle_connections: VecDeque::<Token>::with_capacity(128),
+            deadlines: DeadlineQueue::new(),
+            backlog: VecDeque::<Instant>::with_capacity(1024),
+            thinking: DeadlineQueue::new(),
+            progress: None,
+            rng: StdRng::from_entropy(),
         }
     }
 
@@ -232,13 +450,237 @@ impl Client {
         self.write_timeout = d;
     }
 
-    pub fn set_arrival_process(&mut self, s: &str) {
-        if s == "uniform" {
-            self.arrival_process = ArrivalProcess::Uniform;
-        } else if s == "poisson" {
-            self.arrival_process = ArrivalProcess::Poisson;
+    // Delay of the first scheduled send as a fraction of the gap between arrivals
+    pub fn set_arrival_offset(&mut self, fraction: f64) {
+        self.arrival_offset = fraction;
+    }
+
+    // Finished reporting intervals are sent here during the run
+    pub fn set_progress_sender(&mut self, sender: Sender<(usize, IntervalStats)>) {
+        self.progress = Some(sender);
+    }
+
+    fn report_progress(&self, exec_info: &mut ExecutionInfo) {
+        if let Some(sender) = self.progress.as_ref() {
+            for interval in exec_info.take_finished_intervals(Instant::now()) {
+                // The receiver going away only stops progress output
+                let _ = sender.send(interval);
+            }
+        }
+    }
+
+    pub fn set_arrival_process(&mut self, arrival_process: ArrivalProcess) {
+        self.arrival_process = arrival_process;
+    }
+
+    pub fn set_think_time(&mut self, think_time: ThinkTime) {
+        self.think_time = think_time;
+    }
+
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    fn closed_loop(&self) -> bool {
+        self.arrival_process.is_closed()
+    }
+
+    // Send backlogged requests in schedule order while idle connections are available
+    fn dispatch_backlog(&mut self, exec_info: &mut ExecutionInfo) -> std::io::Result<()> {
+        while !self.backlog.is_empty() {
+            let conn_token = match self.idle_connections.pop_front() {
+                Some(conn_token) => conn_token,
+                None => break,
+            };
+            match self.connections.get(&conn_token) {
+                Some(connection)
+                    if connection.state() == ConnectionState::Idle && !connection.in_scenario() => {
+                }
+                _ => continue,
+            }
+            let scheduled_time = self.backlog.pop_front().unwrap();
+            self.start_request(exec_info, conn_token, scheduled_time)?;
+        }
+        Ok(())
+    }
+
+    // Closed-loop connections whose think time is over send their next request
+    fn dispatch_thinking(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        now: Instant,
+    ) -> std::io::Result<()> {
+        while let Some((token, scheduled_time)) = self.thinking.pop_expired(now) {
+            match self.connections.get(&token) {
+                Some(connection) if connection.state() == ConnectionState::Idle => {}
+                _ => continue,
+            }
+            self.start_request(exec_info, token, scheduled_time)?;
+        }
+        Ok(())
+    }
+
+    fn start_request(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        token: Token,
+        scheduled_time: Instant,
+    ) -> std::io::Result<()> {
+        let connection = self.connections.get_mut(&token).unwrap();
+        let req = match connection.next_req.take() {
+            Some(req) => req,
+            None if self.generator.has_scenarios() => match self.generator.start_scenario() {
+                Ok((index, Ok((session, req)))) => {
+                    connection.scenario = Some(ScenarioRun {
+                        session,
+                        index,
+                        step: 0,
+                        scheduled_time,
+                    });
+                    req
+                }
+                Ok((index, Err(err))) => {
+                    warn!("Scenario failed to start: {}", err);
+                    exec_info.scenario_aborted(index, 0, scheduled_time, false);
+                    self.skip_request(token);
+                    return Ok(());
+                }
+                Err(err) => {
+                    error!("Failed to start a scenario: {}", err);
+                    self.skip_request(token);
+                    return Ok(());
+                }
+            },
+            None => match self.generator.get() {
+                Ok(req) => req,
+                Err(err) => {
+                    warn!("Script failed to make a request: {}", err);
+                    exec_info.request_dropped(scheduled_time);
+                    self.skip_request(token);
+                    return Ok(());
+                }
+            },
+        };
+        match connection.do_request(req, exec_info, scheduled_time) {
+            Ok(true) => {
+                self.advance_connection(token)?;
+            }
+            Ok(false) => {
+                self.schedule_deadline(token);
+            }
+            Err(err) => {
+                error!("Connection with {:?} failed: {}", token, err);
+                self.replace_connection(exec_info, token)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The connection sent nothing for its arrival, it is left idle without
+    // recursing into the backlog, which its caller dispatches
+    fn skip_request(&mut self, token: Token) {
+        if self.closed_loop() {
+            let think_time = self.think_time.sample(&mut self.rng);
+            self.thinking.push(token, Instant::now() + think_time);
+        } else {
+            self.idle_connections.push_back(token);
+        }
+    }
+
+    // A response completed: the scenario of the connection goes on with its next
+    // step, otherwise the connection is free for the next request
+    fn response_done(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        token: Token,
+    ) -> std::io::Result<()> {
+        let connection = self.connections.get_mut(&token).unwrap();
+        if let Some((origin, response)) = connection.take_response() {
+            self.generator.on_response(origin, response);
+        }
+        let think_time = connection.req_think_time();
+        if let Some((mut run, response)) = connection.take_scenario() {
+            let now = Instant::now();
+            let next = if exec_info.success_codes().contains(response.status) {
+                match self.generator.next_step(run.session, response) {
+                    Ok(next) => next,
+                    Err(err) => {
+                        warn!("Scenario step failed: {}", err);
+                        self.generator.end_scenario(run.session);
+                        NextStep::Done(false)
+                    }
+                }
+            } else {
+                self.generator.end_scenario(run.session);
+                NextStep::Done(false)
+            };
+            exec_info.scenario_step(
+                run.index,
+                run.step,
+                run.scheduled_time,
+                connection.req_scheduled_time().unwrap(),
+                now,
+                !matches!(next, NextStep::Done(false)),
+            );
+            match next {
+                NextStep::Request(req) if connection.keep_alive() => {
+                    run.step += 1;
+                    connection.scenario = Some(run);
+                    connection.next_req = Some(req);
+                    self.advance_connection(token)?;
+                    return match think_time {
+                        Some(think_time) if !think_time.is_zero() => {
+                            self.thinking.push(token, now + think_time);
+                            Ok(())
+                        }
+                        _ => self.start_request(exec_info, token, now),
+                    };
+                }
+                NextStep::Request(_) => {
+                    // Steps run on one connection, this one is closing
+                    self.generator.end_scenario(run.session);
+                    exec_info.scenario_aborted(run.index, run.step + 1, run.scheduled_time, false);
+                }
+                NextStep::Done(passed) => {
+                    exec_info.scenario_finished(run.index, run.scheduled_time, now, passed);
+                }
+            }
+        }
+        if connection.keep_alive() {
+            let think_time = think_time.unwrap_or_else(|| self.think_time.sample(&mut self.rng));
+            self.advance_connection(token)?;
+            if self.closed_loop() {
+                self.connection_idle(exec_info, token, think_time)?;
+            }
+            Ok(())
         } else {
-            panic!("Unknown arrival process: {}", s);
+            self.replace_connection(exec_info, token)
+        }
+    }
+
+    // The scenario of a failed connection ends with the step in flight
+    fn abort_scenario(&mut self, exec_info: &mut ExecutionInfo, token: Token, timed_out: bool) {
+        let connection = self.connections.get_mut(&token).unwrap();
+        connection.next_req = None;
+        if let Some(run) = connection.scenario.take() {
+            self.generator.end_scenario(run.session);
+            exec_info.scenario_aborted(run.index, run.step, run.scheduled_time, timed_out);
+        }
+    }
+
+    // A connection became idle, `think_time` is how long its user waits before the next request
+    fn connection_idle(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        token: Token,
+        think_time: Duration,
+    ) -> std::io::Result<()> {
+        if self.closed_loop() {
+            self.thinking.push(token, Instant::now() + think_time);
+            Ok(())
+        } else {
+            self.idle_connections.push_back(token);
+            self.dispatch_backlog(exec_info)
         }
     }
 
@@ -259,6 +701,7 @@ impl Client {
         )?;
         connection.register(self.ev_loop.registry(), Interest::WRITABLE)?;
         self.connections.insert(token, connection);
+        self.schedule_deadline(token);
         info!(
             "Create new connection, total number is {}",
             self.connections.len()
@@ -266,7 +709,64 @@ impl Client {
         Ok(())
     }
 
-    fn connection_failed(&mut self, token: Token) -> std::io::Result<()> {
+    fn schedule_deadline(&mut self, token: Token) {
+        if let Some(deadline) = self.connections.get(&token).and_then(|c| c.deadline()) {
+            self.deadlines.push(token, deadline);
+        }
+    }
+
+    // Advance the connection to its next state and arm the deadline of that state
+    fn advance_connection(&mut self, token: Token) -> std::io::Result<()> {
+        let connection = self.connections.get_mut(&token).unwrap();
+        connection.state_transition(Some(self.ev_loop.registry()))?;
+        self.schedule_deadline(token);
+        Ok(())
+    }
+
+    fn expire_deadlines(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        now: Instant,
+    ) -> std::io::Result<()> {
+        while let Some((token, deadline)) = self.deadlines.pop_expired(now) {
+            let connection = match self.connections.get(&token) {
+                Some(connection) if connection.deadline() == Some(deadline) => connection,
+                _ => continue,
+            };
+            match connection.state() {
+                ConnectionState::Connecting => {
+                    warn!("Connection with {:?} exceeded connect timeout", token);
+                    exec_info.connection_error(ConnError::Connect);
+                }
+                ConnectionState::Sending => {
+                    warn!("Request on {:?} exceeded write timeout", token);
+                    exec_info.request_timeout(
+                        connection.req_type().unwrap(),
+                        connection.req_scheduled_time().unwrap(),
+                    );
+                    connection.trace(exec_info, 0, TraceError::Timeout);
+                }
+                _ => {
+                    warn!("Request on {:?} exceeded read timeout", token);
+                    exec_info.request_timeout(
+                        connection.req_type().unwrap(),
+                        connection.req_scheduled_time().unwrap(),
+                    );
+                    connection.trace(exec_info, 0, TraceError::Timeout);
+                }
+            }
+            self.abort_scenario(exec_info, token, true);
+            self.replace_connection(exec_info, token)?;
+        }
+        Ok(())
+    }
+
+    fn replace_connection(
+        &mut self,
+        exec_info: &mut ExecutionInfo,
+        token: Token,
+    ) -> std::io::Result<()> {
+        self.abort_scenario(exec_info, token, false);
         let connection = self.connections.get_mut(&token).unwrap();
         connection.deregister(self.ev_loop.registry())?;
         self.connections.remove(&token);
@@ -276,8 +776,8 @@ impl Client {
     pub fn run(
         &mut self,
         exec_info: &mut ExecutionInfo,
-        num_connections: i32,
-        qps: i32,
+        num_connections: usize,
+        schedule: &RateSchedule,
         warmup_duration: Duration,
         duration: Duration,
     ) -> std::io::Result<()> {
@@ -285,23 +785,36 @@ impl Client {
             self.create_connection()?;
         }
 
-        let mut tfd = TimerFd::new()?;
-        match self.arrival_process {
-            ArrivalProcess::Uniform => {
-                tfd.set_state(
-                    TimerState::Periodic {
-                        current: Duration::from_millis(100),
-                        interval: Duration::from_nanos(1_000_000_000 / (qps as u64)),
-                    },
-                    SetTimeFlags::Default,
-                );
-            }
-            ArrivalProcess::Poisson => {
-                tfd.set_state(
-                    TimerState::Oneshot(Duration::from_millis(100)),
-                    SetTimeFlags::Default,
-                );
+        let now = Instant::now();
+        let start_time = now + warmup_duration;
+        exec_info.set_initial_time(start_time);
+        let finish_time = start_time + duration;
+
+        // Send times are kept in seconds relative to `start_time`, negative during warm-up
+        let send_time = |secs: f64| {
+            if secs >= 0.0 {
+                start_time + Duration::from_secs_f64(secs)
+            } else {
+                start_time - Duration::from_secs_f64(-secs)
             }
+        };
+        let first_send = 0.1 - warmup_duration.as_secs_f64();
+        let mut arrivals = Arrivals::new(
+            self.arrival_process.clone(),
+            schedule.clone(),
+            StdRng::seed_from_u64(self.rng.gen()),
+        );
+        let mut next_send = arrivals.first(first_send, self.arrival_offset);
+
+        // The timer only wakes up the loop, send times are computed from the schedule
+        // so that queueing delay shows up in the measured latency
+        let mut tfd = TimerFd::new()?;
+        if let Some(secs) = next_send {
+            let d = send_time(secs).saturating_duration_since(Instant::now());
+            tfd.set_state(
+                TimerState::Oneshot(d.max(Duration::from_nanos(1))),
+                SetTimeFlags::Default,
+            );
         }
 
         let raw_fd = tfd.as_raw_fd();
@@ -311,18 +824,20 @@ impl Client {
             .registry()
             .register(&mut sfd, timer_token, Interest::READABLE)?;
 
-        let now = Instant::now();
-        let start_time = now + warmup_duration;
-        exec_info.set_initial_time(start_time);
-        let finish_time = start_time + duration;
-
         let mut events = Events::with_capacity(1024);
 
         while Instant::now() <= finish_time {
-            match self
-                .ev_loop
-                .poll(&mut events, Some(Duration::from_millis(100)))
+            let mut poll_timeout = Duration::from_millis(100);
+            for deadline in [
+                self.deadlines.next_deadline(),
+                self.thinking.next_deadline(),
+            ]
+            .iter()
+            .flatten()
             {
+                poll_timeout = poll_timeout.min(deadline.saturating_duration_since(Instant::now()));
+            }
+            match self.ev_loop.poll(&mut events, Some(poll_timeout)) {
                 Ok(()) => {}
                 Err(err) => match err.kind() {
                     ErrorKind::Interrupted => {
@@ -339,42 +854,48 @@ impl Client {
             for event in &events {
                 let token = event.token();
                 if token == timer_token {
-                    let tfd_value = tfd.read();
-                    if tfd_value > 1 {
-                        warn!("Missing {} timer expires", tfd_value - 1);
-                    }
-                    match self.arrival_process {
-                        ArrivalProcess::Poisson => {
-                            let x: f64 = rand::thread_rng().gen_range(0.0..1.0);
-                            let interval = -x.ln() * 1e9 / (qps as f64);
-                            let d = Duration::from_nanos(interval as u64);
-                            tfd.set_state(TimerState::Oneshot(d), SetTimeFlags::Default);
-                        }
-                        _ => {}
-                    }
-                    let mut request_done = false;
-                    while let Some(conn_token) = self.idle_connections.pop_front() {
-                        if let Some(connection) = self.connections.get_mut(&conn_token) {
-                            assert!(connection.state() == ConnectionState::Idle);
-                            match connection.do_request(&mut self.generator, exec_info) {
-                                Ok(_) => {
-                                    connection.state_transition(Some(self.ev_loop.registry()))?;
-                                }
-                                Err(err) => {
-                                    error!("Connection with {:?} failed: {}", conn_token, err);
-                                    self.connection_failed(conn_token)?;
-                                }
-                            }
-                            request_done = true;
+                    tfd.read();
+                    let now = Instant::now();
+                    while let Some(secs) = next_send {
+                        let t = send_time(secs);
+                        if t > now {
+                            let d = t.saturating_duration_since(Instant::now());
+                            tfd.set_state(
+                                TimerState::Oneshot(d.max(Duration::from_nanos(1))),
+                                SetTimeFlags::Default,
+                            );
                             break;
                         }
+                        self.backlog.push_back(t);
+                        next_send = arrivals.next(secs);
                     }
-                    if !request_done && Instant::now() > start_time {
-                        error!("Cannot find an idle connection.");
-                    }
+                    self.dispatch_backlog(exec_info)?;
                 } else if self.connections.contains_key(&token) {
                     let connection = self.connections.get_mut(&token).unwrap();
-                    if event.is_error() || event.is_read_closed() || event.is_write_closed() {
+                    // Drain the socket first, the peer may close right after a response
+                    if event.is_readable() && connection.state() == ConnectionState::Receiving {
+                        match connection.recv_response(exec_info) {
+                            Ok(true) => self.response_done(exec_info, token)?,
+                            Ok(false) => {}
+                            Err(err) => {
+                                error!("Connection with {:?} failed: {}", token, err);
+                                self.replace_connection(exec_info, token)?;
+                            }
+                        }
+                    } else if event.is_error() || event.is_read_closed() || event.is_write_closed()
+                    {
+                        let kind = if connection.state() == ConnectionState::Connecting {
+                            ConnError::Connect
+                        } else if event.is_error() {
+                            match connection.take_error() {
+                                Ok(Some(err)) => ConnError::from_io(&err),
+                                _ => ConnError::Other,
+                            }
+                        } else if event.is_read_closed() {
+                            ConnError::ReadClosed
+                        } else {
+                            ConnError::WriteClosed
+                        };
                         if Instant::now() > start_time {
                             if event.is_error() {
                                 error!("Connection with {:?} has error", token);
@@ -384,38 +905,40 @@ impl Client {
                                 error!("Connection with {:?} write closed", token);
                             }
                         }
-                        exec_info.connection_error();
-                        self.connection_failed(token)?;
+                        connection.fail(exec_info, kind);
+                        self.replace_connection(exec_info, token)?;
                     } else if event.is_readable() {
+                        panic!("Invalid ConnectionState for readable event");
+                    } else if event.is_writable() {
                         match connection.state() {
-                            ConnectionState::Receiving => {
-                                match connection.recv_response(exec_info) {
-                                    Ok(_) => {
-                                        connection
-                                            .state_transition(Some(self.ev_loop.registry()))?;
+                            ConnectionState::Connecting => {
+                                match connection.finish_connect(exec_info) {
+                                    Ok(true) => {
+                                        self.advance_connection(token)?;
+                                        self.connection_idle(exec_info, token, Duration::ZERO)?;
                                     }
+                                    Ok(false) => {}
                                     Err(err) => {
                                         error!("Connection with {:?} failed: {}", token, err);
-                                        self.connection_failed(token)?;
+                                        self.replace_connection(exec_info, token)?;
                                     }
                                 }
                             }
-                            _ => {
-                                panic!("Invalid ConnectionState for readable event");
-                            }
-                        }
-                    } else if event.is_writable() {
-                        match connection.state() {
                             ConnectionState::Idle => {
-                                self.idle_connections.push_back(token);
+                                // Closed-loop connections were queued when they became idle,
+                                // scenarios go on when their think time is over
+                                if !connection.in_scenario() && !self.closed_loop() {
+                                    self.connection_idle(exec_info, token, Duration::ZERO)?;
+                                }
                             }
                             ConnectionState::Sending => match connection.write_request(exec_info) {
-                                Ok(_) => {
-                                    connection.state_transition(Some(self.ev_loop.registry()))?;
+                                Ok(true) => {
+                                    self.advance_connection(token)?;
                                 }
+                                Ok(false) => {}
                                 Err(err) => {
                                     error!("Connection with {:?} failed: {}", token, err);
-                                    self.connection_failed(token)?;
+                                    self.replace_connection(exec_info, token)?;
                                 }
                             },
                             _ => {
@@ -427,8 +950,80 @@ impl Client {
                     panic!("Unknown token");
                 }
             }
+            self.expire_deadlines(exec_info, Instant::now())?;
+            self.dispatch_thinking(exec_info, Instant::now())?;
+            self.report_progress(exec_info);
         }
+        self.report_progress(exec_info);
+        self.progress = None;
 
+        while let Some(scheduled_time) = self.backlog.pop_front() {
+            exec_info.request_dropped(scheduled_time);
+        }
+        exec_info.finish_trace();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_think_times() {
+        let cases = [
+            ("100ms", "100ms"),
+            (" 2s ", "2s"),
+            ("0s", "0s"),
+            ("exp:100ms", "exp:100ms"),
+            ("exp: 1m 30s", "exp:1m 30s"),
+        ];
+        for &(s, displayed) in cases.iter() {
+            let think_time = s.parse::<ThinkTime>().unwrap();
+            assert_eq!(think_time.to_string(), displayed, "{}", s);
+            assert_eq!(
+                displayed.parse::<ThinkTime>().unwrap().to_string(),
+                displayed
+            );
+        }
+        assert!(matches!(
+            "100ms".parse(),
+            Ok(ThinkTime::Fixed(d)) if d == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            "exp:100ms".parse(),
+            Ok(ThinkTime::Exponential(d)) if d == Duration::from_millis(100)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_think_times() {
+        for s in [
+            "",
+            "100",
+            "-1s",
+            "exp:",
+            "exp:100",
+            "uniform:100ms",
+            "100ms:exp",
+        ]
+        .iter()
+        {
+            assert!(s.parse::<ThinkTime>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn samples_think_times() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let fixed = ThinkTime::Fixed(Duration::from_millis(100));
+        assert!((0..100).all(|_| fixed.sample(&mut rng) == Duration::from_millis(100)));
+        let exponential = ThinkTime::Exponential(Duration::from_millis(100));
+        let samples = (0..10000)
+            .map(|_| exponential.sample(&mut rng).as_secs_f64())
+            .collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!((mean - 0.1).abs() < 0.005, "{}", mean);
+        assert!(samples.iter().any(|&x| x > 0.3));
+    }
+}
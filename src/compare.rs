@@ -0,0 +1,408 @@
+use crate::report::{self, OutputFormat};
+use crate::runner::Result;
+use crate::slo;
+
+use std::fs;
+use std::io;
+
+use serde::Serialize;
+use serde_json::Value;
+use structopt::StructOpt;
+
+/// Compare the JSON results of two runs, exit with 2 if the candidate regressed
+#[derive(Debug, StructOpt, Serialize)]
+pub struct CompareOpt {
+    /// Latency percentiles compared, from those in the JSON results
+    #[structopt(long = "percentiles", default_value = "50,90,99")]
+    pub percentiles: String,
+
+    /// Relative latency increase tolerated, e.g. 10% or 0.1
+    #[structopt(long = "latency-tolerance", default_value = "10%")]
+    pub latency_tolerance: String,
+
+    /// Latency increase tolerated regardless of --latency-tolerance, for noise on fast requests
+    #[structopt(long = "latency-slack", default_value = "0s")]
+    pub latency_slack: String,
+
+    /// Relative throughput decrease tolerated
+    #[structopt(long = "throughput-tolerance", default_value = "5%")]
+    pub throughput_tolerance: String,
+
+    /// Error rate increase tolerated, in absolute terms
+    #[structopt(long = "error-tolerance", default_value = "0.1%")]
+    pub error_tolerance: String,
+
+    /// Format of the results (text, json or csv)
+    #[structopt(long = "output-format", default_value = "text")]
+    pub output_format: OutputFormat,
+
+    /// JSON results of the baseline run
+    #[structopt(name = "BASELINE")]
+    pub baseline: String,
+
+    /// JSON results of the candidate run
+    #[structopt(name = "CANDIDATE")]
+    pub candidate: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricKind {
+    // Microseconds, lower is better
+    Latency,
+    // Requests per second, higher is better
+    Throughput,
+    // Fraction of requests, lower is better
+    ErrorRate,
+}
+
+/// One metric of the whole run or of one request type in both runs.
+pub struct Comparison {
+    pub scope: String,
+    pub metric: String,
+    pub kind: MetricKind,
+    pub baseline: f64,
+    pub candidate: f64,
+    pub regressed: bool,
+}
+
+struct Tolerances {
+    latency: f64,
+    latency_slack: f64,
+    throughput: f64,
+    errors: f64,
+}
+
+impl Tolerances {
+    fn regressed(&self, kind: MetricKind, baseline: f64, candidate: f64) -> bool {
+        match kind {
+            MetricKind::Latency => candidate > baseline * (1.0 + self.latency) + self.latency_slack,
+            MetricKind::Throughput => candidate < baseline * (1.0 - self.throughput),
+            MetricKind::ErrorRate => candidate > baseline + self.errors,
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Value> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let value: Value = serde_json::from_str(&content)
+        .map_err(|err| format!("Invalid JSON results in {}: {}", path, err))?;
+    match value["schema_version"].as_u64() {
+        Some(version) if version == u64::from(report::SCHEMA_VERSION) => {}
+        Some(version) => {
+            return Err(format!("Unsupported schema version {} of {}", version, path).into())
+        }
+        None => return Err(format!("{} is not a JSON result of flood", path).into()),
+    }
+    // Search results and runs without latencies have nothing to compare
+    if !value["summary"].is_object() {
+        return Err(format!("{} has no summary of a run", path).into());
+    }
+    if !value["latency"]["percentiles_us"].is_object() {
+        return Err(format!("{} has no latency percentiles", path).into());
+    }
+    Ok(value)
+}
+
+fn number(value: &Value) -> f64 {
+    value.as_f64().unwrap_or(0.0)
+}
+
+// Same definition as the `errors` metric of SLOs
+fn summary_error_rate(summary: &Value) -> f64 {
+    let errors = number(&summary["failure"])
+        + number(&summary["dropped"])
+        + summary["errors"]
+            .as_object()
+            .map_or(0.0, |errors| errors.values().map(number).sum());
+    let total = number(&summary["success"]) + errors;
+    if total == 0.0 {
+        0.0
+    } else {
+        errors / total
+    }
+}
+
+// Failures and timeouts relative to everything completed, per request type
+fn type_error_rate(stats: &Value) -> f64 {
+    let errors = number(&stats["failure"]) + number(&stats["timeout"]);
+    let total = number(&stats["success"]) + errors;
+    if total == 0.0 {
+        0.0
+    } else {
+        errors / total
+    }
+}
+
+/// Compares the overall results, then every request type found in both runs.
+/// Types found in only one of them are returned by name.
+pub fn compare(opt: &CompareOpt) -> Result<(Vec<Comparison>, Vec<String>)> {
+    let fraction = |name: &str, s: &str| {
+        slo::parse_fraction(s.trim())
+            .filter(|fraction| *fraction >= 0.0)
+            .ok_or_else(|| format!("Invalid {}: {}", name, s))
+    };
+    let tolerances = Tolerances {
+        latency: fraction("--latency-tolerance", &opt.latency_tolerance)?,
+        latency_slack: humantime::parse_duration(&opt.latency_slack)?.as_micros() as f64,
+        throughput: fraction("--throughput-tolerance", &opt.throughput_tolerance)?,
+        errors: fraction("--error-tolerance", &opt.error_tolerance)?,
+    };
+    let baseline = load(&opt.baseline)?;
+    let candidate = load(&opt.candidate)?;
+    let percentiles = opt
+        .percentiles
+        .split(',')
+        .map(|percentile| {
+            let key = format!("p{}", percentile.trim()).replace('.', "_");
+            for (path, results) in [(&opt.baseline, &baseline), (&opt.candidate, &candidate)] {
+                if results["latency"]["percentiles_us"].get(&key).is_none() {
+                    return Err(format!(
+                        "Percentile {} is not in {}",
+                        percentile.trim(),
+                        path
+                    ));
+                }
+            }
+            Ok(key)
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let mut comparisons = Vec::new();
+    let mut add_scope = |scope: &str, baseline: &Value, candidate: &Value, errors: [f64; 2]| {
+        let mut add = |metric: &str, kind: MetricKind, baseline: f64, candidate: f64| {
+            comparisons.push(Comparison {
+                scope: scope.to_string(),
+                metric: metric.to_string(),
+                kind,
+                baseline,
+                candidate,
+                regressed: tolerances.regressed(kind, baseline, candidate),
+            });
+        };
+        add(
+            "req/s",
+            MetricKind::Throughput,
+            number(&baseline["requests_per_sec"]),
+            number(&candidate["requests_per_sec"]),
+        );
+        add("errors", MetricKind::ErrorRate, errors[0], errors[1]);
+        for key in percentiles.iter() {
+            add(
+                key,
+                MetricKind::Latency,
+                number(&baseline["latency"]["percentiles_us"][key]),
+                number(&candidate["latency"]["percentiles_us"][key]),
+            );
+        }
+    };
+
+    let mut baseline_all = baseline["summary"].clone();
+    baseline_all["latency"] = baseline["latency"].clone();
+    let mut candidate_all = candidate["summary"].clone();
+    candidate_all["latency"] = candidate["latency"].clone();
+    add_scope(
+        "all",
+        &baseline_all,
+        &candidate_all,
+        [
+            summary_error_rate(&baseline["summary"]),
+            summary_error_rate(&candidate["summary"]),
+        ],
+    );
+
+    let empty = serde_json::Map::new();
+    let baseline_types = baseline["types"].as_object().unwrap_or(&empty);
+    let candidate_types = candidate["types"].as_object().unwrap_or(&empty);
+    let type_name = |req_type: &str, stats: &Value| match stats["name"].as_str() {
+        Some(name) => format!("{} ({})", name, req_type),
+        None => format!("type {}", req_type),
+    };
+    let mut unmatched = Vec::new();
+    for (req_type, baseline_stats) in baseline_types.iter() {
+        match candidate_types.get(req_type) {
+            Some(candidate_stats) => add_scope(
+                &type_name(req_type, baseline_stats),
+                baseline_stats,
+                candidate_stats,
+                [
+                    type_error_rate(baseline_stats),
+                    type_error_rate(candidate_stats),
+                ],
+            ),
+            None => unmatched.push(type_name(req_type, baseline_stats)),
+        }
+    }
+    for (req_type, candidate_stats) in candidate_types.iter() {
+        if !baseline_types.contains_key(req_type) {
+            unmatched.push(type_name(req_type, candidate_stats));
+        }
+    }
+    Ok((comparisons, unmatched))
+}
+
+// Entry point of `flood compare`, returns whether the candidate regressed
+pub fn main(opt: CompareOpt) -> Result<bool> {
+    let (comparisons, unmatched) = compare(&opt)?;
+    report::write_comparison_report(
+        &mut io::stdout().lock(),
+        opt.output_format,
+        &opt,
+        &comparisons,
+        &unmatched,
+    )?;
+    Ok(comparisons.iter().any(|comparison| comparison.regressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("flood-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn results(p99: f64, types: Value) -> Value {
+        json!({
+            "schema_version": report::SCHEMA_VERSION,
+            "summary": {"success": 90, "failure": 10, "requests_per_sec": 100.0},
+            "latency": {"percentiles_us": {"p50": 1000.0, "p90": 1500.0, "p99": p99}},
+            "types": types,
+        })
+    }
+
+    fn type_stats(name: &str, failure: u64) -> Value {
+        json!({
+            "name": name,
+            "success": 100 - failure,
+            "failure": failure,
+            "timeout": 0,
+            "requests_per_sec": 10.0,
+            "latency": {"percentiles_us": {"p50": 1000.0, "p90": 1500.0, "p99": 2000.0}},
+        })
+    }
+
+    fn compare_files(
+        name: &str,
+        baseline: &Value,
+        candidate: &Value,
+    ) -> Result<(Vec<Comparison>, Vec<String>)> {
+        let paths = [
+            temp_path(&format!("{}-baseline.json", name)),
+            temp_path(&format!("{}-candidate.json", name)),
+        ];
+        fs::write(&paths[0], baseline.to_string()).unwrap();
+        fs::write(&paths[1], candidate.to_string()).unwrap();
+        let opt = CompareOpt::from_iter(&["compare", &paths[0], &paths[1]]);
+        let result = compare(&opt);
+        for path in paths.iter() {
+            fs::remove_file(path).unwrap();
+        }
+        result
+    }
+
+    #[test]
+    fn applies_tolerances() {
+        let tolerances = Tolerances {
+            latency: 0.25,
+            latency_slack: 50.0,
+            throughput: 0.5,
+            errors: 0.125,
+        };
+        let cases = [
+            (MetricKind::Latency, 1000.0, 1000.0, false),
+            (MetricKind::Latency, 1000.0, 1300.0, false),
+            (MetricKind::Latency, 1000.0, 1300.5, true),
+            (MetricKind::Latency, 0.0, 50.0, false),
+            (MetricKind::Latency, 0.0, 51.0, true),
+            (MetricKind::Latency, 1000.0, 500.0, false),
+            (MetricKind::Throughput, 100.0, 150.0, false),
+            (MetricKind::Throughput, 100.0, 50.0, false),
+            (MetricKind::Throughput, 100.0, 49.5, true),
+            (MetricKind::ErrorRate, 0.25, 0.375, false),
+            (MetricKind::ErrorRate, 0.25, 0.5, true),
+            (MetricKind::ErrorRate, 0.0, 0.125, false),
+            (MetricKind::ErrorRate, 0.5, 0.0, false),
+        ];
+        for &(kind, baseline, candidate, regressed) in cases.iter() {
+            assert_eq!(
+                tolerances.regressed(kind, baseline, candidate),
+                regressed,
+                "{:?} {} -> {}",
+                kind,
+                baseline,
+                candidate
+            );
+        }
+    }
+
+    #[test]
+    fn compares_types_of_both_runs() {
+        let baseline = results(
+            2000.0,
+            json!({"0": type_stats("login", 0), "1": type_stats("search", 0)}),
+        );
+        let candidate = results(
+            4000.0,
+            json!({"1": type_stats("search", 50), "2": type_stats("upload", 0)}),
+        );
+        let (comparisons, unmatched) = compare_files("types", &baseline, &candidate).unwrap();
+        assert_eq!(unmatched, vec!["login (0)", "upload (2)"]);
+        let found = comparisons
+            .iter()
+            .map(|c| (c.scope.as_str(), c.metric.as_str(), c.regressed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("all", "req/s", false),
+                ("all", "errors", false),
+                ("all", "p50", false),
+                ("all", "p90", false),
+                ("all", "p99", true),
+                ("search (1)", "req/s", false),
+                ("search (1)", "errors", true),
+                ("search (1)", "p50", false),
+                ("search (1)", "p90", false),
+                ("search (1)", "p99", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        let complete = results(2000.0, json!({}));
+        let mut no_summary = complete.clone();
+        no_summary.as_object_mut().unwrap().remove("summary");
+        let mut no_latency = complete.clone();
+        no_latency.as_object_mut().unwrap().remove("latency");
+        let mut no_p90 = complete.clone();
+        no_p90["latency"]["percentiles_us"]
+            .as_object_mut()
+            .unwrap()
+            .remove("p90");
+        let mut no_version = complete.clone();
+        no_version.as_object_mut().unwrap().remove("schema_version");
+        let mut other_version = complete.clone();
+        other_version["schema_version"] = json!(report::SCHEMA_VERSION + 1);
+        let cases = [
+            (no_summary, "has no summary of a run"),
+            (no_latency, "has no latency percentiles"),
+            (no_p90, "Percentile 90 is not in"),
+            (no_version, "is not a JSON result of flood"),
+            (other_version, "Unsupported schema version"),
+        ];
+        for (candidate, message) in cases.iter() {
+            let err = match compare_files("missing", &complete, candidate) {
+                Ok(_) => panic!("{} accepted", candidate),
+                Err(err) => err.to_string(),
+            };
+            assert!(err.contains(message), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use quick_js::{self, JsValue};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+/// Dataset given with `--data name=path`, readable by scripts as `flood.data.<name>`.
+#[derive(Clone, Debug)]
+pub struct DataSpec {
+    pub name: String,
+    pub path: String,
+}
+
+impl FromStr for DataSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DataSpec, String> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid dataset {}, expected name=path", s))?;
+        let name = name.trim();
+        let is_identifier = name.chars().enumerate().all(|(i, c)| {
+            c == '_' || c == '$' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())
+        });
+        if name.is_empty() || !is_identifier {
+            return Err(format!("Invalid dataset name: {}", name));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            path: path.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for DataSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.path)
+    }
+}
+
+impl Serialize for DataSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Rows of a dataset, with the cursors of `next()` and `unique()` shared by
+/// every JS context of the process.
+pub struct Dataset {
+    pub name: String,
+    pub rows: Vec<Value>,
+    next: AtomicUsize,
+    unique: AtomicUsize,
+}
+
+impl Dataset {
+    pub fn new(name: &str, rows: Vec<Value>) -> Dataset {
+        Self {
+            name: name.to_string(),
+            rows,
+            next: AtomicUsize::new(0),
+            unique: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reads a CSV file with a header row, each row becoming an object of
+    /// strings keyed by column, or a JSONL file with one JSON value per line.
+    pub fn load(spec: &DataSpec) -> Result<Dataset, String> {
+        let content = fs::read_to_string(&spec.path)
+            .map_err(|err| format!("Failed to read {}: {}", spec.path, err))?;
+        let content = content.trim_start_matches('\u{feff}');
+        let rows = if spec.path.ends_with(".csv") {
+            parse_csv(content).map_err(|err| format!("Invalid CSV {}: {}", spec.path, err))?
+        } else if spec.path.ends_with(".jsonl") || spec.path.ends_with(".ndjson") {
+            parse_jsonl(content).map_err(|err| format!("Invalid JSONL {}: {}", spec.path, err))?
+        } else {
+            return Err(format!(
+                "Unknown format of {}, expected .csv or .jsonl",
+                spec.path
+            ));
+        };
+        if rows.is_empty() {
+            return Err(format!("{} has no rows", spec.path));
+        }
+        Ok(Self::new(&spec.name, rows))
+    }
+
+    /// Restarts `next()` and `unique()` from the first row.
+    pub fn rewind(&self) {
+        self.next.store(0, Ordering::Relaxed);
+        self.unique.store(0, Ordering::Relaxed);
+    }
+
+    fn row(&self, index: usize) -> JsValue {
+        self.rows.get(index).map_or(JsValue::Undefined, js_value)
+    }
+}
+
+// Fields of one record per line, quoted fields may hold commas, quotes as ""
+// and line breaks
+fn parse_csv(content: &str) -> Result<Vec<Value>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push((line, std::mem::take(&mut record)));
+                line += 1;
+            }
+            _ => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((line, record));
+    }
+    // Blank lines are skipped
+    let mut records = records
+        .into_iter()
+        .filter(|(_, record)| record.len() > 1 || !record[0].is_empty());
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    if let Some((i, name)) = header
+        .iter()
+        .enumerate()
+        .find(|(i, name)| header[..*i].contains(name))
+    {
+        return Err(format!(
+            "column {} is named {} like an earlier one",
+            i + 1,
+            name
+        ));
+    }
+    records
+        .map(|(line, record)| {
+            if record.len() != header.len() {
+                return Err(format!(
+                    "line {} has {} fields, the header has {}",
+                    line,
+                    record.len(),
+                    header.len()
+                ));
+            }
+            Ok(Value::Object(
+                header
+                    .iter()
+                    .cloned()
+                    .zip(record.into_iter().map(Value::String))
+                    .collect(),
+            ))
+        })
+        .collect()
+}
+
+fn parse_jsonl(content: &str) -> Result<Vec<Value>, String> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|err| format!("line {}: {}", i + 1, err))
+        })
+        .collect()
+}
+
+fn js_value(value: &Value) -> JsValue {
+    match value {
+        Value::Null => JsValue::Null,
+        Value::Bool(b) => JsValue::Bool(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) if i32::try_from(n).is_ok() => JsValue::Int(n as i32),
+            _ => JsValue::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => JsValue::String(s.clone()),
+        Value::Array(values) => JsValue::Array(values.iter().map(js_value).collect()),
+        Value::Object(map) => JsValue::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), js_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Defines `flood.data` in `js_context`, rows being read through callbacks.
+pub fn install(
+    js_context: &quick_js::Context,
+    datasets: &Arc<Vec<Dataset>>,
+) -> Result<(), quick_js::ExecutionError> {
+    if datasets.is_empty() {
+        return Ok(());
+    }
+    let data = datasets.clone();
+    js_context.add_callback("floodDataNext", move |index: i32| {
+        let dataset = &data[index as usize];
+        dataset.row(dataset.next.fetch_add(1, Ordering::Relaxed) % dataset.rows.len())
+    })?;
+    let data = datasets.clone();
+    js_context.add_callback("floodDataUnique", move |index: i32| {
+        let dataset = &data[index as usize];
+        match dataset.unique.fetch_add(1, Ordering::Relaxed) {
+            i if i < dataset.rows.len() => dataset.row(i),
+            _ => JsValue::Null,
+        }
+    })?;
+    let data = datasets.clone();
+    js_context.add_callback("floodDataRow", move |index: i32, row: i32| {
+        data[index as usize].row(row as usize)
+    })?;
+    for (i, dataset) in datasets.iter().enumerate() {
+        js_context.eval(&format!(
+            "flood.data[{}] = floodDataset({}, {});",
+            Value::String(dataset.name.clone()),
+            i,
+            dataset.rows.len()
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_csv() {
+        let cases = [
+            (
+                "id,name\n1,alice\n2,bob\n",
+                json!([{"id": "1", "name": "alice"}, {"id": "2", "name": "bob"}]),
+            ),
+            ("id,name\n1,alice", json!([{"id": "1", "name": "alice"}])),
+            (
+                "id,name\r\n1,alice\r\n2,bob\r\n",
+                json!([{"id": "1", "name": "alice"}, {"id": "2", "name": "bob"}]),
+            ),
+            ("a,b\n\"x, y\",z\n", json!([{"a": "x, y", "b": "z"}])),
+            (
+                "a,b\n\"say \"\"hi\"\"\",\"\"\n",
+                json!([{"a": "say \"hi\"", "b": ""}]),
+            ),
+            (
+                "a,b\n\"line 1\nline 2\",\"cr\r\nlf\"\n",
+                json!([{"a": "line 1\nline 2", "b": "cr\r\nlf"}]),
+            ),
+            (
+                "\na,b\n\n1,2\r\n\r\n3,4\n\n",
+                json!([{"a": "1", "b": "2"}, {"a": "3", "b": "4"}]),
+            ),
+            ("a,b\n,\n", json!([{"a": "", "b": ""}])),
+            ("a\n1\n\n2\n", json!([{"a": "1"}, {"a": "2"}])),
+            ("a,b\n", json!([])),
+            ("", json!([])),
+        ];
+        for (content, expected) in cases.iter() {
+            let rows = parse_csv(content).unwrap();
+            assert_eq!(&Value::Array(rows), expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_csv() {
+        let cases = [
+            ("a,b\n1,2\n3\n", "line 3 has 1 fields, the header has 2"),
+            ("a,b\n1,2,3\n", "line 2 has 3 fields, the header has 2"),
+            (
+                "a,b\n\"x\ny\",1\n1,2,3\n",
+                "line 4 has 3 fields, the header has 2",
+            ),
+            ("a,b\n\n\n1\n", "line 4 has 1 fields, the header has 2"),
+            ("a,b\n\"x,1\n", "unterminated quoted field"),
+            (
+                "id,name,id\n1,2,3\n",
+                "column 3 is named id like an earlier one",
+            ),
+        ];
+        for (content, message) in cases.iter() {
+            assert_eq!(parse_csv(content).unwrap_err(), *message, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn parses_jsonl() {
+        let rows = parse_jsonl("{\"a\": 1}\n\n[2]\r\n\"s\"\n").unwrap();
+        assert_eq!(rows, vec![json!({"a": 1}), json!([2]), json!("s")]);
+        assert!(parse_jsonl("{}\n{\n").unwrap_err().starts_with("line 2: "));
+    }
+
+    #[test]
+    fn parses_dataset_specs() {
+        let spec = " users = data/users.csv ".parse::<DataSpec>().unwrap();
+        assert_eq!(
+            (spec.name.as_str(), spec.path.as_str()),
+            ("users", "data/users.csv")
+        );
+        assert_eq!(spec.to_string(), "users=data/users.csv");
+        assert_eq!("$a_1=x=y.csv".parse::<DataSpec>().unwrap().path, "x=y.csv");
+        for spec in [
+            "users.csv",
+            "=users.csv",
+            "1users=users.csv",
+            "user-s=users.csv",
+        ]
+        .iter()
+        {
+            assert!(spec.parse::<DataSpec>().is_err(), "{}", spec);
+        }
+    }
+}
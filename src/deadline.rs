@@ -0,0 +1,100 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+use mio::Token;
+
+/// Min-heap of connection deadlines. Entries are never removed eagerly, the
+/// owner is expected to drop stale ones when they are popped.
+pub struct DeadlineQueue {
+    heap: BinaryHeap<Reverse<(Instant, Token)>>,
+}
+
+impl DeadlineQueue {
+    pub fn new() -> DeadlineQueue {
+        Self {
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, token: Token, deadline: Instant) {
+        self.heap.push(Reverse((deadline, token)));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    pub fn pop_expired(&mut self, now: Instant) -> Option<(Token, Instant)> {
+        match self.heap.peek() {
+            Some(Reverse((deadline, _))) if *deadline <= now => {
+                let Reverse((deadline, token)) = self.heap.pop().unwrap();
+                Some((token, deadline))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pops_expired_deadlines_in_order() {
+        let t0 = Instant::now();
+        let at = |millis: u64| t0 + Duration::from_millis(millis);
+        let mut queue = DeadlineQueue::new();
+        assert_eq!(queue.next_deadline(), None);
+        assert_eq!(queue.pop_expired(at(1000)), None);
+        queue.push(Token(1), at(30));
+        queue.push(Token(2), at(10));
+        queue.push(Token(3), at(20));
+        assert_eq!(queue.next_deadline(), Some(at(10)));
+        assert_eq!(queue.pop_expired(at(5)), None);
+        assert_eq!(queue.pop_expired(at(10)), Some((Token(2), at(10))));
+        assert_eq!(queue.pop_expired(at(15)), None);
+        assert_eq!(queue.next_deadline(), Some(at(20)));
+        assert_eq!(queue.pop_expired(at(100)), Some((Token(3), at(20))));
+        assert_eq!(queue.pop_expired(at(100)), Some((Token(1), at(30))));
+        assert_eq!(queue.pop_expired(at(100)), None);
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test]
+    fn keeps_superseded_deadlines() {
+        let t0 = Instant::now();
+        let at = |millis: u64| t0 + Duration::from_millis(millis);
+        let mut queue = DeadlineQueue::new();
+        // The deadline of token 1 moved later and then earlier, every entry is
+        // popped with the deadline it was pushed with
+        queue.push(Token(1), at(10));
+        queue.push(Token(1), at(50));
+        queue.push(Token(1), at(5));
+        queue.push(Token(2), at(20));
+        let popped = std::iter::from_fn(|| queue.pop_expired(at(100))).collect::<Vec<_>>();
+        assert_eq!(
+            popped,
+            vec![
+                (Token(1), at(5)),
+                (Token(1), at(10)),
+                (Token(2), at(20)),
+                (Token(1), at(50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_equal_deadlines_by_token() {
+        let deadline = Instant::now();
+        let mut queue = DeadlineQueue::new();
+        for &token in [3, 1, 2, 1].iter() {
+            queue.push(Token(token), deadline);
+        }
+        let popped = std::iter::from_fn(|| queue.pop_expired(deadline))
+            .map(|(Token(token), _)| token)
+            .collect::<Vec<_>>();
+        assert_eq!(popped, vec![1, 1, 2, 3]);
+    }
+}
@@ -1,45 +1,436 @@
-use std::fs::File;
-use std::io::{self, BufWriter, Write};
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant, SystemTime};
 
 use hdrhistogram::Histogram;
 use log::*;
-use rand::Rng;
-use zstd;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::generator::ScenarioDef;
+use crate::http::SuccessCodes;
+use crate::schedule::RateSchedule;
+use crate::trace::{TraceRecord, TraceSink};
+
+#[derive(Clone, Copy, Debug)]
+pub enum ConnError {
+    Connect,
+    Reset,
+    ReadClosed,
+    WriteClosed,
+    Other,
+}
+
+impl ConnError {
+    pub fn from_io(err: &io::Error) -> ConnError {
+        match err.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => ConnError::Reset,
+            ErrorKind::UnexpectedEof => ConnError::ReadClosed,
+            ErrorKind::BrokenPipe => ConnError::WriteClosed,
+            ErrorKind::ConnectionRefused => ConnError::Connect,
+            _ => ConnError::Other,
+        }
+    }
+}
+
+// Results of one request type or schedule phase
+pub struct RequestStats {
+    pub latency_hist: Histogram<u32>,
+    pub success_count: u32,
+    pub failure_count: u32,
+    pub timeout_count: u32,
+}
+
+// Results of one reporting interval, attributed by completion time
+#[derive(Clone)]
+pub struct IntervalStats {
+    pub latency_hist: Histogram<u32>,
+    pub success_count: u32,
+    pub failure_count: u32,
+    pub timeout_count: u32,
+    pub error_count: u32,
+}
+
+// Results of one scenario, attributed by its scheduled start. Steps pass if
+// their status is a success and their check passed.
+pub struct ScenarioStats {
+    pub name: String,
+    // Passed scenarios, from the scheduled start to the last response
+    pub latency_hist: Histogram<u32>,
+    pub passed_count: u32,
+    pub failed_count: u32,
+    pub timeout_count: u32,
+    pub steps: Vec<(String, RequestStats)>,
+}
 
 pub struct ExecutionInfo {
     initial_time: Instant,
-    traces: Vec<(u32, u32, u32)>,
+    // Wall clock time of `initial_time`
+    initial_wall_time: SystemTime,
+    report_interval: Option<Duration>,
+    reported_intervals: usize,
+    pub intervals: Vec<IntervalStats>,
+    hist_max: u64,
+    trace: Option<TraceSink>,
     trace_sample_ratio: f32,
+    trace_rng: StdRng,
+    success_codes: SuccessCodes,
+    pub type_names: BTreeMap<u32, String>,
+    pub type_stats: BTreeMap<u32, RequestStats>,
+    schedule: Option<RateSchedule>,
+    // Attributed by scheduled send time, one entry per schedule phase
+    pub phase_stats: Vec<RequestStats>,
+    pub scenario_stats: Vec<ScenarioStats>,
     pub latency_hist: Histogram<u32>,
+    pub connect_hist: Histogram<u32>,
+    pub send_delay_hist: Histogram<u32>,
     pub bytes_sent: usize,
     pub bytes_recv: usize,
     pub request_total: u32,
-    pub success_count: u32,    // 200
-    pub failure_count: u32,    // non-200
+    pub status_counts: BTreeMap<u16, u32>,
+    pub success_count: u32, // status in success_codes
+    pub failure_count: u32, // other statuses
+    pub connect_error_count: u32,
+    pub reset_count: u32,
+    pub read_closed_count: u32,
+    pub write_closed_count: u32,
     pub conn_error_count: u32, // other errors
     pub parse_error_count: u32,
+    pub timeout_count: u32,
+    pub late_count: u32, // sent more than LATE_THRESHOLD_MICROS behind schedule
+    pub dropped_count: u32, // still waiting for a connection when the run ended, or the script failed to make it
+}
+
+const LATE_THRESHOLD_MICROS: u64 = 1000;
+
+// Latencies include queueing delay, so they are not bounded by the timeouts
+fn new_latency_hist(hist_max: u64) -> Histogram<u32> {
+    let mut hist = Histogram::<u32>::new_with_max(hist_max, 3).unwrap();
+    hist.auto(true);
+    hist
+}
+
+impl RequestStats {
+    pub fn new(hist_max: u64) -> RequestStats {
+        Self {
+            latency_hist: new_latency_hist(hist_max),
+            success_count: 0,
+            failure_count: 0,
+            timeout_count: 0,
+        }
+    }
+
+    fn merge(&mut self, other: RequestStats) {
+        if self.latency_hist.add(&other.latency_hist).is_err() {
+            warn!("Failed to merge histograms");
+        }
+        self.success_count += other.success_count;
+        self.failure_count += other.failure_count;
+        self.timeout_count += other.timeout_count;
+    }
+}
+
+impl IntervalStats {
+    pub fn new() -> IntervalStats {
+        // Two significant digits keep long time series small
+        let mut latency_hist = Histogram::<u32>::new(2).unwrap();
+        latency_hist.auto(true);
+        Self {
+            latency_hist,
+            success_count: 0,
+            failure_count: 0,
+            timeout_count: 0,
+            error_count: 0,
+        }
+    }
+
+    pub fn merge(&mut self, other: &IntervalStats) {
+        if self.latency_hist.add(&other.latency_hist).is_err() {
+            warn!("Failed to merge histograms");
+        }
+        self.success_count += other.success_count;
+        self.failure_count += other.failure_count;
+        self.timeout_count += other.timeout_count;
+        self.error_count += other.error_count;
+    }
+}
+
+impl ScenarioStats {
+    pub fn new(def: &ScenarioDef, hist_max: u64) -> ScenarioStats {
+        Self {
+            name: def.name.clone(),
+            latency_hist: new_latency_hist(hist_max),
+            passed_count: 0,
+            failed_count: 0,
+            timeout_count: 0,
+            steps: def
+                .steps
+                .iter()
+                .map(|name| (name.clone(), RequestStats::new(hist_max)))
+                .collect(),
+        }
+    }
+
+    fn merge(&mut self, other: ScenarioStats) {
+        if self.latency_hist.add(&other.latency_hist).is_err() {
+            warn!("Failed to merge histograms");
+        }
+        self.passed_count += other.passed_count;
+        self.failed_count += other.failed_count;
+        self.timeout_count += other.timeout_count;
+        for ((_, step), (_, other_step)) in self.steps.iter_mut().zip(other.steps) {
+            step.merge(other_step);
+        }
+    }
 }
 
 impl ExecutionInfo {
-    pub fn new(hist_max: u64, trace_size: usize, trace_sample_ratio: f32) -> ExecutionInfo {
+    pub fn new(hist_max: u64) -> ExecutionInfo {
         Self {
             initial_time: Instant::now(),
-            traces: Vec::<(u32, u32, u32)>::with_capacity(trace_size),
-            trace_sample_ratio: trace_sample_ratio,
-            latency_hist: Histogram::<u32>::new_with_max(hist_max, 3).unwrap(),
+            initial_wall_time: SystemTime::now(),
+            report_interval: None,
+            reported_intervals: 0,
+            intervals: Vec::new(),
+            hist_max,
+            trace: None,
+            trace_sample_ratio: 0.0,
+            trace_rng: StdRng::from_entropy(),
+            success_codes: SuccessCodes::default(),
+            type_names: BTreeMap::new(),
+            type_stats: BTreeMap::new(),
+            schedule: None,
+            phase_stats: Vec::new(),
+            scenario_stats: Vec::new(),
+            latency_hist: new_latency_hist(hist_max),
+            connect_hist: Histogram::<u32>::new(3).unwrap(),
+            send_delay_hist: Histogram::<u32>::new(3).unwrap(),
             bytes_sent: 0,
             bytes_recv: 0,
             request_total: 0,
+            status_counts: BTreeMap::new(),
             success_count: 0,
             failure_count: 0,
+            connect_error_count: 0,
+            reset_count: 0,
+            read_closed_count: 0,
+            write_closed_count: 0,
             conn_error_count: 0,
             parse_error_count: 0,
+            timeout_count: 0,
+            late_count: 0,
+            dropped_count: 0,
         }
     }
 
     pub fn set_initial_time(&mut self, t: Instant) {
         self.initial_time = t;
+        self.initial_wall_time = SystemTime::now() + t.saturating_duration_since(Instant::now());
+    }
+
+    // When warm-up ended, by the wall clock
+    pub fn start_wall_time(&self) -> SystemTime {
+        self.initial_wall_time
+    }
+
+    // For results of another process, whose warm-up ended at `t`
+    pub fn set_start_wall_time(&mut self, t: SystemTime) {
+        self.initial_wall_time = t;
+    }
+
+    pub fn set_report_interval(&mut self, d: Duration) {
+        self.report_interval = Some(d);
+    }
+
+    pub fn report_interval(&self) -> Option<Duration> {
+        self.report_interval
+    }
+
+    fn interval_index(&self, t: Instant) -> Option<usize> {
+        let interval = self.report_interval?;
+        if t < self.initial_time {
+            return None;
+        }
+        Some((t.duration_since(self.initial_time).as_nanos() / interval.as_nanos()) as usize)
+    }
+
+    fn interval_mut(&mut self, t: Instant) -> Option<&mut IntervalStats> {
+        let index = self.interval_index(t)?;
+        if self.intervals.len() <= index {
+            self.intervals.resize_with(index + 1, IntervalStats::new);
+        }
+        Some(&mut self.intervals[index])
+    }
+
+    // Intervals that ended before `now` and were not returned by earlier calls
+    pub fn take_finished_intervals(&mut self, now: Instant) -> Vec<(usize, IntervalStats)> {
+        let finished = match self.interval_index(now) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        if self.intervals.len() < finished {
+            self.intervals.resize_with(finished, IntervalStats::new);
+        }
+        let result = (self.reported_intervals..finished)
+            .map(|index| (index, self.intervals[index].clone()))
+            .collect::<Vec<_>>();
+        self.reported_intervals = self.reported_intervals.max(finished);
+        result
+    }
+
+    pub fn set_trace(&mut self, sink: TraceSink, sample_ratio: f32, rng: StdRng) {
+        self.trace = Some(sink);
+        self.trace_sample_ratio = sample_ratio;
+        self.trace_rng = rng;
+    }
+
+    // Whether to trace a request, sampled among those scheduled after warm-up
+    pub fn should_trace(&mut self, scheduled_time: Instant) -> bool {
+        self.trace.is_some()
+            && scheduled_time >= self.initial_time
+            && self.trace_rng.gen_range(0.0..1.0) < self.trace_sample_ratio
+    }
+
+    // Microseconds since the end of warm-up, as stored in traces
+    pub fn trace_timestamp(&self, t: Instant) -> u64 {
+        t.saturating_duration_since(self.initial_time).as_micros() as u64
+    }
+
+    pub fn trace(&mut self, record: TraceRecord) {
+        if let Some(sink) = self.trace.as_mut() {
+            sink.push(record);
+        }
+    }
+
+    // Hand the remaining records to the writer and release it
+    pub fn finish_trace(&mut self) {
+        if let Some(mut sink) = self.trace.take() {
+            sink.flush();
+            if sink.dropped() > 0 {
+                warn!(
+                    "Dropped {} trace records, the trace writer fell behind",
+                    sink.dropped()
+                );
+            }
+        }
+    }
+
+    pub fn set_schedule(&mut self, schedule: RateSchedule) {
+        let hist_max = self.hist_max;
+        self.phase_stats = schedule
+            .phases()
+            .iter()
+            .map(|_| RequestStats::new(hist_max))
+            .collect();
+        self.schedule = Some(schedule);
+    }
+
+    pub fn schedule(&self) -> Option<&RateSchedule> {
+        self.schedule.as_ref()
+    }
+
+    fn phase_stats_mut(&mut self, start_time: Instant) -> Option<&mut RequestStats> {
+        let t = start_time.duration_since(self.initial_time).as_secs_f64();
+        let index = self.schedule.as_ref()?.phase_index(t);
+        self.phase_stats.get_mut(index)
+    }
+
+    pub fn set_scenarios(&mut self, scenarios: &[ScenarioDef]) {
+        let hist_max = self.hist_max;
+        self.scenario_stats = scenarios
+            .iter()
+            .map(|def| ScenarioStats::new(def, hist_max))
+            .collect();
+    }
+
+    // A step of a scenario scheduled at `scenario_start` got its response
+    pub fn scenario_step(
+        &mut self,
+        index: usize,
+        step: usize,
+        scenario_start: Instant,
+        step_start: Instant,
+        finish_time: Instant,
+        passed: bool,
+    ) {
+        if scenario_start < self.initial_time {
+            return;
+        }
+        let stats = &mut self.scenario_stats[index].steps[step].1;
+        let latency = finish_time.duration_since(step_start).as_micros() as u64;
+        if stats.latency_hist.record(latency).is_err() {
+            warn!("Failed to record latency: {}", latency);
+        }
+        if passed {
+            stats.success_count += 1;
+        } else {
+            stats.failure_count += 1;
+        }
+    }
+
+    // A step failed without a response, which ends its scenario
+    pub fn scenario_aborted(
+        &mut self,
+        index: usize,
+        step: usize,
+        scenario_start: Instant,
+        timed_out: bool,
+    ) {
+        if scenario_start < self.initial_time {
+            return;
+        }
+        let stats = &mut self.scenario_stats[index];
+        if timed_out {
+            stats.timeout_count += 1;
+            stats.steps[step].1.timeout_count += 1;
+        } else {
+            stats.failed_count += 1;
+            stats.steps[step].1.failure_count += 1;
+        }
+    }
+
+    pub fn scenario_finished(
+        &mut self,
+        index: usize,
+        scenario_start: Instant,
+        finish_time: Instant,
+        passed: bool,
+    ) {
+        if scenario_start < self.initial_time {
+            return;
+        }
+        let stats = &mut self.scenario_stats[index];
+        if !passed {
+            stats.failed_count += 1;
+            return;
+        }
+        stats.passed_count += 1;
+        let latency = finish_time.duration_since(scenario_start).as_micros() as u64;
+        if stats.latency_hist.record(latency).is_err() {
+            warn!("Failed to record latency: {}", latency);
+        }
+    }
+
+    pub fn set_success_codes(&mut self, success_codes: SuccessCodes) {
+        self.success_codes = success_codes;
+    }
+
+    pub fn success_codes(&self) -> &SuccessCodes {
+        &self.success_codes
+    }
+
+    pub fn type_name(&self, req_type: u32) -> String {
+        match self.type_names.get(&req_type) {
+            Some(name) => format!("{} ({})", name, req_type),
+            None => format!("type {}", req_type),
+        }
+    }
+
+    fn type_stats_mut(&mut self, req_type: u32) -> &mut RequestStats {
+        let hist_max = self.hist_max;
+        self.type_stats
+            .entry(req_type)
+            .or_insert_with(|| RequestStats::new(hist_max))
     }
 
     pub fn inc_bytes_send(&mut self, delta: usize) {
@@ -50,86 +441,286 @@ impl ExecutionInfo {
         self.bytes_recv += delta;
     }
 
-    pub fn new_request(&mut self, start_time: Instant) {
-        if start_time >= self.initial_time {
-            self.request_total += 1;
+    // Connect latency is recorded for all connections, including warm-up ones
+    pub fn connection_established(&mut self, start_time: Instant, finish_time: Instant) {
+        let latency: u64 = finish_time.duration_since(start_time).as_micros() as u64;
+        if self.connect_hist.record(latency).is_err() {
+            warn!("Failed to record connect latency: {}", latency);
+        }
+    }
+
+    pub fn new_request(&mut self, scheduled_time: Instant, start_time: Instant) {
+        if scheduled_time < self.initial_time {
+            return;
+        }
+        self.request_total += 1;
+        let delay: u64 = start_time.duration_since(scheduled_time).as_micros() as u64;
+        if delay > LATE_THRESHOLD_MICROS {
+            self.late_count += 1;
+        }
+        if self.send_delay_hist.record(delay).is_err() {
+            warn!("Failed to record send delay: {}", delay);
+        }
+    }
+
+    pub fn request_dropped(&mut self, scheduled_time: Instant) {
+        if scheduled_time >= self.initial_time {
+            self.dropped_count += 1;
         }
     }
 
     fn record_request(&mut self, req_type: u32, start_time: Instant, finish_time: Instant) {
         let latency: u64 = finish_time.duration_since(start_time).as_micros() as u64;
-        if !self.latency_hist.record(latency).is_ok() {
+        if self.latency_hist.record(latency).is_err() {
+            warn!("Failed to record latency: {}", latency);
+        }
+        if self
+            .type_stats_mut(req_type)
+            .latency_hist
+            .record(latency)
+            .is_err()
+        {
             warn!("Failed to record latency: {}", latency);
         }
-        if self.trace_sample_ratio > 0.0 {
-            let start_timestamp = start_time.duration_since(self.initial_time).as_micros() as u32;
-            let finish_timestamp = finish_time.duration_since(self.initial_time).as_micros() as u32;
-            if rand::thread_rng().gen_range(0.0..1.0) < self.trace_sample_ratio {
-                self.traces
-                    .push((req_type, start_timestamp, finish_timestamp));
+        if let Some(phase) = self.phase_stats_mut(start_time) {
+            if phase.latency_hist.record(latency).is_err() {
+                warn!("Failed to record latency: {}", latency);
+            }
+        }
+        if let Some(interval) = self.interval_mut(finish_time) {
+            if interval.latency_hist.record(latency).is_err() {
+                warn!("Failed to record latency: {}", latency);
             }
         }
     }
 
-    pub fn request_finished(&mut self, req_type: u32, start_time: Instant, finish_time: Instant) {
+    pub fn request_finished(
+        &mut self,
+        req_type: u32,
+        status: u16,
+        start_time: Instant,
+        finish_time: Instant,
+    ) {
         if start_time < self.initial_time {
             return;
         }
-        self.success_count += 1;
+        *self.status_counts.entry(status).or_insert(0) += 1;
+        let success = self.success_codes.contains(status);
+        if success {
+            self.success_count += 1;
+            self.type_stats_mut(req_type).success_count += 1;
+        } else {
+            self.failure_count += 1;
+            self.type_stats_mut(req_type).failure_count += 1;
+        }
+        if let Some(phase) = self.phase_stats_mut(start_time) {
+            if success {
+                phase.success_count += 1;
+            } else {
+                phase.failure_count += 1;
+            }
+        }
+        if let Some(interval) = self.interval_mut(finish_time) {
+            if success {
+                interval.success_count += 1;
+            } else {
+                interval.failure_count += 1;
+            }
+        }
         self.record_request(req_type, start_time, finish_time);
     }
 
-    pub fn request_failed(&mut self, req_type: u32, start_time: Instant, finish_time: Instant) {
-        if start_time < self.initial_time {
+    pub fn connection_error(&mut self, kind: ConnError) {
+        let now = Instant::now();
+        if now < self.initial_time {
             return;
         }
-        self.failure_count += 1;
-        self.record_request(req_type, start_time, finish_time);
+        if let Some(interval) = self.interval_mut(now) {
+            interval.error_count += 1;
+        }
+        match kind {
+            ConnError::Connect => self.connect_error_count += 1,
+            ConnError::Reset => self.reset_count += 1,
+            ConnError::ReadClosed => self.read_closed_count += 1,
+            ConnError::WriteClosed => self.write_closed_count += 1,
+            ConnError::Other => self.conn_error_count += 1,
+        }
     }
 
-    pub fn connection_error(&mut self) {
-        if Instant::now() >= self.initial_time {
-            self.conn_error_count += 1;
+    // Sum of all connection-level errors
+    pub fn error_count(&self) -> u32 {
+        self.connect_error_count
+            + self.reset_count
+            + self.read_closed_count
+            + self.write_closed_count
+            + self.conn_error_count
+            + self.parse_error_count
+            + self.timeout_count
+    }
+
+    pub fn request_timeout(&mut self, req_type: u32, start_time: Instant) {
+        if start_time >= self.initial_time {
+            self.timeout_count += 1;
+            self.type_stats_mut(req_type).timeout_count += 1;
+            if let Some(phase) = self.phase_stats_mut(start_time) {
+                phase.timeout_count += 1;
+            }
+            if let Some(interval) = self.interval_mut(Instant::now()) {
+                interval.timeout_count += 1;
+            }
         }
     }
 
     pub fn parse_error(&mut self) {
-        if Instant::now() >= self.initial_time {
+        let now = Instant::now();
+        if now >= self.initial_time {
             self.parse_error_count += 1;
+            if let Some(interval) = self.interval_mut(now) {
+                interval.error_count += 1;
+            }
         }
     }
 
-    pub fn save_trace(&self, save_path: &str) -> io::Result<()> {
-        let f = File::create(save_path)?;
-        let mut encoder = zstd::stream::Encoder::new(
-            BufWriter::with_capacity(1024 * 1024 * 16, f),
-            /* level= */ 0,
-        )
-        .unwrap();
-
-        write!(&mut encoder, "[").unwrap();
-        let mut first = true;
-        for &trace in self.traces.iter() {
-            if first {
-                write!(
-                    &mut encoder,
-                    "{{\"type\":{},\"start\":{},\"finish\":{}}}",
-                    trace.0, trace.1, trace.2
-                )
-                .unwrap();
-                first = false;
-            } else {
-                write!(
-                    &mut encoder,
-                    ",{{\"type\":{},\"start\":{},\"finish\":{}}}",
-                    trace.0, trace.1, trace.2
-                )
-                .unwrap();
+    // Fold in the results of another event loop of the same run
+    pub fn merge(&mut self, other: ExecutionInfo) {
+        if self.intervals.len() < other.intervals.len() {
+            self.intervals
+                .resize_with(other.intervals.len(), IntervalStats::new);
+        }
+        for (interval, other_interval) in self.intervals.iter_mut().zip(other.intervals.iter()) {
+            interval.merge(other_interval);
+        }
+        for (req_type, name) in other.type_names {
+            self.type_names.entry(req_type).or_insert(name);
+        }
+        for (req_type, stats) in other.type_stats {
+            self.type_stats_mut(req_type).merge(stats);
+        }
+        for (phase, other_phase) in self.phase_stats.iter_mut().zip(other.phase_stats) {
+            phase.merge(other_phase);
+        }
+        for (scenario, other_scenario) in self.scenario_stats.iter_mut().zip(other.scenario_stats) {
+            scenario.merge(other_scenario);
+        }
+        for (hist, other_hist) in [
+            (&mut self.latency_hist, &other.latency_hist),
+            (&mut self.connect_hist, &other.connect_hist),
+            (&mut self.send_delay_hist, &other.send_delay_hist),
+        ] {
+            if hist.add(other_hist).is_err() {
+                warn!("Failed to merge histograms");
             }
         }
-        write!(&mut encoder, "]").unwrap();
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_recv += other.bytes_recv;
+        self.request_total += other.request_total;
+        for (status, count) in other.status_counts {
+            *self.status_counts.entry(status).or_insert(0) += count;
+        }
+        self.success_count += other.success_count;
+        self.failure_count += other.failure_count;
+        self.connect_error_count += other.connect_error_count;
+        self.reset_count += other.reset_count;
+        self.read_closed_count += other.read_closed_count;
+        self.write_closed_count += other.write_closed_count;
+        self.conn_error_count += other.conn_error_count;
+        self.parse_error_count += other.parse_error_count;
+        self.timeout_count += other.timeout_count;
+        self.late_count += other.late_count;
+        self.dropped_count += other.dropped_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_event_loops() {
+        let t0 = Instant::now();
+        let at = |millis: u64| t0 + Duration::from_millis(millis);
+        let new = |names: &[(u32, &str)]| {
+            let mut exec_info = ExecutionInfo::new(1000);
+            exec_info.set_initial_time(t0);
+            exec_info.set_report_interval(Duration::from_secs(1));
+            for &(req_type, name) in names.iter() {
+                exec_info.type_names.insert(req_type, name.to_string());
+            }
+            exec_info
+        };
+        let mut merged = new(&[(0, "login")]);
+        merged.new_request(at(0), at(0));
+        merged.request_finished(0, 200, at(0), at(1));
+        merged.new_request(at(1500), at(1500));
+        merged.request_finished(1, 500, at(1500), at(1505));
+        merged.connection_error(ConnError::Reset);
+        let mut other = new(&[(0, "other"), (1, "search")]);
+        other.new_request(at(2500), at(2500));
+        other.request_finished(1, 200, at(2500), at(2502));
+        other.request_dropped(at(2600));
+        other.request_timeout(1, at(2700));
+        merged.merge(other);
+
+        assert_eq!(merged.request_total, 3);
+        assert_eq!(merged.success_count, 2);
+        assert_eq!(merged.failure_count, 1);
+        assert_eq!(merged.reset_count, 1);
+        assert_eq!(merged.dropped_count, 1);
+        assert_eq!(merged.timeout_count, 1);
+        assert_eq!(merged.error_count(), 2);
+        assert_eq!(
+            merged.status_counts.into_iter().collect::<Vec<_>>(),
+            vec![(200, 2), (500, 1)]
+        );
+        assert_eq!(merged.type_names[&0], "login");
+        assert_eq!(merged.type_names[&1], "search");
+        let types = merged
+            .type_stats
+            .iter()
+            .map(|(&req_type, stats)| {
+                (
+                    req_type,
+                    stats.success_count,
+                    stats.failure_count,
+                    stats.timeout_count,
+                    stats.latency_hist.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![(0, 1, 0, 0, 1), (1, 1, 1, 1, 2)]);
+        assert_eq!(merged.latency_hist.len(), 3);
+        // Latencies past hist_max still fit, histograms grow
+        let max = merged.latency_hist.max();
+        assert!(merged.latency_hist.equivalent(max, 5000), "{}", max);
+        let intervals = merged
+            .intervals
+            .iter()
+            .map(|interval| (interval.success_count, interval.failure_count))
+            .collect::<Vec<_>>();
+        assert_eq!(intervals, vec![(1, 0), (0, 1), (1, 0)]);
+    }
 
-        let mut w = encoder.finish()?;
-        w.flush()
+    #[test]
+    fn counts_late_and_dropped_requests() {
+        let t0 = Instant::now();
+        let at = |micros: u64| t0 + Duration::from_micros(micros);
+        let mut exec_info = ExecutionInfo::new(1000);
+        exec_info.set_initial_time(at(1_000_000));
+        // Scheduled during warm-up, not counted
+        exec_info.new_request(at(0), at(5_000));
+        exec_info.request_dropped(at(999_999));
+        // Sent within the late threshold, at it and past it
+        exec_info.new_request(at(1_000_000), at(1_000_000));
+        exec_info.new_request(at(1_100_000), at(1_101_000));
+        exec_info.new_request(at(1_200_000), at(1_201_001));
+        exec_info.new_request(at(1_300_000), at(1_800_000));
+        exec_info.request_dropped(at(1_000_000));
+        exec_info.request_dropped(at(2_000_000));
+        assert_eq!(exec_info.request_total, 4);
+        assert_eq!(exec_info.late_count, 2);
+        assert_eq!(exec_info.dropped_count, 2);
+        let hist = &exec_info.send_delay_hist;
+        assert_eq!(hist.len(), 4);
+        assert!(hist.equivalent(hist.max(), 500_000));
     }
 }
@@ -1,18 +1,51 @@
-use std::collections::VecDeque;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::{self, Write};
 use std::iter;
 use std::sync::{atomic, Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use log::*;
 use quick_js::{self, JsValue};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-static JS_LIB_CODE: &'static str = include_str!("lib.js");
+use crate::data::{self, Dataset};
+use crate::http::Response;
+use crate::replay::ReplayEntry;
+use crate::runner::derive_seed;
+
+static JS_LIB_CODE: &str = include_str!("lib.js");
 
 pub struct Request {
     pub input: Bytes,
     pub req_type: u32,
+    pub is_head: bool,
+    // Overrides the think time after this request in closed-loop mode
+    pub think_time: Option<Duration>,
+    // Set if the script defines onResponse
+    pub origin: Option<Origin>,
+}
+
+/// What follows the response to a scenario step.
+pub enum NextStep {
+    Request(Request),
+    // The scenario ended, passed unless a step failed
+    Done(bool),
+}
+
+/// Names of a scenario defined with `flood.scenario` and of its steps.
+pub struct ScenarioDef {
+    pub name: String,
+    pub steps: Vec<String>,
+}
+
+/// The object a script returned for a request, passed to `onResponse` along
+/// with the response.
+pub struct Origin {
+    object: HashMap<String, JsValue>,
 }
 
 #[derive(Debug)]
@@ -45,7 +78,7 @@ struct RequestQueue {
 impl RequestQueue {
     pub fn new(capacity: usize) -> RequestQueue {
         Self {
-            capacity: capacity,
+            capacity,
             queue: Mutex::new(VecDeque::<Request>::with_capacity(capacity)),
             cond: Condvar::new(),
             waiter: atomic::AtomicUsize::new(0),
@@ -86,11 +119,20 @@ impl RequestQueue {
 
 pub struct Generator {
     host: String,
+    // Requests are made on the event loop if there are no JS threads
     num_threads: usize,
     thread_control: Arc<atomic::AtomicBool>,
     threads: Vec<thread::JoinHandle<()>>,
     queue: Arc<RequestQueue>,
     js_context: quick_js::Context,
+    // Datasets of --data, shared with every JS thread
+    data: Arc<Vec<Dataset>>,
+    // Requests of a replayed log, handed out in send order
+    replay: VecDeque<ReplayEntry>,
+    has_replay_request: bool,
+    has_response_hook: bool,
+    // Scenarios replace newRequest and run on the context of the event loop
+    has_scenarios: bool,
 }
 
 macro_rules! expect_js_int {
@@ -115,6 +157,17 @@ macro_rules! expect_js_str {
     };
 }
 
+macro_rules! expect_js_array {
+    ($value:expr, $msg:expr) => {
+        match $value {
+            JsValue::Array(array) => array,
+            _ => {
+                return Err(Error::InvalidScript($msg.to_string()));
+            }
+        }
+    };
+}
+
 macro_rules! expect_js_obj {
     ($value:expr, $msg:expr) => {
         match $value {
@@ -137,44 +190,226 @@ impl Drop for Generator {
 }
 
 impl Generator {
-    pub fn new(host: &str, num_threads: usize, max_qsize: usize) -> Generator {
+    pub fn new(
+        host: &str,
+        num_threads: usize,
+        max_qsize: usize,
+        data: Arc<Vec<Dataset>>,
+    ) -> Generator {
         let js_context = quick_js::Context::new().unwrap();
         js_context.eval(JS_LIB_CODE).unwrap();
+        data::install(&js_context, &data).unwrap();
         Self {
             host: String::from(host),
-            num_threads: num_threads,
+            num_threads,
             thread_control: Arc::new(atomic::AtomicBool::new(false)),
             threads: Vec::<thread::JoinHandle<()>>::with_capacity(num_threads),
             queue: Arc::new(RequestQueue::new(max_qsize)),
-            js_context: js_context,
+            js_context,
+            data,
+            replay: VecDeque::new(),
+            has_replay_request: false,
+            has_response_hook: false,
+            has_scenarios: false,
         }
     }
 
-    fn test_user_script(&self, user_script: &str) -> Result<()> {
+    /// Makes the requests of the script reproducible, before it is loaded:
+    /// Math.random draws from stream 0 of the event loop's `seed`.
+    pub fn set_seed(&self, seed: u64) -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(derive_seed(seed, 0));
+        let args = (0..4).map(|_| JsValue::Int(rng.gen()));
+        self.js_context
+            .call_function("floodSeedRandom", args)
+            .map_err(Error::JsExecError)?;
+        Ok(())
+    }
+
+    fn test_user_script(&mut self, user_script: &str) -> Result<()> {
         if let Err(js_err) = self.js_context.eval(user_script) {
             return Err(Error::JsExecError(js_err));
         }
-        if let Err(err) = Generator::new_request("test.com", &self.js_context) {
-            return Err(err);
+        let defined = self
+            .js_context
+            .eval("flood._scenarios.length > 0")
+            .map_err(Error::JsExecError)?;
+        self.has_scenarios = defined == JsValue::Bool(true);
+        self.js_context
+            .eval("floodDataPeek = true")
+            .map_err(Error::JsExecError)?;
+        if self.has_scenarios {
+            self.test_scenarios()?;
+        } else {
+            Generator::new_request("test.com", &self.js_context, false)?;
+        }
+        self.js_context
+            .eval("floodDataPeek = false")
+            .map_err(Error::JsExecError)?;
+        Ok(())
+    }
+
+    // Every scenario needs steps, and its first request has to be valid
+    fn test_scenarios(&mut self) -> Result<()> {
+        let scenarios = self.scenarios()?;
+        let empty_args = iter::empty::<JsValue>();
+        let value = self
+            .js_context
+            .call_function("floodTestScenarios", empty_args)
+            .map_err(Error::JsExecError)?;
+        let requests = expect_js_array!(value, "floodTestScenarios must return an array");
+        for (scenario, request) in scenarios.iter().zip(requests) {
+            if scenario.steps.is_empty() {
+                return Err(Error::InvalidScript(format!(
+                    "scenario `{}` has no steps",
+                    scenario.name
+                )));
+            }
+            let request = expect_js_obj!(request, "scenario steps must return a request object");
+            Generator::build_request("test.com", &request)?;
         }
         Ok(())
     }
 
+    pub fn has_scenarios(&self) -> bool {
+        self.has_scenarios
+    }
+
+    // Scenarios in the order of `flood.scenario` calls
+    pub fn scenarios(&self) -> Result<Vec<ScenarioDef>> {
+        let empty_args = iter::empty::<JsValue>();
+        let value = self
+            .js_context
+            .call_function("floodScenarioNames", empty_args)
+            .map_err(Error::JsExecError)?;
+        let mut scenarios = Vec::new();
+        for scenario in expect_js_array!(value, "scenarios must be an array") {
+            let mut scenario = expect_js_obj!(scenario, "scenario must be an object");
+            let name = expect_js_str!(
+                scenario.remove("name").unwrap_or(JsValue::Undefined),
+                "scenario name must be a string"
+            );
+            let mut steps = Vec::new();
+            for step in expect_js_array!(
+                scenario.remove("steps").unwrap_or(JsValue::Undefined),
+                "scenario steps must be an array"
+            ) {
+                steps.push(expect_js_str!(step, "step name must be a string"));
+            }
+            scenarios.push(ScenarioDef { name, steps });
+        }
+        Ok(scenarios)
+    }
+
+    /// Starts a scenario picked by weight. Returns its index, with its session
+    /// and first request unless the script failed to make that request.
+    pub fn start_scenario(&mut self) -> Result<(usize, Result<(i32, Request)>)> {
+        let empty_args = iter::empty::<JsValue>();
+        let value = self
+            .js_context
+            .call_function("floodStartScenario", empty_args)
+            .map_err(Error::JsExecError)?;
+        let mut started = expect_js_obj!(value, "floodStartScenario must return an object");
+        let session = expect_js_int!(
+            started.get("session").unwrap_or(&JsValue::Undefined),
+            "session must be an integer"
+        );
+        let index = expect_js_int!(
+            started.get("scenario").unwrap_or(&JsValue::Undefined),
+            "scenario must be an integer"
+        ) as usize;
+        if let Some(JsValue::String(err)) = started.get("error") {
+            return Ok((index, Err(Error::InvalidScript(err.clone()))));
+        }
+        let req = match started.remove("request") {
+            Some(JsValue::Object(request)) => Generator::build_request(&self.host, &request),
+            _ => Err(Error::InvalidScript(
+                "scenario steps must return a request object".to_string(),
+            )),
+        };
+        if req.is_err() {
+            self.end_scenario(session);
+        }
+        Ok((index, req.map(|req| (session, req))))
+    }
+
+    // Checks the response to the current step of `session`, then makes the next request
+    pub fn next_step(&mut self, session: i32, response: Response) -> Result<NextStep> {
+        let args = vec![JsValue::Int(session), Generator::response_value(response)];
+        let value = self
+            .js_context
+            .call_function("floodNextStep", args)
+            .map_err(Error::JsExecError)?;
+        let mut next = expect_js_obj!(value, "floodNextStep must return an object");
+        if let Some(request) = next.remove("request") {
+            let request = expect_js_obj!(request, "scenario steps must return a request object");
+            return Ok(NextStep::Request(Generator::build_request(
+                &self.host, &request,
+            )?));
+        }
+        if let Some(JsValue::String(err)) = next.get("error") {
+            warn!("Scenario step failed: {}", err);
+        }
+        Ok(NextStep::Done(
+            next.get("passed") == Some(&JsValue::Bool(true)),
+        ))
+    }
+
+    // Forgets a scenario that will not go on
+    pub fn end_scenario(&mut self, session: i32) {
+        let args = iter::once(JsValue::Int(session));
+        if let Err(js_err) = self.js_context.call_function("floodEndScenario", args) {
+            warn!("Failed to end scenario: {}", js_err);
+        }
+    }
+
+    // Names given to request types through `flood.typeNames`
+    pub fn type_names(&self) -> Result<BTreeMap<u32, String>> {
+        let value = self
+            .js_context
+            .eval("flood.typeNames")
+            .map_err(Error::JsExecError)?;
+        let names = expect_js_obj!(value, "`flood.typeNames` must be an object");
+        let mut type_names = BTreeMap::new();
+        for (key, value) in names.into_iter() {
+            let req_type = key.parse::<u32>().map_err(|_| {
+                Error::InvalidScript(format!("`flood.typeNames` has invalid type `{}`", key))
+            })?;
+            let name = expect_js_str!(value, "type name must be a string");
+            type_names.insert(req_type, name);
+        }
+        Ok(type_names)
+    }
+
     pub fn load_user_script(&mut self, user_script: &str) -> Result<()> {
         self.test_user_script(user_script)?;
+        if self.has_scenarios {
+            return Ok(());
+        }
+        let defined = self
+            .js_context
+            .eval("typeof onResponse === 'function'")
+            .map_err(Error::JsExecError)?;
+        self.has_response_hook = defined == JsValue::Bool(true);
+        // Requests have to see the responses before them, so they are made on
+        // the event loop once due rather than ahead of time by JS threads
+        if self.has_response_hook {
+            self.num_threads = 0;
+        }
         self.thread_control.store(true, atomic::Ordering::SeqCst);
         for i in 0..self.num_threads {
             let control = self.thread_control.clone();
             let queue = self.queue.clone();
             let user_script = String::from(user_script);
             let host = self.host.clone();
+            let data = self.data.clone();
             let thread = thread::spawn(move || {
                 info!("{}-th JS thread starts", i);
                 let js_context = quick_js::Context::new().unwrap();
                 js_context.eval(JS_LIB_CODE).unwrap();
+                data::install(&js_context, &data).unwrap();
                 js_context.eval(&user_script).unwrap();
                 while control.load(atomic::Ordering::SeqCst) {
-                    let req = Generator::new_request(&host, &js_context).unwrap();
+                    let req = Generator::new_request(&host, &js_context, false).unwrap();
                     queue.push(req);
                 }
             });
@@ -183,14 +418,61 @@ impl Generator {
         Ok(())
     }
 
-    fn new_request(host: &str, js_context: &quick_js::Context) -> Result<Request> {
+    /// Replays `entries` ahead of generated requests. Entries without request
+    /// bytes come from `replayRequest(type)` if the script defines it.
+    pub fn set_replay(&mut self, entries: Vec<ReplayEntry>) -> Result<()> {
+        let defined = self
+            .js_context
+            .eval("typeof replayRequest === 'function'")
+            .map_err(Error::JsExecError)?;
+        self.has_replay_request = defined == JsValue::Bool(true);
+        if self.has_scenarios {
+            return Err(Error::InvalidScript(
+                "scenarios cannot be replayed".to_string(),
+            ));
+        }
+        if !self.has_replay_request && entries.iter().any(|entry| entry.input.is_none()) {
+            warn!("Script has no replayRequest(type), replayed types come from newRequest");
+        }
+        self.replay = entries.into();
+        Ok(())
+    }
+
+    fn new_request(
+        host: &str,
+        js_context: &quick_js::Context,
+        keep_origin: bool,
+    ) -> Result<Request> {
         let empty_args = iter::empty::<JsValue>();
-        let request = match js_context.call_function("newRequest", empty_args) {
+        let request = match js_context.call_function("floodNewRequest", empty_args) {
             Ok(value) => expect_js_obj!(value, "newRequest must return an object"),
             Err(js_err) => {
                 return Err(Error::JsExecError(js_err));
             }
         };
+        let mut req = Generator::build_request(host, &request)?;
+        if keep_origin {
+            req.origin = Some(Origin { object: request });
+        }
+        Ok(req)
+    }
+
+    fn replay_request(&self, req_type: u32) -> Result<Request> {
+        let args = iter::once(JsValue::Int(req_type as i32));
+        let request = match self.js_context.call_function("floodReplayRequest", args) {
+            Ok(value) => expect_js_obj!(value, "replayRequest must return an object"),
+            Err(js_err) => {
+                return Err(Error::JsExecError(js_err));
+            }
+        };
+        let mut req = Generator::build_request(&self.host, &request)?;
+        if self.has_response_hook {
+            req.origin = Some(Origin { object: request });
+        }
+        Ok(req)
+    }
+
+    fn build_request(host: &str, request: &HashMap<String, JsValue>) -> Result<Request> {
         for &key in ["type", "method", "path", "headers"].iter() {
             if !request.contains_key(key) {
                 return Err(Error::InvalidScript(format!(
@@ -200,11 +482,12 @@ impl Generator {
             }
         }
         let req_type = expect_js_int!(request.get("type").unwrap(), "`type` must be an integer");
+        let method = expect_js_str!(request.get("method").unwrap(), "`method` must be a string");
         let mut data = BytesMut::with_capacity(256);
         write!(
             &mut data,
             "{} {} HTTP/1.1\r\n",
-            expect_js_str!(request.get("method").unwrap(), "`method` must be a string"),
+            method,
             expect_js_str!(request.get("path").unwrap(), "`path` must be a string")
         )
         .unwrap();
@@ -241,29 +524,423 @@ impl Generator {
         if !has_user_agent {
             write!(&mut data, "User-Agent: flood\r\n").unwrap();
         }
+        // Binary bodies come from lib.js as `{ base64 }`
+        let body = match request.get("body") {
+            None => None,
+            Some(JsValue::String(body)) => Some((body.as_bytes().to_vec(), false)),
+            Some(JsValue::Object(obj)) => match obj.get("base64") {
+                Some(JsValue::String(encoded)) => {
+                    let body = base64::decode(encoded).map_err(|err| {
+                        Error::InvalidScript(format!("Invalid base64 `body`: {}", err))
+                    })?;
+                    Some((body, true))
+                }
+                _ => {
+                    return Err(Error::InvalidScript(
+                        "`body` object must be a `{ base64 }`".to_string(),
+                    ))
+                }
+            },
+            Some(_) => {
+                return Err(Error::InvalidScript(
+                    "`body` must be a string, Uint8Array, ArrayBuffer or `{ base64 }`".to_string(),
+                ))
+            }
+        };
         if !has_content_type {
-            write!(&mut data, "Content-Type: text/plain\r\n").unwrap();
+            match body {
+                Some((_, true)) => {
+                    write!(&mut data, "Content-Type: application/octet-stream\r\n").unwrap()
+                }
+                _ => write!(&mut data, "Content-Type: text/plain\r\n").unwrap(),
+            }
         }
 
-        if request.contains_key("body") {
-            let body = expect_js_str!(request.get("body").unwrap(), "`body` must be a string");
+        if let Some((body, _)) = body {
             write!(&mut data, "Content-Length: {}\r\n\r\n", body.len()).unwrap();
-            data.put_slice(body.as_bytes());
+            data.put_slice(&body);
         } else {
             write!(&mut data, "\r\n").unwrap();
         }
 
+        let think_time = match request.get("thinkTime") {
+            None | Some(JsValue::Undefined) | Some(JsValue::Null) => None,
+            Some(JsValue::Int(ms)) if *ms >= 0 => Some(Duration::from_millis(*ms as u64)),
+            Some(JsValue::Float(ms)) if *ms >= 0.0 && ms.is_finite() => {
+                Some(Duration::from_secs_f64(*ms / 1000.0))
+            }
+            _ => {
+                return Err(Error::InvalidScript(
+                    "`thinkTime` must be a non-negative number of milliseconds".to_string(),
+                ))
+            }
+        };
+
         Ok(Request {
             input: data.freeze(),
             req_type: req_type as u32,
+            is_head: method.eq_ignore_ascii_case("HEAD"),
+            think_time,
+            origin: None,
         })
     }
 
-    pub fn get(&mut self) -> Request {
-        if let Some(req) = self.queue.pop() {
-            return req;
+    // `{ status, headers, body }` as scripts see it, duplicate headers joined by commas
+    fn response_value(response: Response) -> JsValue {
+        let mut headers = HashMap::<String, String>::new();
+        for (name, value) in response.headers {
+            match headers.entry(name) {
+                Entry::Occupied(mut entry) => {
+                    let joined = entry.get_mut();
+                    joined.push_str(", ");
+                    joined.push_str(&value);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+            }
+        }
+        let mut resp = HashMap::new();
+        resp.insert("status".to_string(), JsValue::Int(response.status as i32));
+        resp.insert(
+            "headers".to_string(),
+            JsValue::Object(
+                headers
+                    .into_iter()
+                    .map(|(name, value)| (name, JsValue::String(value)))
+                    .collect(),
+            ),
+        );
+        resp.insert(
+            "body".to_string(),
+            JsValue::String(String::from_utf8_lossy(&response.body).into_owned()),
+        );
+        JsValue::Object(resp)
+    }
+
+    // Passes a response to the `onResponse` hook of the script
+    pub fn on_response(&self, origin: Origin, response: Response) {
+        let args = vec![
+            JsValue::Object(origin.object),
+            Generator::response_value(response),
+        ];
+        if let Err(js_err) = self.js_context.call_function("onResponse", args) {
+            warn!("onResponse failed: {}", js_err);
+        }
+    }
+
+    pub fn get(&mut self) -> Result<Request> {
+        if let Some(entry) = self.replay.pop_front() {
+            match entry.input {
+                Some(input) => {
+                    return Ok(Request {
+                        is_head: input.starts_with(b"HEAD "),
+                        input,
+                        req_type: entry.req_type,
+                        think_time: None,
+                        origin: None,
+                    })
+                }
+                None if self.has_replay_request => return self.replay_request(entry.req_type),
+                None => {}
+            }
+        }
+        if self.num_threads > 0 {
+            if let Some(req) = self.queue.pop() {
+                return Ok(req);
+            }
+            warn!("JS threads failed to generate enough request data");
+        }
+        Generator::new_request(&self.host, &self.js_context, self.has_response_hook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generates on the calling thread, as seeded runs and `onResponse` do
+    fn generator(script: &str, seed: Option<u64>) -> Generator {
+        let mut generator = Generator::new("test.com", 0, 16, Arc::new(Vec::new()));
+        if let Some(seed) = seed {
+            generator.set_seed(seed).unwrap();
+        }
+        generator.load_user_script(script).unwrap();
+        generator
+    }
+
+    fn requests(generator: &mut Generator, count: usize) -> Vec<Bytes> {
+        (0..count).map(|_| generator.get().unwrap().input).collect()
+    }
+
+    const RANDOM_SCRIPT: &str = "function newRequest() { \
+        return flood.doGet({ type: flood.randInt(0, 3), path: '/' + flood.randAlphabetString(8) }); }";
+
+    #[test]
+    fn repeats_seeded_draws() {
+        let draws = |seed| {
+            let generator = generator(RANDOM_SCRIPT, Some(seed));
+            (0..16)
+                .map(
+                    |_| match generator.js_context.eval("Math.random()").unwrap() {
+                        JsValue::Float(x) => x,
+                        value => panic!("Math.random() returned {:?}", value),
+                    },
+                )
+                .collect::<Vec<_>>()
+        };
+        let first = draws(7);
+        assert_eq!(first, draws(7));
+        assert_ne!(first, draws(8));
+        assert!(first.iter().all(|x| (0.0..1.0).contains(x)));
+    }
+
+    #[test]
+    fn repeats_seeded_requests() {
+        let first = requests(&mut generator(RANDOM_SCRIPT, Some(7)), 64);
+        assert_eq!(first, requests(&mut generator(RANDOM_SCRIPT, Some(7)), 64));
+        assert_ne!(first, requests(&mut generator(RANDOM_SCRIPT, Some(8)), 64));
+        assert_ne!(first, requests(&mut generator(RANDOM_SCRIPT, None), 64));
+    }
+
+    #[test]
+    fn reads_think_times_of_requests() {
+        // Loading the script tries the first request
+        let script = "let i = 0; \
+            const cases = [{}, { thinkTime: 250 }, { thinkTime: 1.5 }, {}, { thinkTime: 0 }, \
+                { thinkTime: null }, { thinkTime: -1 }, { thinkTime: '1s' }]; \
+            function newRequest() { \
+                return i % 2 ? flood.doGet(cases[i++]) : flood.doPost(cases[i++]); }";
+        let mut generator = generator(script, None);
+        let cases = [
+            Some(Some(Duration::from_millis(250))),
+            Some(Some(Duration::from_micros(1500))),
+            Some(None),
+            Some(Some(Duration::ZERO)),
+            Some(None),
+            None,
+            None,
+        ];
+        for (i, think_time) in cases.iter().enumerate() {
+            let request = generator.get();
+            assert_eq!(request.ok().map(|r| r.think_time), *think_time, "{}", i);
+        }
+    }
+
+    #[test]
+    fn sends_binary_bodies() {
+        // Loading the script tries the first request
+        let script = "let i = 0; \
+            const post = body => ({ type: 0, method: 'POST', path: '/', headers: {}, body }); \
+            const cases = [null, \
+                post(Uint8Array.of(0, 1, 0xff)), \
+                post(Uint16Array.of(0x0102, 0x0304)), \
+                post(Uint8Array.of(1, 2, 3).buffer), \
+                post(new DataView(Uint8Array.of(9, 8, 7, 6).buffer, 1, 2)), \
+                post(flood.base64('AAEC/w==')), \
+                post(new Uint8Array(0)), \
+                post('héllo'), \
+                flood.doPost({ bytes: [0x08, 0x96, 0x01], \
+                    headers: { 'Content-Type': 'application/x-protobuf' } }), \
+                post(flood.bytesWriter().u8(0x1ff).u16(0x0102).u16(0x0102, true) \
+                    .u32(0x01020304).u32(0x01020304, true) \
+                    .varint(1).varint(300).varint(2 ** 32).string('é€\u{1f600}') \
+                    .bytes([0xff]).finish()), \
+            ]; \
+            function newRequest() { return cases[i++] || post(''); }";
+        let request = |content_type: &str, body: &[u8]| {
+            let mut request = format!(
+                "POST / HTTP/1.1\r\nHost: test.com\r\nConnection: keep-alive\r\n\
+                 Accept: */*\r\nUser-Agent: flood\r\nContent-Type: {}\r\n\
+                 Content-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(body);
+            request
+        };
+        let binary = |body: &[u8]| request("application/octet-stream", body);
+        let cases = [
+            binary(&[0, 1, 0xff]),
+            // Typed arrays are sent in the byte order of the machine
+            binary(&[0x0102u16.to_ne_bytes(), 0x0304u16.to_ne_bytes()].concat()),
+            binary(&[1, 2, 3]),
+            binary(&[8, 7]),
+            binary(&[0, 1, 2, 0xff]),
+            binary(&[]),
+            request("text/plain", "h\u{e9}llo".as_bytes()),
+            // Headers of the script come before the default ones
+            [
+                &b"POST / HTTP/1.1\r\nHost: test.com\r\nConnection: keep-alive\r\n\
+                   Content-Type: application/x-protobuf\r\nAccept: */*\r\n\
+                   User-Agent: flood\r\nContent-Length: 3\r\n\r\n"[..],
+                &[0x08, 0x96, 0x01],
+            ]
+            .concat(),
+            binary(&[
+                0xff, // u8 keeps the low byte
+                0x01, 0x02, 0x02, 0x01, // u16 big and little-endian
+                0x01, 0x02, 0x03, 0x04, 0x04, 0x03, 0x02, 0x01, // u32
+                0x01, 0xac, 0x02, 0x80, 0x80, 0x80, 0x80, 0x10, // varints
+                0xc3, 0xa9, 0xe2, 0x82, 0xac, 0xf0, 0x9f, 0x98, 0x80, // UTF-8
+                0xff,
+            ]),
+        ];
+        let mut generator = generator(script, None);
+        for (i, expected) in cases.iter().enumerate() {
+            let input = generator.get().unwrap().input;
+            assert_eq!(
+                &input[..],
+                &expected[..],
+                "{}: {}",
+                i,
+                String::from_utf8_lossy(&input)
+            );
+        }
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &str) -> Response {
+        Response {
+            status,
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Bytes::from(body.to_string()),
         }
-        warn!("JS threads failed to generate enough request data");
-        Generator::new_request(&self.host, &self.js_context).unwrap()
+    }
+
+    fn request_line(request: &Request) -> String {
+        let input = String::from_utf8_lossy(&request.input);
+        input.lines().next().unwrap().to_string()
+    }
+
+    fn header(request: &Request, name: &str) -> Option<String> {
+        let input = String::from_utf8_lossy(&request.input);
+        let prefix = format!("{}: ", name);
+        input
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix).map(str::to_string))
+    }
+
+    // Sessions are forgotten once they end, so going on with them fails
+    fn ended(generator: &mut Generator, session: i32) -> bool {
+        generator
+            .next_step(session, response(200, &[], "{}"))
+            .is_err()
+    }
+
+    const SCENARIO_SCRIPT: &str = "flood.scenario('cart', [ \
+        { name: 'login', \
+          request: vars => flood.doPost({ path: '/login', json: { user: 'u' } }), \
+          check: (resp, vars) => { vars.token = JSON.parse(resp.body).token; \
+              return resp.status === 200; } }, \
+        { name: 'cart', \
+          request: vars => flood.doGet({ path: '/cart', \
+              headers: { Authorization: 'Bearer ' + vars.token } }), \
+          check: (resp, vars) => { if (resp.status >= 500) throw new Error('down'); \
+              return resp.headers['X-Items'] !== '0'; } }, \
+    ]);";
+
+    #[test]
+    fn runs_scenarios_step_by_step() {
+        let mut generator = generator(SCENARIO_SCRIPT, None);
+        assert!(generator.has_scenarios());
+        let scenarios = generator.scenarios().unwrap();
+        assert_eq!(scenarios.len(), 1);
+        assert_eq!(scenarios[0].name, "cart");
+        assert_eq!(scenarios[0].steps, vec!["login", "cart"]);
+
+        // Sessions interleave, each keeps its own vars
+        let mut sessions = Vec::new();
+        for token in ["a", "b"].iter() {
+            let (index, started) = generator.start_scenario().unwrap();
+            assert_eq!(index, 0);
+            let (session, request) = started.unwrap();
+            assert_eq!(request_line(&request), "POST /login HTTP/1.1");
+            sessions.push((session, *token));
+        }
+        for &(session, token) in sessions.iter() {
+            let body = format!("{{\"token\": \"{}\"}}", token);
+            match generator.next_step(session, response(200, &[], &body)) {
+                Ok(NextStep::Request(request)) => {
+                    assert_eq!(request_line(&request), "GET /cart HTTP/1.1");
+                    assert_eq!(
+                        header(&request, "Authorization"),
+                        Some(format!("Bearer {}", token))
+                    );
+                }
+                _ => panic!("No request for the second step"),
+            }
+        }
+        let (first, second) = (sessions[0].0, sessions[1].0);
+        let items = |count: &str| response(200, &[("X-Items", count)], "");
+        assert!(matches!(
+            generator.next_step(first, items("2")),
+            Ok(NextStep::Done(true))
+        ));
+        assert!(matches!(
+            generator.next_step(second, items("0")),
+            Ok(NextStep::Done(false))
+        ));
+        assert!(ended(&mut generator, first));
+        assert!(ended(&mut generator, second));
+    }
+
+    #[test]
+    fn fails_scenarios_on_checks() {
+        let mut generator = generator(SCENARIO_SCRIPT, None);
+        let cases: &[&[(u16, &str)]] = &[
+            // `check` returns false
+            &[(401, "{}")],
+            // `check` throws on a body that is not JSON
+            &[(200, "<html>")],
+            // `check` of the second step throws
+            &[(200, "{\"token\": \"t\"}"), (503, "")],
+        ];
+        for responses in cases.iter() {
+            let session = generator.start_scenario().unwrap().1.unwrap().0;
+            let mut next = None;
+            for &(status, body) in responses.iter() {
+                next = Some(generator.next_step(session, response(status, &[], body)));
+            }
+            assert!(
+                matches!(next, Some(Ok(NextStep::Done(false)))),
+                "{:?}",
+                responses
+            );
+            assert!(ended(&mut generator, session));
+        }
+
+        // Sessions dropped before their end are forgotten
+        let session = generator.start_scenario().unwrap().1.unwrap().0;
+        assert!(!ended(&mut generator, session));
+        let session = generator.start_scenario().unwrap().1.unwrap().0;
+        generator.end_scenario(session);
+        assert!(ended(&mut generator, session));
+    }
+
+    #[test]
+    fn passes_responses_to_the_hook() {
+        let script = "let seen = []; \
+            function newRequest() { \
+                return flood.doGet({ path: '/' + seen.length, headers: { 'X-Seen': seen.join('; ') } }); } \
+            function onResponse(req, resp) { \
+                seen.push([req.path, resp.status, resp.headers['Set-Cookie'], resp.body].join(' ')); }";
+        let mut generator = generator(script, None);
+        for (status, body) in [(200, "ok"), (404, "gone")].iter() {
+            let request = generator.get().unwrap();
+            let origin = request.origin.expect("Requests keep their origin");
+            let cookies = [("Set-Cookie", "a=1"), ("Set-Cookie", "b=2")];
+            generator.on_response(origin, response(*status, &cookies, body));
+        }
+        // Responses are seen before the next request is made
+        let request = generator.get().unwrap();
+        assert_eq!(request_line(&request), "GET /2 HTTP/1.1");
+        assert_eq!(
+            header(&request, "X-Seen").as_deref(),
+            Some("/0 200 a=1, b=2 ok; /1 404 a=1, b=2 gone")
+        );
     }
 }
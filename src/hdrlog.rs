@@ -0,0 +1,278 @@
+use crate::exec_info::ExecutionInfo;
+use crate::report;
+use crate::runner::Result;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+use hdrhistogram::serialization::interval_log::{
+    IntervalLogIterator, IntervalLogWriterBuilder, LogEntry,
+};
+use hdrhistogram::serialization::{Deserializer, V2DeflateSerializer};
+use hdrhistogram::Histogram;
+use structopt::StructOpt;
+
+// Java tools show the max column in milliseconds, histograms hold microseconds
+const MAX_VALUE_DIVISOR: f64 = 1000.0;
+
+// Interval timestamps far below StartTime are relative to it, as in HistogramLogReader
+const YEAR: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Merge HdrHistogram interval logs of several runs or machines
+#[derive(Debug, StructOpt)]
+pub struct MergeOpt {
+    /// Width of the merged intervals, aligned to the earliest interval of all logs
+    #[structopt(long = "interval", default_value = "1s")]
+    pub interval: String,
+
+    /// Path for saving the merged interval log
+    #[structopt(short = "o", long = "output", default_value = "")]
+    pub output: String,
+
+    /// Interval logs, e.g. written with --hdr-log
+    #[structopt(name = "LOG", required = true)]
+    pub logs: Vec<String>,
+}
+
+/// Writes the latency of every reporting interval of `exec_info` to `path`,
+/// histograms being V2 compressed.
+pub fn write_interval_log(path: &str, exec_info: &ExecutionInfo) -> Result<()> {
+    let interval = exec_info
+        .report_interval()
+        .ok_or("HdrHistogram logs need a --report-interval")?;
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut serializer = V2DeflateSerializer::new();
+    let start_time = exec_info.start_wall_time();
+    let mut writer = IntervalLogWriterBuilder::new()
+        .add_comment("Written by flood, values in microseconds")
+        .with_start_time(start_time)
+        .with_base_time(start_time)
+        .with_max_value_divisor(MAX_VALUE_DIVISOR)
+        .begin_log_with(&mut out, &mut serializer)?;
+    for (i, stats) in exec_info.intervals.iter().enumerate() {
+        writer
+            .write_histogram(&stats.latency_hist, interval * i as u32, interval, None)
+            .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+    }
+    drop(writer);
+    out.flush()?;
+    Ok(())
+}
+
+// Start time since the epoch, duration and histogram of every interval in `path`
+fn read_interval_log(path: &str) -> Result<Vec<(Duration, Duration, Histogram<u64>)>> {
+    let content = fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let mut deserializer = Deserializer::new();
+    let mut start_time = None;
+    let mut base_time = None;
+    let mut intervals = Vec::new();
+    for entry in IntervalLogIterator::new(&content) {
+        match entry.map_err(|err| format!("Invalid interval log {}: {:?}", path, err))? {
+            LogEntry::StartTime(t) => start_time = Some(t),
+            LogEntry::BaseTime(t) => base_time = Some(t),
+            LogEntry::Interval(interval) => {
+                let timestamp = interval.start_timestamp();
+                let offset = match (base_time, start_time) {
+                    (Some(base_time), _) => base_time,
+                    (None, Some(start_time)) if timestamp + YEAR < start_time => start_time,
+                    _ => Duration::ZERO,
+                };
+                let encoded = base64::decode(interval.encoded_histogram())
+                    .map_err(|err| format!("Invalid histogram in {}: {}", path, err))?;
+                let hist = deserializer
+                    .deserialize(&mut &encoded[..])
+                    .map_err(|err| format!("Invalid histogram in {}: {}", path, err))?;
+                intervals.push((offset + timestamp, interval.duration(), hist));
+            }
+        }
+    }
+    Ok(intervals)
+}
+
+fn new_hist() -> Histogram<u64> {
+    let mut hist = Histogram::<u64>::new(3).unwrap();
+    hist.auto(true);
+    hist
+}
+
+// Histograms of `intervals` summed per `interval` since `first`, by index
+fn merge_intervals(
+    intervals: &[(Duration, Duration, Histogram<u64>)],
+    first: Duration,
+    interval: Duration,
+) -> Result<BTreeMap<u64, Histogram<u64>>> {
+    let mut merged = BTreeMap::<u64, Histogram<u64>>::new();
+    for (start, _, hist) in intervals.iter() {
+        let index = ((*start - first).as_nanos() / interval.as_nanos()) as u64;
+        merged
+            .entry(index)
+            .or_insert_with(new_hist)
+            .add(hist)
+            .map_err(|err| format!("Failed to merge histograms: {:?}", err))?;
+    }
+    Ok(merged)
+}
+
+// Entry point of `flood merge`
+pub fn main(opt: MergeOpt) -> Result<()> {
+    let interval = humantime::parse_duration(&opt.interval)?;
+    if interval.is_zero() {
+        return Err("--interval must be positive".into());
+    }
+    let mut intervals = Vec::new();
+    for path in opt.logs.iter() {
+        intervals.extend(read_interval_log(path)?);
+    }
+    let first = intervals
+        .iter()
+        .map(|&(start, _, _)| start)
+        .min()
+        .ok_or("The logs have no intervals")?;
+    let last = intervals
+        .iter()
+        .map(|&(start, duration, _)| start + duration)
+        .max()
+        .unwrap();
+
+    let merged = merge_intervals(&intervals, first, interval)?;
+    let mut total = new_hist();
+    for hist in merged.values() {
+        total
+            .add(hist)
+            .map_err(|err| format!("Failed to merge histograms: {:?}", err))?;
+    }
+
+    if !opt.output.is_empty() {
+        let mut out = BufWriter::new(File::create(&opt.output)?);
+        let mut serializer = V2DeflateSerializer::new();
+        let start_time = UNIX_EPOCH + first;
+        let mut writer = IntervalLogWriterBuilder::new()
+            .add_comment(&format!("Merged by flood from {}", opt.logs.join(", ")))
+            .with_start_time(start_time)
+            .with_base_time(start_time)
+            .with_max_value_divisor(MAX_VALUE_DIVISOR)
+            .begin_log_with(&mut out, &mut serializer)?;
+        for (&index, hist) in merged.iter() {
+            writer
+                .write_histogram(hist, interval * index as u32, interval, None)
+                .map_err(|err| format!("Failed to write {}: {}", opt.output, err))?;
+        }
+        drop(writer);
+        out.flush()?;
+    }
+
+    let out = &mut io::stdout().lock();
+    writeln!(
+        out,
+        "Merged {} intervals of {} logs into {}",
+        intervals.len(),
+        opt.logs.len(),
+        merged.len()
+    )?;
+    writeln!(
+        out,
+        "  {} to {}, {} requests",
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + first),
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + last),
+        total.len()
+    )?;
+    if !total.is_empty() {
+        report::write_latency_distribution(out, &total)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec_info::IntervalStats;
+    use hdrhistogram::Counter;
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("flood-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Results of a run started at `start` since the epoch, with the latencies
+    // of every interval
+    fn exec_info(start: Duration, interval: Duration, latencies: &[&[u64]]) -> ExecutionInfo {
+        let mut exec_info = ExecutionInfo::new(1_000_000);
+        exec_info.set_start_wall_time(UNIX_EPOCH + start);
+        exec_info.set_report_interval(interval);
+        for values in latencies.iter() {
+            let mut stats = IntervalStats::new();
+            for &value in values.iter() {
+                stats.latency_hist.record(value).unwrap();
+            }
+            exec_info.intervals.push(stats);
+        }
+        exec_info
+    }
+
+    fn counts<T: Counter>(hist: &Histogram<T>) -> Vec<(u64, u64)> {
+        hist.iter_recorded()
+            .map(|value| (value.value_iterated_to(), value.count_at_value().as_u64()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_written_intervals() {
+        let start = Duration::from_millis(1_600_000_000_250);
+        let interval = Duration::from_millis(500);
+        let exec_info = exec_info(
+            start,
+            interval,
+            &[&[100, 200, 200], &[], &[1_000, 50_000, 2_000_000]],
+        );
+        let path = temp_path("roundtrip.hlog");
+        write_interval_log(&path, &exec_info).unwrap();
+        let intervals = read_interval_log(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(intervals.len(), 3);
+        for (i, (start_time, duration, hist)) in intervals.iter().enumerate() {
+            assert_eq!(*start_time, start + interval * i as u32);
+            assert_eq!(*duration, interval);
+            assert_eq!(counts(hist), counts(&exec_info.intervals[i].latency_hist));
+        }
+    }
+
+    #[test]
+    fn merges_aligned_intervals() {
+        let start = Duration::from_secs(1_600_000_000);
+        let second = Duration::from_secs(1);
+        let logs = [
+            (
+                temp_path("merge-a.hlog"),
+                exec_info(start, second, &[&[100], &[200], &[300]]),
+            ),
+            (
+                temp_path("merge-b.hlog"),
+                exec_info(start + second / 2, second, &[&[400], &[500, 600]]),
+            ),
+        ];
+        let mut intervals = Vec::new();
+        for (path, exec_info) in logs.iter() {
+            write_interval_log(path, exec_info).unwrap();
+            intervals.extend(read_interval_log(path).unwrap());
+            fs::remove_file(path).unwrap();
+        }
+        // Intervals starting within two seconds of the earliest one are merged
+        let merged = merge_intervals(&intervals, start, second * 2).unwrap();
+        let merged = merged
+            .iter()
+            .map(|(&index, hist)| (index, counts(hist)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            vec![
+                (0, vec![(100, 1), (200, 1), (400, 1), (500, 1), (600, 1)]),
+                (1, vec![(300, 1)]),
+            ]
+        );
+    }
+}
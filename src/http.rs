@@ -0,0 +1,667 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{Buf, Bytes, BytesMut};
+use httparse::{self, Status};
+use serde::{Serialize, Serializer};
+
+const MAX_HEADERS: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    HttpParse(httparse::Error),
+    InvalidContentLength,
+    InvalidChunk,
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::HttpParse(err) => write!(f, "Invalid response head: {}", err),
+            Error::InvalidContentLength => write!(f, "Invalid Content-Length header"),
+            Error::InvalidChunk => write!(f, "Invalid chunked encoding"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(PartialEq, Clone, Copy)]
+enum ParseState {
+    Head,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkDataEnd,
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+/// Status, headers and body of a response, kept for the `onResponse` hook of
+/// scripts. Header names are lowercase.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+/// Incremental HTTP/1.1 response parser. Bytes are consumed from the front of
+/// the buffer as soon as they are parsed, so large bodies are never buffered
+/// unless the response is captured.
+pub struct ResponseParser {
+    state: ParseState,
+    head_request: bool,
+    status: u16,
+    keep_alive: bool,
+    capture: bool,
+    headers: Vec<(String, String)>,
+    body: BytesMut,
+}
+
+impl ResponseParser {
+    pub fn new() -> ResponseParser {
+        Self {
+            state: ParseState::Head,
+            head_request: false,
+            status: 0,
+            keep_alive: true,
+            capture: false,
+            headers: Vec::new(),
+            body: BytesMut::new(),
+        }
+    }
+
+    // Headers and body of the next response are kept if `capture` is set
+    pub fn reset(&mut self, head_request: bool, capture: bool) {
+        self.state = ParseState::Head;
+        self.head_request = head_request;
+        self.status = 0;
+        self.keep_alive = true;
+        self.capture = capture;
+        self.headers.clear();
+        self.body.clear();
+    }
+
+    // The captured response, once done
+    pub fn take_response(&mut self) -> Response {
+        Response {
+            status: self.status,
+            headers: std::mem::take(&mut self.headers),
+            body: self.body.split().freeze(),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    // Whether the connection can be reused after the current response
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ParseState::Done
+    }
+
+    /// Consumes as much of `buf` as possible, returns true once the full
+    /// response has been received.
+    pub fn parse(&mut self, buf: &mut BytesMut) -> Result<bool> {
+        loop {
+            match self.state {
+                ParseState::Head => {
+                    if !self.parse_head(buf)? {
+                        return Ok(false);
+                    }
+                }
+                ParseState::Body(remaining) => {
+                    let n = remaining.min(buf.len() as u64);
+                    self.capture_body(&buf[..n as usize]);
+                    buf.advance(n as usize);
+                    if n < remaining {
+                        self.state = ParseState::Body(remaining - n);
+                        return Ok(false);
+                    }
+                    self.state = ParseState::Done;
+                }
+                ParseState::ChunkSize => match httparse::parse_chunk_size(&buf[..]) {
+                    Ok(Status::Complete((nparsed, size))) => {
+                        buf.advance(nparsed);
+                        self.state = if size == 0 {
+                            ParseState::Trailers
+                        } else {
+                            ParseState::ChunkData(size)
+                        };
+                    }
+                    Ok(Status::Partial) => return Ok(false),
+                    Err(_) => return Err(Error::InvalidChunk),
+                },
+                ParseState::ChunkData(remaining) => {
+                    let n = remaining.min(buf.len() as u64);
+                    self.capture_body(&buf[..n as usize]);
+                    buf.advance(n as usize);
+                    if n < remaining {
+                        self.state = ParseState::ChunkData(remaining - n);
+                        return Ok(false);
+                    }
+                    self.state = ParseState::ChunkDataEnd;
+                }
+                ParseState::ChunkDataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(Error::InvalidChunk);
+                    }
+                    buf.advance(2);
+                    self.state = ParseState::ChunkSize;
+                }
+                ParseState::Trailers => match find_crlf(&buf[..]) {
+                    Some(pos) => {
+                        buf.advance(pos + 2);
+                        if pos == 0 {
+                            self.state = ParseState::Done;
+                        }
+                    }
+                    None => return Ok(false),
+                },
+                ParseState::UntilClose => {
+                    self.capture_body(&buf[..]);
+                    buf.clear();
+                    return Ok(false);
+                }
+                ParseState::Done => return Ok(true),
+            }
+        }
+    }
+
+    /// Called when the peer closed the connection, returns true if this
+    /// completes the response.
+    pub fn parse_eof(&mut self) -> bool {
+        self.keep_alive = false;
+        match self.state {
+            ParseState::UntilClose | ParseState::Done => {
+                self.state = ParseState::Done;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn capture_body(&mut self, data: &[u8]) {
+        if self.capture {
+            self.body.extend_from_slice(data);
+        }
+    }
+
+    fn parse_head(&mut self, buf: &mut BytesMut) -> Result<bool> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut resp = httparse::Response::new(&mut headers);
+        let nparsed = match resp.parse(&buf[..]) {
+            Ok(Status::Complete(nparsed)) => nparsed,
+            Ok(Status::Partial) => return Ok(false),
+            Err(err) => return Err(Error::HttpParse(err)),
+        };
+
+        let status = resp.code.unwrap();
+        let mut content_length: Option<u64> = None;
+        let mut chunked = false;
+        let mut keep_alive = resp.version.unwrap() >= 1;
+        let mut captured = Vec::new();
+        for header in resp.headers.iter() {
+            let value = std::str::from_utf8(header.value).unwrap_or("");
+            if self.capture {
+                captured.push((
+                    header.name.to_ascii_lowercase(),
+                    String::from_utf8_lossy(header.value).into_owned(),
+                ));
+            }
+            if header.name.eq_ignore_ascii_case("Content-Length") {
+                let len = value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| Error::InvalidContentLength)?;
+                if content_length.is_some() && content_length != Some(len) {
+                    return Err(Error::InvalidContentLength);
+                }
+                content_length = Some(len);
+            } else if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                // Only the final transfer coding decides the framing
+                chunked = value
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+            } else if header.name.eq_ignore_ascii_case("Connection") {
+                for token in value.split(',') {
+                    if token.trim().eq_ignore_ascii_case("close") {
+                        keep_alive = false;
+                    } else if token.trim().eq_ignore_ascii_case("keep-alive") {
+                        keep_alive = true;
+                    }
+                }
+            }
+        }
+        buf.advance(nparsed);
+
+        // Interim responses are followed by the final one
+        if (100..200).contains(&status) && status != 101 {
+            return Ok(true);
+        }
+
+        self.status = status;
+        self.keep_alive = keep_alive;
+        self.headers = captured;
+        self.state = if self.head_request || status == 101 || status == 204 || status == 304 {
+            if status == 101 {
+                self.keep_alive = false;
+            }
+            ParseState::Done
+        } else if chunked {
+            ParseState::ChunkSize
+        } else if let Some(len) = content_length {
+            if len == 0 {
+                ParseState::Done
+            } else {
+                ParseState::Body(len)
+            }
+        } else {
+            self.keep_alive = false;
+            ParseState::UntilClose
+        };
+        Ok(true)
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+/// Set of status codes that count as a successful response, parsed from a
+/// comma-separated list of codes (`200`), ranges (`200-299`) and classes (`2xx`).
+#[derive(Clone, Debug)]
+pub struct SuccessCodes {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl SuccessCodes {
+    pub fn contains(&self, status: u16) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(low, high)| low <= status && status <= high)
+    }
+}
+
+impl Default for SuccessCodes {
+    fn default() -> SuccessCodes {
+        Self {
+            ranges: vec![(200, 299), (300, 399)],
+        }
+    }
+}
+
+impl FromStr for SuccessCodes {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SuccessCodes, String> {
+        let parse_code = |code: &str| match code.trim().parse::<u16>() {
+            Ok(code) if (100..600).contains(&code) => Ok(code),
+            _ => Err(format!("Invalid status code: {}", code)),
+        };
+        let mut ranges = Vec::new();
+        for item in s.split(',').map(str::trim) {
+            if item.len() == 3 && item.ends_with("xx") {
+                let class = parse_code(&format!("{}00", &item[..1]))?;
+                ranges.push((class, class + 99));
+            } else if let Some((low, high)) = item.split_once('-') {
+                let (low, high) = (parse_code(low)?, parse_code(high)?);
+                if low > high {
+                    return Err(format!("Invalid status range: {}", item));
+                }
+                ranges.push((low, high));
+            } else {
+                let code = parse_code(item)?;
+                ranges.push((code, code));
+            }
+        }
+        Ok(Self { ranges })
+    }
+}
+
+impl fmt::Display for SuccessCodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(low, high)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if low == high {
+                write!(f, "{}", low)?;
+            } else if low % 100 == 0 && high == low + 99 {
+                write!(f, "{}xx", low / 100)?;
+            } else {
+                write!(f, "{}-{}", low, high)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for SuccessCodes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Case {
+        name: &'static str,
+        input: &'static [u8],
+        head_request: bool,
+        // The response ends with the connection, e.g. it has no length
+        eof: bool,
+        status: u16,
+        body: &'static [u8],
+        keep_alive: bool,
+        // Start of the next pipelined response, left in the buffer
+        leftover: &'static [u8],
+    }
+
+    const NEXT: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Len";
+
+    const CASES: &[Case] = &[
+        Case {
+            name: "content-length",
+            input: b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"hello",
+            keep_alive: true,
+            leftover: b"",
+        },
+        Case {
+            name: "pipelined content-length",
+            input: b"HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nnopHTTP/1.1 200 OK\r\nContent-Len",
+            head_request: false,
+            eof: false,
+            status: 404,
+            body: b"nop",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "empty content-length",
+            input: b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\nHTTP/1.1 200 OK\r\nContent-Len",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "chunked with extensions and trailers",
+            input: b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: t\r\n\r\n\
+                     HTTP/1.1 200 OK\r\nContent-Len",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"hello world",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "chunked as the final coding",
+            input: b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"ok",
+            keep_alive: true,
+            leftover: b"",
+        },
+        Case {
+            name: "head",
+            input: b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHTTP/1.1 200 OK\r\nContent-Len",
+            head_request: true,
+            eof: false,
+            status: 200,
+            body: b"",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "no content",
+            input: b"HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 200 OK\r\nContent-Len",
+            head_request: false,
+            eof: false,
+            status: 204,
+            body: b"",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "not modified",
+            input: b"HTTP/1.1 304 Not Modified\r\nContent-Length: 7\r\n\r\nHTTP/1.1 200 OK\r\nContent-Len",
+            head_request: false,
+            eof: false,
+            status: 304,
+            body: b"",
+            keep_alive: true,
+            leftover: NEXT,
+        },
+        Case {
+            name: "interim response",
+            input: b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+            head_request: false,
+            eof: false,
+            status: 201,
+            body: b"ok",
+            keep_alive: true,
+            leftover: b"",
+        },
+        Case {
+            name: "connection close",
+            input: b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"ok",
+            keep_alive: false,
+            leftover: b"",
+        },
+        Case {
+            name: "http/1.0 keep-alive",
+            input: b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok",
+            head_request: false,
+            eof: false,
+            status: 200,
+            body: b"ok",
+            keep_alive: true,
+            leftover: b"",
+        },
+        Case {
+            name: "until close",
+            input: b"HTTP/1.0 200 OK\r\n\r\nbody until close",
+            head_request: false,
+            eof: true,
+            status: 200,
+            body: b"body until close",
+            keep_alive: false,
+            leftover: b"",
+        },
+    ];
+
+    fn check(case: &Case, parser: &mut ResponseParser, buf: &BytesMut, done: bool, at: &str) {
+        let mut done = done;
+        if case.eof {
+            assert!(!done, "{}: done before eof {}", case.name, at);
+            done = parser.parse_eof();
+        }
+        assert!(done, "{}: not done {}", case.name, at);
+        assert_eq!(parser.status(), case.status, "{}: status {}", case.name, at);
+        assert_eq!(
+            parser.keep_alive(),
+            case.keep_alive,
+            "{}: keep-alive {}",
+            case.name,
+            at
+        );
+        assert_eq!(&buf[..], case.leftover, "{}: leftover {}", case.name, at);
+        let response = parser.take_response();
+        assert_eq!(&response.body[..], case.body, "{}: body {}", case.name, at);
+    }
+
+    #[test]
+    fn parses_input_split_anywhere() {
+        for case in CASES {
+            for at in 0..=case.input.len() {
+                let mut parser = ResponseParser::new();
+                parser.reset(case.head_request, true);
+                let mut buf = BytesMut::from(&case.input[..at]);
+                parser.parse(&mut buf).unwrap();
+                buf.extend_from_slice(&case.input[at..]);
+                let done = parser.parse(&mut buf).unwrap();
+                check(case, &mut parser, &buf, done, &format!("split at {}", at));
+            }
+        }
+    }
+
+    #[test]
+    fn parses_input_byte_by_byte() {
+        for case in CASES {
+            let mut parser = ResponseParser::new();
+            parser.reset(case.head_request, true);
+            let mut buf = BytesMut::new();
+            let mut done = false;
+            for &byte in case.input {
+                buf.extend_from_slice(&[byte]);
+                done = parser.parse(&mut buf).unwrap();
+            }
+            check(case, &mut parser, &buf, done, "byte by byte");
+        }
+    }
+
+    #[test]
+    fn reuses_parser_for_pipelined_responses() {
+        let mut parser = ResponseParser::new();
+        let mut buf = BytesMut::from(
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+               HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nb\r\n0\r\n\r\n\
+               HTTP/1.1 500 Oops\r\nContent-Length: 1\r\n\r\nc"[..],
+        );
+        for &(status, body) in &[(200, b"a"), (200, b"b"), (500, b"c")] {
+            parser.reset(false, true);
+            assert!(parser.parse(&mut buf).unwrap());
+            assert_eq!(parser.status(), status);
+            assert_eq!(&parser.take_response().body[..], body);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn drops_body_unless_captured() {
+        let mut parser = ResponseParser::new();
+        parser.reset(false, false);
+        let mut buf =
+            BytesMut::from(&b"HTTP/1.1 200 OK\r\nX-A: b\r\nContent-Length: 2\r\n\r\nok"[..]);
+        assert!(parser.parse(&mut buf).unwrap());
+        let response = parser.take_response();
+        assert!(response.body.is_empty());
+        assert!(response.headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_framing() {
+        let cases: &[(&[u8], &str)] = &[
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n",
+                "Invalid Content-Length header",
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                "Invalid Content-Length header",
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                "Invalid chunked encoding",
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nabc",
+                "Invalid chunked encoding",
+            ),
+            (b"HTTP/1.1 2000 OK\r\n\r\n", "Invalid response head"),
+        ];
+        for (input, message) in cases {
+            let mut parser = ResponseParser::new();
+            let mut buf = BytesMut::from(&input[..]);
+            match parser.parse(&mut buf) {
+                Err(err) => assert!(err.to_string().starts_with(message), "{}", err),
+                Ok(done) => panic!("{:?} parsed, done: {}", input, done),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_success_codes() {
+        let cases = [
+            ("2xx,3xx", "2xx,3xx"),
+            ("200", "200"),
+            (" 200 , 404 ", "200,404"),
+            ("200-204,404", "200-204,404"),
+            ("200-299", "2xx"),
+            ("500-500", "500"),
+            ("1xx,5xx", "1xx,5xx"),
+        ];
+        for (s, display) in cases.iter() {
+            let codes = s.parse::<SuccessCodes>().unwrap();
+            assert_eq!(codes.to_string(), *display);
+            let reparsed = display.parse::<SuccessCodes>().unwrap();
+            assert_eq!(reparsed.ranges, codes.ranges, "{}", s);
+        }
+        assert_eq!(SuccessCodes::default().to_string(), "2xx,3xx");
+    }
+
+    #[test]
+    fn matches_success_codes() {
+        let codes = "200-204,404,5xx".parse::<SuccessCodes>().unwrap();
+        for &status in [200, 204, 404, 500, 599].iter() {
+            assert!(codes.contains(status), "{}", status);
+        }
+        for &status in [199, 205, 301, 403, 405, 600].iter() {
+            assert!(!codes.contains(status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_success_codes() {
+        let cases = [
+            ("", "Invalid status code: "),
+            ("ok", "Invalid status code: ok"),
+            ("99", "Invalid status code: 99"),
+            ("600", "Invalid status code: 600"),
+            ("6xx", "Invalid status code: 600"),
+            ("200,", "Invalid status code: "),
+            ("204-200", "Invalid status range: 204-200"),
+            ("200-", "Invalid status code: "),
+        ];
+        for (s, message) in cases.iter() {
+            assert_eq!(s.parse::<SuccessCodes>().unwrap_err(), *message, "{}", s);
+        }
+    }
+
+    #[test]
+    fn needs_the_end_of_framed_bodies() {
+        let mut parser = ResponseParser::new();
+        let mut buf = BytesMut::from(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel"[..]);
+        assert!(!parser.parse(&mut buf).unwrap());
+        assert!(!parser.parse_eof());
+        assert!(!parser.keep_alive());
+    }
+}
@@ -12,6 +12,41 @@ const allLowerAlpha = [..."abcdefghijklmnopqrstuvwxyz"];
 const allAlpha = [...allCapsAlpha, ...allLowerAlpha];
 const allAlphaDigits = [...allAlpha, ...allDigits];
 
+const base64Chars = 'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/';
+
+function base64Encode(bytes) {
+    let result = '';
+    for (let i = 0; i < bytes.length; i += 3) {
+        const n = (bytes[i] << 16) | ((bytes[i + 1] | 0) << 8) | (bytes[i + 2] | 0);
+        result += base64Chars[n >> 18 & 63] + base64Chars[n >> 12 & 63]
+            + (i + 1 < bytes.length ? base64Chars[n >> 6 & 63] : '=')
+            + (i + 2 < bytes.length ? base64Chars[n & 63] : '=');
+    }
+    return result;
+}
+
+function isBinary(value) {
+    return value instanceof ArrayBuffer || ArrayBuffer.isView(value);
+}
+
+// Uint8Array over an ArrayBuffer, a typed array or DataView, an array of
+// byte values or the UTF-8 encoding of a string
+function toBytes(value) {
+    if (value instanceof Uint8Array) {
+        return value;
+    }
+    if (value instanceof ArrayBuffer) {
+        return new Uint8Array(value);
+    }
+    if (ArrayBuffer.isView(value)) {
+        return new Uint8Array(value.buffer, value.byteOffset, value.byteLength);
+    }
+    if (typeof value === 'string') {
+        return flood.utf8(value);
+    }
+    return Uint8Array.from(value);
+}
+
 function randomString(base, length) {
     let result = "";
     for (let i = 0; i < length; i++) {
@@ -22,6 +57,12 @@ function randomString(base, length) {
 }
 
 const flood = {
+    // Human-readable names of request types, e.g. { 0: 'read', 1: 'write' }
+    typeNames: {},
+
+    // Datasets loaded with --data, e.g. flood.data.users.next()
+    data: {},
+
     // Random integer within [a, b)
     randInt(a, b) {
         return (Math.random() * (b - a) | 0) + a;
@@ -54,7 +95,11 @@ const flood = {
         if ('qs' in args) {
             path += '?' + encodeUriParams(args.qs);
         }
-        return { type: type, method: 'GET', path: path, headers: headers };
+        let req = { type: type, method: 'GET', path: path, headers: headers };
+        if ('thinkTime' in args) {
+            req.thinkTime = args.thinkTime;
+        }
+        return req;
     },
 
     doPost(args) {
@@ -71,7 +116,241 @@ const flood = {
         } else if ('json' in args) {
             headers['Content-Type'] = 'application/json';
             body = JSON.stringify(args.json);
+        } else if ('bytes' in args) {
+            if (!('Content-Type' in headers)) {
+                headers['Content-Type'] = 'application/octet-stream';
+            }
+            body = toBytes(args.bytes);
+        } else if ('body' in args) {
+            body = args.body;
+        }
+        let req = { type: type, method: 'POST', path: path, headers: headers, body: body };
+        if ('thinkTime' in args) {
+            req.thinkTime = args.thinkTime;
+        }
+        return req;
+    },
+
+    // UTF-8 encoding of `str` as a Uint8Array
+    utf8(str) {
+        const bytes = [];
+        for (const ch of str) {
+            let c = ch.codePointAt(0);
+            if (c < 0x80) {
+                bytes.push(c);
+            } else if (c < 0x800) {
+                bytes.push(0xc0 | c >> 6, 0x80 | c & 63);
+            } else if (c < 0x10000) {
+                bytes.push(0xe0 | c >> 12, 0x80 | c >> 6 & 63, 0x80 | c & 63);
+            } else {
+                bytes.push(0xf0 | c >> 18, 0x80 | c >> 12 & 63, 0x80 | c >> 6 & 63, 0x80 | c & 63);
+            }
         }
-        return { type: type, method: 'POST', path: path, headers: headers, body: body };
+        return Uint8Array.from(bytes);
+    },
+
+    // Concatenation of byte sequences, each accepted by `flood.bytesWriter().bytes`
+    concatBytes(...parts) {
+        const writer = flood.bytesWriter();
+        parts.forEach(part => writer.bytes(part));
+        return writer.finish();
+    },
+
+    // Body of bytes already encoded in base64
+    base64(encoded) {
+        return { base64: encoded };
+    },
+
+    // Base64 encoding of bytes, e.g. for headers or JSON fields
+    base64Encode(value) {
+        return base64Encode(toBytes(value));
+    },
+
+    // Builder of binary payloads, e.g. protobuf or length-prefixed frames:
+    // flood.bytesWriter().u8(1).u32(7).varint(300).string('abc').finish()
+    bytesWriter() {
+        const bytes = [];
+        const writer = {
+            u8(n) {
+                bytes.push(n & 0xff);
+                return writer;
+            },
+            // Big-endian unless `littleEndian` is true
+            u16(n, littleEndian) {
+                const b = [n >>> 8 & 0xff, n & 0xff];
+                bytes.push(...(littleEndian ? b.reverse() : b));
+                return writer;
+            },
+            u32(n, littleEndian) {
+                const b = [n >>> 24 & 0xff, n >>> 16 & 0xff, n >>> 8 & 0xff, n & 0xff];
+                bytes.push(...(littleEndian ? b.reverse() : b));
+                return writer;
+            },
+            // Unsigned LEB128 varint of protobuf, exact up to 2^53
+            varint(n) {
+                while (n >= 0x80) {
+                    bytes.push(n % 0x80 | 0x80);
+                    n = Math.floor(n / 0x80);
+                }
+                bytes.push(n);
+                return writer;
+            },
+            bytes(value) {
+                toBytes(value).forEach(b => bytes.push(b));
+                return writer;
+            },
+            // UTF-8 encoding of `str`
+            string(str) {
+                return writer.bytes(flood.utf8(str));
+            },
+            finish() {
+                return Uint8Array.from(bytes);
+            },
+        };
+        return writer;
+    },
+
+    // Scenarios defined with flood.scenario, each run on one connection
+    _scenarios: [],
+
+    // Registers a scenario of `steps` run in order, picked in proportion to
+    // `weight` (default 1). A step is either a function `(vars) => request`
+    // or an object `{ name, request: (vars) => request, check: (resp, vars) => bool }`
+    // where `check` can keep data in `vars` for later steps and fails the
+    // scenario by returning false or throwing.
+    scenario(name, steps, weight) {
+        flood._scenarios.push({
+            name: name,
+            weight: weight === undefined ? 1 : weight,
+            steps: steps.map((step, i) => {
+                if (typeof step === 'function') {
+                    step = { request: step };
+                }
+                return {
+                    name: ('name' in step) ? step.name : 'step ' + (i + 1),
+                    request: step.request,
+                    check: step.check,
+                };
+            }),
+        });
     }
 };
+
+// Replaces Math.random with sfc32 seeded by four 32-bit integers, for --seed
+function floodSeedRandom(a, b, c, d) {
+    Math.random = function () {
+        const t = (a + b | 0) + d | 0;
+        d = d + 1 | 0;
+        a = b ^ b >>> 9;
+        b = c + (c << 3) | 0;
+        c = (c << 21 | c >>> 11) + t | 0;
+        return (t >>> 0) / 4294967296;
+    };
+    for (let i = 0; i < 12; i++) {
+        Math.random();
+    }
+    return null;
+}
+
+// Set while flood checks the script, so that the check takes no rows
+let floodDataPeek = false;
+
+// Rows of a dataset are shared with every other JS context through callbacks
+function floodDataset(index, length) {
+    return {
+        length: length,
+        // Rows in order, wrapping around
+        next() {
+            return floodDataPeek ? floodDataRow(index, 0) : floodDataNext(index);
+        },
+        random() {
+            return floodDataRow(index, Math.random() * length | 0);
+        },
+        // Every row once, then null
+        unique() {
+            return floodDataPeek ? floodDataRow(index, 0) : floodDataUnique(index);
+        },
+    };
+}
+
+// Binary bodies cross into Rust as `{ base64 }` objects
+function floodEncodeRequest(req) {
+    if (req !== null && typeof req === 'object' && isBinary(req.body)) {
+        req.body = { base64: base64Encode(toBytes(req.body)) };
+    }
+    return req;
+}
+
+function floodNewRequest() {
+    return floodEncodeRequest(newRequest());
+}
+
+function floodReplayRequest(type) {
+    return floodEncodeRequest(replayRequest(type));
+}
+
+// Scenarios in progress by session id, called from the event loop
+const floodSessions = new Map();
+let floodNextSession = 0;
+
+function floodScenarioNames() {
+    return flood._scenarios.map(s => ({ name: s.name, steps: s.steps.map(step => step.name) }));
+}
+
+function floodStartScenario() {
+    const total = flood._scenarios.reduce((sum, s) => sum + s.weight, 0);
+    let x = Math.random() * total;
+    let index = 0;
+    while (index < flood._scenarios.length - 1 && x >= flood._scenarios[index].weight) {
+        x -= flood._scenarios[index].weight;
+        index++;
+    }
+    const id = floodNextSession;
+    floodNextSession = (floodNextSession + 1) % 2147483647;
+    const session = { scenario: index, step: 0, vars: {} };
+    floodSessions.set(id, session);
+    try {
+        return { session: id, scenario: index, request: floodEncodeRequest(flood._scenarios[index].steps[0].request(session.vars)) };
+    } catch (e) {
+        floodSessions.delete(id);
+        return { session: id, scenario: index, error: String(e) };
+    }
+}
+
+// First request of every scenario, made when flood checks the script
+function floodTestScenarios() {
+    return flood._scenarios.map(s => {
+        s.steps.forEach(step => {
+            if (typeof step.request !== 'function') {
+                throw new Error('step ' + step.name + ' of scenario ' + s.name + ' has no request function');
+            }
+        });
+        return s.steps.length === 0 ? null : floodEncodeRequest(s.steps[0].request({}));
+    });
+}
+
+function floodNextStep(id, resp) {
+    const session = floodSessions.get(id);
+    const steps = flood._scenarios[session.scenario].steps;
+    try {
+        const check = steps[session.step].check;
+        if (check !== undefined && check(resp, session.vars) === false) {
+            floodSessions.delete(id);
+            return { done: true, passed: false };
+        }
+        session.step++;
+        if (session.step === steps.length) {
+            floodSessions.delete(id);
+            return { done: true, passed: true };
+        }
+        return { request: floodEncodeRequest(steps[session.step].request(session.vars)) };
+    } catch (e) {
+        floodSessions.delete(id);
+        return { done: true, passed: false, error: String(e) };
+    }
+}
+
+function floodEndScenario(id) {
+    floodSessions.delete(id);
+    return null;
+}
@@ -1,21 +1,65 @@
+mod agent;
+mod analyze;
+mod arrival;
 mod client;
+mod compare;
+mod data;
+mod deadline;
 mod exec_info;
 mod generator;
+mod hdrlog;
+mod http;
+mod replay;
+mod report;
+mod runner;
+mod schedule;
+mod search;
+mod slo;
+mod trace;
 
-use client::Client;
-use exec_info::ExecutionInfo;
-use generator::Generator;
+use agent::AgentOpt;
+use analyze::AnalyzeOpt;
+use arrival::ArrivalProcess;
+use client::ThinkTime;
+use compare::CompareOpt;
+use data::{DataSpec, Dataset};
+use hdrlog::MergeOpt;
+use http::SuccessCodes;
+use replay::ReplayLog;
+use report::OutputFormat;
+use runner::RunConfig;
+use schedule::RateSchedule;
+use search::{SearchMode, SearchOptions};
+use slo::{Condition, Slo};
+use trace::TraceWriter;
 
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
 use env_logger::{self, Env};
-use humantime;
+use serde::Serialize;
+use structopt::clap::{self, AppSettings};
 use structopt::StructOpt;
 
+// Without a subcommand, flood runs SCRIPT; `flood -- agent` runs a script named `agent`
 #[derive(Debug, StructOpt)]
-#[structopt(name = "flood")]
+enum Command {
+    Agent(AgentOpt),
+    Analyze(AnalyzeOpt),
+    Compare(CompareOpt),
+    Merge(MergeOpt),
+}
+
+#[derive(Debug, StructOpt, Serialize)]
+#[structopt(
+    name = "flood",
+    setting = AppSettings::ArgsNegateSubcommands,
+    setting = AppSettings::DisableHelpSubcommand
+)]
 struct Opt {
     /// Host address
     #[structopt(short = "h", long = "host", default_value = "127.0.0.1:8080")]
@@ -23,37 +67,54 @@ struct Opt {
 
     /// Fraction of warm-up duration w.r.t. full duration
     #[structopt(long = "warmup-fraction", default_value = "0.2")]
-    warmup_fraction: f32,
+    warmup_fraction: f64,
 
-    /// Running duration
+    /// Running duration, ignored with --schedule
     #[structopt(short = "d", long = "duration", default_value = "30s")]
     duration: String,
 
-    /// QPS
+    /// QPS, ignored with --schedule
     #[structopt(short = "r", long = "qps", default_value = "100")]
     qps: i32,
 
+    /// Rate schedule, e.g. "ramp 100->5000 over 60s, hold 5000 for 120s", or @FILE to read it from a file
+    #[structopt(long = "schedule", default_value = "")]
+    schedule: String,
+
     /// Number of connections
     #[structopt(short = "c", long = "conn", default_value = "16")]
     num_conn: i32,
 
+    /// Number of event loop threads, connections and QPS are split among them
+    #[structopt(long = "threads", default_value = "1")]
+    num_threads: i32,
+
     /// Connect timeout
     #[structopt(long = "connect-timeout", default_value = "100ms")]
     connect_timeout: String,
 
-    /// Connect timeout
+    /// Read timeout of a single request
     #[structopt(long = "read-timeout", default_value = "100ms")]
     read_timeout: String,
 
-    /// Connect timeout
+    /// Write timeout of a single request
     #[structopt(long = "write-timeout", default_value = "100ms")]
     write_timeout: String,
 
-    /// Arrival process (uniform or poisson)
+    /// Arrival process: uniform, poisson, closed, mmpp, pareto, lognormal or trace, with
+    /// parameters as in mmpp:on=4,off=0.25,on_time=1s,off_time=3s
     #[structopt(long = "arrival-process", default_value = "poisson")]
-    arrival_process: String,
+    arrival_process: ArrivalProcess,
+
+    /// Think time of closed-loop users, fixed (100ms) or exponential (exp:100ms)
+    #[structopt(long = "think-time", default_value = "0s")]
+    think_time: ThinkTime,
+
+    /// Status codes counted as success, e.g. 2xx,3xx or 200-204,404
+    #[structopt(long = "success-codes", default_value = "2xx,3xx")]
+    success_codes: SuccessCodes,
 
-    /// Number of JS threads
+    /// Number of JS threads, split among event loops; unused with onResponse or --seed
     #[structopt(short = "t", long = "js-threads", default_value = "2")]
     num_js_threads: i32,
 
@@ -61,146 +122,324 @@ struct Opt {
     #[structopt(long = "request-qsize", default_value = "128")]
     request_qsize: i32,
 
-    /// Path for saving trace file
+    /// Path for saving a binary trace of sampled requests, written as the run goes
     #[structopt(short = "f", long = "trace-save-path", default_value = "")]
     trace_save_path: String,
 
     /// Sampling ratio for saved trace
     #[structopt(long = "trace-sample-ratio", default_value = "1.0")]
-    trace_sample_ratio: f32,
+    trace_sample_ratio: f64,
+
+    /// Interval of the progress line and the saved time series, empty to disable
+    #[structopt(long = "report-interval", default_value = "1s")]
+    report_interval: String,
+
+    /// Format of the results (text, json or csv)
+    #[structopt(long = "output-format", default_value = "text")]
+    output_format: OutputFormat,
+
+    /// Path for saving results, text results still go to stdout
+    #[structopt(long = "output-file", default_value = "")]
+    output_file: String,
+
+    /// Path for saving the latency of every --report-interval as an HdrHistogram interval log
+    #[structopt(long = "hdr-log", default_value = "")]
+    hdr_log: String,
+
+    /// Condition checked after the run, e.g. "p99<50ms", "error_rate<0.01" or "rps>=0.95*target",
+    /// repeatable; flood exits with 2 if one fails
+    #[structopt(long = "assert", number_of_values = 1)]
+    assertions: Vec<Condition>,
+
+    /// Search for the highest rate meeting --slo (binary or step), each step runs for --duration
+    #[structopt(long = "search")]
+    search: Option<SearchMode>,
+
+    /// SLO judged in --search, e.g. "p99 < 20ms and errors < 0.1%"
+    #[structopt(long = "slo")]
+    slo: Option<Slo>,
+
+    /// Lowest rate tried by --search
+    #[structopt(long = "search-min", default_value = "100")]
+    search_min: f64,
+
+    /// Highest rate tried by --search
+    #[structopt(long = "search-max", default_value = "10000")]
+    search_max: f64,
+
+    /// Rate increment of step search, resolution of binary search
+    #[structopt(long = "search-step", default_value = "100")]
+    search_step: f64,
+
+    /// Comma-separated addresses of `flood agent` processes to split the run among, each running
+    /// --threads event loops
+    #[structopt(long = "agents", default_value = "")]
+    agents: String,
 
-    /// JavaScript file
+    /// Secret sent to the agents with every job, has to match their --token
+    #[structopt(
+        long = "agent-token",
+        env = "FLOOD_AGENT_TOKEN",
+        hide_env_values = true,
+        default_value = ""
+    )]
+    #[serde(skip)]
+    agent_token: String,
+
+    /// Dataset readable by the script as flood.data.<name>, from a CSV file with a header row or
+    /// a JSONL file, e.g. users=users.csv; repeatable
+    #[structopt(long = "data", number_of_values = 1)]
+    data: Vec<DataSpec>,
+
+    /// Seed of the script's Math.random, send times, think times and trace sampling, for
+    /// reproducible runs; requests are then generated on the event loops, ignoring --js-threads
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Replay the send times and requests of a saved trace or request log, warm-up is skipped
+    #[structopt(long = "replay", default_value = "")]
+    replay: String,
+
+    /// Speed factor of --replay, 2 halves the gaps between requests
+    #[structopt(long = "replay-speed", default_value = "1.0")]
+    replay_speed: f64,
+
+    /// JavaScript file, required unless a subcommand is given
     #[structopt(name = "SCRIPT")]
-    js_script_path: String,
-}
+    js_script_path: Option<String>,
 
-fn format_latency(micro: u64) -> String {
-    if micro < 1000 {
-        format!("{:>6.2}µs", micro as f64)
-    } else if micro < 1_000_000 {
-        format!("{:>6.2}ms", micro as f64 / 1000.0)
-    } else if micro < 1_000_000_000 {
-        format!("{:>6.2}s", micro as f64 / 1000000.0)
-    } else {
-        format!("{:>6.2}m", micro as f64 / 60000000.0)
-    }
+    #[structopt(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
 }
 
-fn format_bytes(bytes: f64) -> String {
-    if bytes < 1024.0 {
-        format!("{:>6.2}B", bytes)
-    } else if bytes < 1024.0 * 1024.0 {
-        format!("{:>6.2}KB", bytes / 1024.0)
-    } else if bytes < 1024.0 * 1024.0 * 1024.0 {
-        format!("{:>6.2}MB", bytes / 1024.0 / 1024.0)
-    } else {
-        format!("{:>6.2}GB", bytes / 1024.0 / 1024.0 / 1024.0)
-    }
-}
+// Exit code of runs that completed but failed a check, errors exit with 1
+const CHECK_FAILED_EXIT_CODE: i32 = 2;
 
-fn print_results(opt: &Opt, duration: Duration, exec_info: &ExecutionInfo) {
-    print!(
-        "Running {} test @ http://{}\n",
-        humantime::format_duration(duration),
-        opt.host
-    );
-    print!("  {} connections\n", opt.num_conn);
-    let hist = &exec_info.latency_hist;
-    if !hist.is_empty() {
-        print!("  Latency Distribution (HdrHistogram)\n");
-        for &percentile in [50.0, 75.0, 90.0, 99.0, 99.9, 99.99, 99.999, 100.0].iter() {
-            print!(
-                "{:>7.3}%  {}\n",
-                percentile,
-                format_latency(hist.value_at_percentile(percentile))
-            );
-        }
-        print!("\n");
-        print!("  Detailed Percentile spectrum:\n");
-        print!("       Value   Percentile   TotalCount 1/(1-Percentile)\n");
-        print!("\n");
-        for iter_value in hist.iter_quantiles(1) {
-            if iter_value.count_since_last_iteration() > 0 {
-                print!(
-                    "  {:>10.3}  {:>10.6}  {:>10}  {:>10.2}\n",
-                    iter_value.value_iterated_to() as f32 / 1000.0,
-                    iter_value.percentile(),
-                    iter_value.count_since_last_iteration(),
-                    1.0 / (1.0 - iter_value.quantile())
-                );
+fn main() -> runner::Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let mut opt = Opt::from_args();
+    match opt.command.take() {
+        Some(Command::Agent(opt)) => return agent::main(opt),
+        Some(Command::Analyze(opt)) => return analyze::main(opt),
+        Some(Command::Compare(opt)) => {
+            if compare::main(opt)? {
+                process::exit(CHECK_FAILED_EXIT_CODE);
             }
+            return Ok(());
         }
-        print!("----------------------------------------------------------\n");
+        Some(Command::Merge(opt)) => return hdrlog::main(opt),
+        None => {}
     }
-    print!("\n");
-    let total_requests = exec_info.success_count + exec_info.failure_count;
-    print!(
-        "  {} requests in {}, {} read\n",
-        total_requests,
-        humantime::format_duration(duration),
-        format_bytes(exec_info.bytes_recv as f64)
-    );
-    if exec_info.failure_count > 0 {
-        print!("  Non-2xx or 3xx responses: {}\n", exec_info.failure_count);
-    }
-    print!(
-        "Requests/sec:{:>10.2}\n",
-        total_requests as f32 / duration.as_secs_f32()
-    );
-    print!(
-        "Transfer/sec:    {}\n",
-        format_bytes(exec_info.bytes_sent as f64 / duration.as_secs_f64())
-    );
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    let opt = Opt::from_args();
+    let script_path = match opt.js_script_path.as_ref() {
+        Some(path) => path,
+        None => clap::Error::with_description(
+            "The SCRIPT to run is missing, see --help",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
 
     let mut resolved_addrs = opt.host.to_socket_addrs()?;
     let addr: SocketAddr = resolved_addrs.next().unwrap();
-    let duration = humantime::parse_duration(&opt.duration)?;
-    let warmup_duration = Duration::from_secs_f32(duration.as_secs_f32() * opt.warmup_fraction);
-    let script_content =
-        fs::read_to_string(&opt.js_script_path).expect("Failed to read script file");
-
-    let mut generator = Generator::new(
-        &opt.host,
-        opt.num_js_threads as usize,
-        opt.request_qsize as usize,
-    );
-    generator.load_user_script(&script_content)?;
-    let mut client = Client::new(&addr, generator);
-
-    client.set_connect_timeout(humantime::parse_duration(&opt.connect_timeout)?);
+    if opt.arrival_process.is_closed() && !opt.schedule.is_empty() {
+        return Err("--schedule does not apply to the closed-loop arrival process".into());
+    }
+    if opt.arrival_process.is_closed() && opt.assertions.iter().any(Condition::uses_target) {
+        return Err("Closed loops have no target rate to assert against".into());
+    }
+    if !opt.hdr_log.is_empty() && (opt.report_interval.is_empty() || opt.search.is_some()) {
+        return Err("--hdr-log needs a --report-interval and does not apply to --search".into());
+    }
+    let agents = opt
+        .agents
+        .split(',')
+        .map(str::trim)
+        .filter(|agent| !agent.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if !agents.is_empty() {
+        if opt.search.is_some() || !opt.replay.is_empty() || !opt.trace_save_path.is_empty() {
+            return Err(
+                "--agents does not apply to --search, --replay or --trace-save-path".into(),
+            );
+        }
+        if matches!(opt.arrival_process, ArrivalProcess::Trace { .. }) {
+            return Err("--agents does not apply to the trace arrival process".into());
+        }
+    }
+    let mut schedule = if opt.schedule.is_empty() {
+        RateSchedule::constant(opt.qps as f64, humantime::parse_duration(&opt.duration)?)
+    } else if let Some(path) = opt.schedule.strip_prefix('@') {
+        fs::read_to_string(path)?.parse::<RateSchedule>()?
+    } else {
+        opt.schedule.parse::<RateSchedule>()?
+    };
+    let mut duration = schedule.duration();
+    let mut warmup_duration = Duration::from_secs_f64(duration.as_secs_f64() * opt.warmup_fraction);
+    let connect_timeout = humantime::parse_duration(&opt.connect_timeout)?;
     let read_timeout = humantime::parse_duration(&opt.read_timeout)?;
-    client.set_read_timeout(read_timeout);
-    client.set_write_timeout(humantime::parse_duration(&opt.write_timeout)?);
-    client.set_arrival_process(&opt.arrival_process);
-
-    let mut exec_info = if !opt.trace_save_path.is_empty() {
-        let estimated_trace_size =
-            1.1 * opt.qps as f32 * duration.as_secs_f32() * opt.trace_sample_ratio;
-        ExecutionInfo::new(
-            read_timeout.as_micros() as u64,
-            estimated_trace_size as usize,
-            opt.trace_sample_ratio,
-        )
+    let write_timeout = humantime::parse_duration(&opt.write_timeout)?;
+
+    let mut arrival_process = opt.arrival_process.clone();
+    let mut replay_entries = None;
+    if !opt.replay.is_empty() {
+        if opt.arrival_process.is_closed() || !opt.schedule.is_empty() || opt.search.is_some() {
+            return Err("--replay takes its send times from the replayed log".into());
+        }
+        if opt.replay_speed <= 0.0 {
+            return Err("--replay-speed must be positive".into());
+        }
+        let log = ReplayLog::load(&opt.replay)?;
+        // Long enough for the last request to complete, sends start after 100ms
+        duration =
+            Duration::from_millis(100 + (1000.0 * log.span() / opt.replay_speed).ceil() as u64)
+                + write_timeout
+                + read_timeout;
+        warmup_duration = Duration::ZERO;
+        schedule =
+            RateSchedule::constant(log.times.len() as f64 / duration.as_secs_f64(), duration);
+        arrival_process = ArrivalProcess::replay(&opt.replay, log.times, opt.replay_speed);
+        replay_entries = Some(Arc::new(log.entries));
+    }
+    let report_interval = if opt.report_interval.is_empty() {
+        None
+    } else {
+        let interval = humantime::parse_duration(&opt.report_interval)?;
+        if interval.is_zero() {
+            return Err("--report-interval must be positive, or empty to disable".into());
+        }
+        Some(interval)
+    };
+    let script_content = fs::read_to_string(script_path)
+        .map_err(|err| format!("Failed to read {}: {}", script_path, err))?;
+    let mut datasets = Vec::new();
+    for spec in opt.data.iter() {
+        if datasets
+            .iter()
+            .any(|dataset: &Dataset| dataset.name == spec.name)
+        {
+            return Err(format!("Dataset {} is given twice", spec.name).into());
+        }
+        datasets.push(Dataset::load(spec)?);
+    }
+    if opt.search.is_some() && !opt.trace_save_path.is_empty() {
+        return Err(
+            "--trace-save-path does not apply to --search, steps would share one trace".into(),
+        );
+    }
+    let trace_writer = if opt.trace_save_path.is_empty() {
+        None
     } else {
-        ExecutionInfo::new(read_timeout.as_micros() as u64, 0, 0.0)
+        Some(TraceWriter::create(&opt.trace_save_path)?)
     };
-    client.run(
-        &mut exec_info,
-        opt.num_conn,
-        opt.qps,
+
+    let config = RunConfig {
+        addr,
+        host: opt.host.clone(),
+        script: script_content,
+        num_threads: opt.num_threads as usize,
+        num_js_threads: opt.num_js_threads as usize,
+        request_qsize: opt.request_qsize as usize,
+        num_conn: opt.num_conn as usize,
+        schedule,
         warmup_duration,
         duration,
-    )?;
-    print_results(&opt, duration, &exec_info);
+        connect_timeout,
+        read_timeout,
+        write_timeout,
+        arrival_process,
+        replay: replay_entries,
+        think_time: opt.think_time,
+        success_codes: opt.success_codes.clone(),
+        data: Arc::new(datasets),
+        report_interval,
+        trace: trace_writer.as_ref().map(TraceWriter::sender),
+        trace_sample_ratio: opt.trace_sample_ratio as f32,
+        start_at: None,
+        seed: opt.seed,
+    };
+    if let Some(mode) = opt.search {
+        let slo = opt
+            .slo
+            .clone()
+            .ok_or("--search needs an --slo to judge steps against")?;
+        if opt.arrival_process.is_closed() || !opt.schedule.is_empty() {
+            return Err("--search runs open loops at constant rates".into());
+        }
+        if !opt.assertions.is_empty() {
+            return Err("--search judges steps by --slo, not --assert".into());
+        }
+        let options = SearchOptions {
+            mode,
+            slo,
+            min_rate: opt.search_min,
+            max_rate: opt.search_max,
+            step: opt.search_step,
+        };
+        let steps = search::search(&config, &options)?;
+        let stdout_format = if opt.output_file.is_empty() {
+            opt.output_format
+        } else {
+            let mut f = BufWriter::new(File::create(&opt.output_file)?);
+            report::write_search_report(&mut f, opt.output_format, &opt, duration, &steps)?;
+            f.flush()?;
+            OutputFormat::Text
+        };
+        report::write_search_report(
+            &mut io::stdout().lock(),
+            stdout_format,
+            &opt,
+            duration,
+            &steps,
+        )?;
+        return Ok(());
+    }
 
-    if !opt.trace_save_path.is_empty() {
-        exec_info.save_trace(&opt.trace_save_path)?;
+    let exec_info = if agents.is_empty() {
+        runner::run(&config)?
+    } else {
+        agent::run(&config, &agents, &opt.agent_token)?
+    };
+    let target_rate = config.schedule.mean_rate();
+    drop(config);
+    if let Some(trace_writer) = trace_writer {
+        trace_writer.finish()?;
     }
+    if !opt.hdr_log.is_empty() {
+        hdrlog::write_interval_log(&opt.hdr_log, &exec_info)?;
+    }
+    let checks = opt
+        .assertions
+        .iter()
+        .map(|condition| condition.check(&exec_info, duration, target_rate))
+        .collect::<Vec<_>>();
+    let stdout_format = if opt.output_file.is_empty() {
+        opt.output_format
+    } else {
+        let mut f = BufWriter::new(File::create(&opt.output_file)?);
+        report::write_report(
+            &mut f,
+            opt.output_format,
+            &opt,
+            duration,
+            &exec_info,
+            &checks,
+        )?;
+        f.flush()?;
+        OutputFormat::Text
+    };
+    report::write_report(
+        &mut io::stdout().lock(),
+        stdout_format,
+        &opt,
+        duration,
+        &exec_info,
+        &checks,
+    )?;
 
+    if checks.iter().any(|check| !check.passed) {
+        process::exit(CHECK_FAILED_EXIT_CODE);
+    }
     Ok(())
 }
@@ -0,0 +1,115 @@
+use crate::trace::TraceReader;
+
+use std::fs;
+
+use bytes::Bytes;
+use serde::Deserialize;
+
+// zstd frame magic number, little endian
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Request to replay. Saved traces only know the type, request logs also
+/// carry the raw request bytes.
+#[derive(Clone)]
+pub struct ReplayEntry {
+    pub req_type: u32,
+    pub input: Option<Bytes>,
+}
+
+/// Requests of a saved trace or request log in start order, `times` being
+/// their start times in seconds relative to the first one.
+pub struct ReplayLog {
+    pub times: Vec<f64>,
+    pub entries: Vec<ReplayEntry>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    // Microseconds
+    start: u64,
+    #[serde(default, rename = "type")]
+    req_type: u32,
+    request: Option<String>,
+    request_base64: Option<String>,
+}
+
+impl ReplayLog {
+    /// Reads a trace written by `--trace-save-path`, or a request log with one
+    /// `{"start": <us>, "type": <n>, "request": <raw HTTP request>}` object per
+    /// line, `request_base64` taking the place of `request` for binary requests.
+    /// Request logs may be zstd-compressed, and so may JSON arrays of such objects
+    /// as saved by older versions.
+    pub fn load(path: &str) -> Result<ReplayLog, String> {
+        let read_error = |err: std::io::Error| format!("Failed to read {}: {}", path, err);
+        if TraceReader::is_trace(path) {
+            // Requests are replayed at the times they were scheduled at
+            let records = TraceReader::open(path)
+                .map_err(read_error)?
+                .map(|record| {
+                    record.map(|record| Record {
+                        start: record.scheduled,
+                        req_type: record.req_type,
+                        request: None,
+                        request_base64: None,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(read_error)?;
+            return Self::from_records(path, records);
+        }
+        let mut data = fs::read(path).map_err(read_error)?;
+        if data.starts_with(&ZSTD_MAGIC) {
+            data = zstd::stream::decode_all(&data[..]).map_err(read_error)?;
+        }
+        let parse_error = |err: serde_json::Error| format!("Invalid record in {}: {}", path, err);
+        let records = if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+            serde_json::from_slice::<Vec<Record>>(&data).map_err(parse_error)?
+        } else {
+            serde_json::Deserializer::from_slice(&data)
+                .into_iter::<Record>()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(parse_error)?
+        };
+        Self::from_records(path, records)
+    }
+
+    fn from_records(path: &str, records: Vec<Record>) -> Result<ReplayLog, String> {
+        if records.is_empty() {
+            return Err(format!("{} has no requests", path));
+        }
+
+        let mut entries = records
+            .into_iter()
+            .map(|record| {
+                let input = match (record.request, record.request_base64) {
+                    (Some(request), _) => Some(Bytes::from(request)),
+                    (None, Some(encoded)) => {
+                        Some(Bytes::from(base64::decode(encoded.trim()).map_err(
+                            |err| format!("Invalid request_base64 in {}: {}", path, err),
+                        )?))
+                    }
+                    (None, None) => None,
+                };
+                let entry = ReplayEntry {
+                    req_type: record.req_type,
+                    input,
+                };
+                Ok((record.start, entry))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // Traces are written in completion order
+        entries.sort_by_key(|&(start, _)| start);
+        let first = entries[0].0;
+        Ok(Self {
+            times: entries
+                .iter()
+                .map(|&(start, _)| (start - first) as f64 / 1e6)
+                .collect(),
+            entries: entries.into_iter().map(|(_, entry)| entry).collect(),
+        })
+    }
+
+    pub fn span(&self) -> f64 {
+        self.times[self.times.len() - 1]
+    }
+}
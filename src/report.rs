@@ -0,0 +1,1181 @@
+use crate::analyze::{Analysis, AnalyzeOpt};
+use crate::compare::{CompareOpt, Comparison, MetricKind};
+use crate::exec_info::ExecutionInfo;
+use crate::search::{self, SearchStep};
+use crate::slo::{self, Check};
+use crate::Opt;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use hdrhistogram::{Counter, Histogram};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl Serialize for OutputFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+const TYPE_TABLE_PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 100.0];
+
+const PERCENTILES: [f64; 8] = [50.0, 75.0, 90.0, 99.0, 99.9, 99.99, 99.999, 100.0];
+
+pub fn format_latency(micro: u64) -> String {
+    if micro < 1000 {
+        format!("{:>6.2}µs", micro as f64)
+    } else if micro < 1_000_000 {
+        format!("{:>6.2}ms", micro as f64 / 1000.0)
+    } else if micro < 1_000_000_000 {
+        format!("{:>6.2}s", micro as f64 / 1000000.0)
+    } else {
+        format!("{:>6.2}m", micro as f64 / 60000000.0)
+    }
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        format!("{:>6.2}B", bytes)
+    } else if bytes < 1024.0 * 1024.0 {
+        format!("{:>6.2}KB", bytes / 1024.0)
+    } else if bytes < 1024.0 * 1024.0 * 1024.0 {
+        format!("{:>6.2}MB", bytes / 1024.0 / 1024.0)
+    } else {
+        format!("{:>6.2}GB", bytes / 1024.0 / 1024.0 / 1024.0)
+    }
+}
+
+pub fn write_latency_distribution<T: Counter>(
+    out: &mut dyn Write,
+    hist: &Histogram<T>,
+) -> io::Result<()> {
+    writeln!(out, "  Latency Distribution (HdrHistogram)")?;
+    for &percentile in PERCENTILES.iter() {
+        writeln!(
+            out,
+            "{:>7.3}%  {}",
+            percentile,
+            format_latency(hist.value_at_percentile(percentile))
+        )?;
+    }
+    Ok(())
+}
+
+fn write_type_row(
+    out: &mut dyn Write,
+    name: &str,
+    success: u32,
+    failure: u32,
+    timeout: u32,
+    duration: Duration,
+    hist: &Histogram<u32>,
+) -> io::Result<()> {
+    write!(
+        out,
+        "  {:<16} {:>9} {:>9} {:>9} {:>10.2}",
+        name,
+        success,
+        failure,
+        timeout,
+        (success + failure) as f64 / duration.as_secs_f64()
+    )?;
+    for &percentile in TYPE_TABLE_PERCENTILES.iter() {
+        write!(
+            out,
+            " {:>10}",
+            format_latency(hist.value_at_percentile(percentile))
+        )?;
+    }
+    writeln!(out)
+}
+
+fn write_type_table(
+    out: &mut dyn Write,
+    duration: Duration,
+    exec_info: &ExecutionInfo,
+) -> io::Result<()> {
+    writeln!(out, "  Per-type breakdown")?;
+    write!(
+        out,
+        "  {:<16} {:>9} {:>9} {:>9} {:>10}",
+        "Type", "Success", "Failure", "Timeout", "Req/s"
+    )?;
+    for &percentile in TYPE_TABLE_PERCENTILES.iter() {
+        write!(out, " {:>10}", format!("p{}", percentile))?;
+    }
+    writeln!(out)?;
+    for (&req_type, stats) in exec_info.type_stats.iter() {
+        write_type_row(
+            out,
+            &exec_info.type_name(req_type),
+            stats.success_count,
+            stats.failure_count,
+            stats.timeout_count,
+            duration,
+            &stats.latency_hist,
+        )?;
+    }
+    write_type_row(
+        out,
+        "all",
+        exec_info.success_count,
+        exec_info.failure_count,
+        exec_info.timeout_count,
+        duration,
+        &exec_info.latency_hist,
+    )
+}
+
+fn write_scenario_table(
+    out: &mut dyn Write,
+    duration: Duration,
+    exec_info: &ExecutionInfo,
+) -> io::Result<()> {
+    writeln!(out, "  Per-scenario breakdown")?;
+    write!(
+        out,
+        "  {:<16} {:>9} {:>9} {:>9} {:>10}",
+        "Scenario", "Passed", "Failed", "Timeout", "Runs/s"
+    )?;
+    for &percentile in TYPE_TABLE_PERCENTILES.iter() {
+        write!(out, " {:>10}", format!("p{}", percentile))?;
+    }
+    writeln!(out)?;
+    for stats in exec_info.scenario_stats.iter() {
+        write_type_row(
+            out,
+            &stats.name,
+            stats.passed_count,
+            stats.failed_count,
+            stats.timeout_count,
+            duration,
+            &stats.latency_hist,
+        )?;
+        for (i, (name, step)) in stats.steps.iter().enumerate() {
+            write_type_row(
+                out,
+                &format!("  {}. {}", i + 1, name),
+                step.success_count,
+                step.failure_count,
+                step.timeout_count,
+                duration,
+                &step.latency_hist,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_phase_table(out: &mut dyn Write, exec_info: &ExecutionInfo) -> io::Result<()> {
+    let phases = match exec_info.schedule() {
+        Some(schedule) => schedule.phases(),
+        None => return Ok(()),
+    };
+    let names = phases
+        .iter()
+        .enumerate()
+        .map(|(i, phase)| format!("{}. {}", i + 1, phase.label))
+        .collect::<Vec<_>>();
+    // Labels are usually longer than type names, pad them to a common width
+    let name_width = names
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(16);
+    writeln!(out, "  Per-phase breakdown")?;
+    write!(
+        out,
+        "  {:<width$} {:>9} {:>9} {:>9} {:>10}",
+        "Phase",
+        "Success",
+        "Failure",
+        "Timeout",
+        "Req/s",
+        width = name_width
+    )?;
+    for &percentile in TYPE_TABLE_PERCENTILES.iter() {
+        write!(out, " {:>10}", format!("p{}", percentile))?;
+    }
+    writeln!(out)?;
+    for ((name, phase), stats) in names.iter().zip(phases).zip(exec_info.phase_stats.iter()) {
+        write_type_row(
+            out,
+            &format!("{:<width$}", name, width = name_width),
+            stats.success_count,
+            stats.failure_count,
+            stats.timeout_count,
+            phase.duration,
+            &stats.latency_hist,
+        )?;
+    }
+    Ok(())
+}
+
+fn write_text(
+    out: &mut dyn Write,
+    opt: &Opt,
+    duration: Duration,
+    exec_info: &ExecutionInfo,
+    checks: &[Check],
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "Running {} test @ http://{}",
+        humantime::format_duration(duration),
+        opt.host
+    )?;
+    if opt.arrival_process.is_closed() {
+        writeln!(
+            out,
+            "  {} connections, closed loop with think time {}",
+            opt.num_conn, opt.think_time
+        )?;
+    } else {
+        writeln!(out, "  {} connections", opt.num_conn)?;
+    }
+    let hist = &exec_info.latency_hist;
+    if !hist.is_empty() {
+        write_latency_distribution(out, hist)?;
+        writeln!(out)?;
+        writeln!(out, "  Detailed Percentile spectrum:")?;
+        writeln!(
+            out,
+            "       Value   Percentile   TotalCount 1/(1-Percentile)"
+        )?;
+        writeln!(out)?;
+        for iter_value in hist.iter_quantiles(1) {
+            if iter_value.count_since_last_iteration() > 0 {
+                writeln!(
+                    out,
+                    "  {:>10.3}  {:>10.6}  {:>10}  {:>10.2}",
+                    iter_value.value_iterated_to() as f32 / 1000.0,
+                    iter_value.percentile(),
+                    iter_value.count_since_last_iteration(),
+                    1.0 / (1.0 - iter_value.quantile())
+                )?;
+            }
+        }
+        writeln!(
+            out,
+            "----------------------------------------------------------"
+        )?;
+    }
+    let connect_hist = &exec_info.connect_hist;
+    if !connect_hist.is_empty() {
+        writeln!(
+            out,
+            "  Connect Latency ({} connections)",
+            connect_hist.len()
+        )?;
+        for &percentile in [50.0, 90.0, 99.0, 100.0].iter() {
+            writeln!(
+                out,
+                "{:>7.3}%  {}",
+                percentile,
+                format_latency(connect_hist.value_at_percentile(percentile))
+            )?;
+        }
+    }
+    writeln!(out)?;
+    let total_requests = exec_info.success_count + exec_info.failure_count;
+    writeln!(
+        out,
+        "  {} requests in {}, {} read",
+        total_requests,
+        humantime::format_duration(duration),
+        format_bytes(exec_info.bytes_recv as f64)
+    )?;
+    if !exec_info.status_counts.is_empty() {
+        let counts = exec_info
+            .status_counts
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<_>>();
+        writeln!(out, "  Status codes: {}", counts.join(", "))?;
+    }
+    if exec_info.failure_count > 0 {
+        writeln!(
+            out,
+            "  Responses not in {}: {}",
+            exec_info.success_codes(),
+            exec_info.failure_count
+        )?;
+    }
+    if exec_info.error_count() > 0 {
+        writeln!(
+            out,
+            "  Errors: connect {}, reset {}, read closed {}, write closed {}, timeout {}, parse {}, other {}",
+            exec_info.connect_error_count,
+            exec_info.reset_count,
+            exec_info.read_closed_count,
+            exec_info.write_closed_count,
+            exec_info.timeout_count,
+            exec_info.parse_error_count,
+            exec_info.conn_error_count
+        )?;
+    }
+    if exec_info.late_count > 0 {
+        let delay_hist = &exec_info.send_delay_hist;
+        writeln!(
+            out,
+            "  Sent behind schedule: {} (p99 delay {}, max {})",
+            exec_info.late_count,
+            format_latency(delay_hist.value_at_percentile(99.0)).trim(),
+            format_latency(delay_hist.max()).trim()
+        )?;
+    }
+    if exec_info.dropped_count > 0 {
+        writeln!(out, "  Never sent: {}", exec_info.dropped_count)?;
+    }
+    if !exec_info.type_stats.is_empty() {
+        write_type_table(out, duration, exec_info)?;
+    }
+    if !exec_info.scenario_stats.is_empty() {
+        write_scenario_table(out, duration, exec_info)?;
+    }
+    // A single phase would repeat the totals
+    if exec_info.phase_stats.len() > 1 {
+        write_phase_table(out, exec_info)?;
+    }
+    if opt.arrival_process.is_closed() {
+        writeln!(out, "Concurrency: {:>10}", opt.num_conn)?;
+    }
+    writeln!(
+        out,
+        "Requests/sec:{:>10.2}",
+        total_requests as f32 / duration.as_secs_f32()
+    )?;
+    writeln!(
+        out,
+        "Transfer/sec:    {}",
+        format_bytes(exec_info.bytes_sent as f64 / duration.as_secs_f64())
+    )?;
+    if !checks.is_empty() {
+        writeln!(out, "Assertions:")?;
+        for check in checks.iter() {
+            writeln!(
+                out,
+                "  {:<6} {} (actual {})",
+                if check.passed { "pass" } else { "FAIL" },
+                check.condition,
+                check.actual
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Version of the JSON and CSV schemas documented in README.md
+pub const SCHEMA_VERSION: u32 = 1;
+
+// JSON keys of PERCENTILES, e.g. `p99_9` for 99.9%
+fn percentile_key(percentile: f64) -> String {
+    format!("p{}", percentile).replace('.', "_")
+}
+
+fn hist_json(hist: &Histogram<u32>) -> Value {
+    let mut percentiles = Map::new();
+    for &percentile in PERCENTILES.iter() {
+        percentiles.insert(
+            percentile_key(percentile),
+            json!(hist.value_at_percentile(percentile)),
+        );
+    }
+    json!({
+        "count": hist.len(),
+        "min_us": hist.min(),
+        "max_us": hist.max(),
+        "mean_us": hist.mean(),
+        "stdev_us": hist.stdev(),
+        "percentiles_us": percentiles,
+    })
+}
+
+fn intervals_json(exec_info: &ExecutionInfo) -> Value {
+    let interval = match exec_info.report_interval() {
+        Some(interval) => interval,
+        None => return json!([]),
+    };
+    let secs = interval.as_secs_f64();
+    let intervals = exec_info
+        .intervals
+        .iter()
+        .enumerate()
+        .map(|(index, stats)| {
+            let hist = &stats.latency_hist;
+            json!({
+                "start_secs": index as f64 * secs,
+                "duration_secs": secs,
+                "success": stats.success_count,
+                "failure": stats.failure_count,
+                "timeout": stats.timeout_count,
+                "errors": stats.error_count,
+                "requests_per_sec": (stats.success_count + stats.failure_count) as f64 / secs,
+                "p50_us": hist.value_at_percentile(50.0),
+                "p90_us": hist.value_at_percentile(90.0),
+                "p99_us": hist.value_at_percentile(99.0),
+                "max_us": hist.max(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!(intervals)
+}
+
+fn phases_json(exec_info: &ExecutionInfo) -> Value {
+    let phases = match exec_info.schedule() {
+        Some(schedule) => schedule.phases(),
+        None => return json!([]),
+    };
+    let mut start_secs = 0.0;
+    let phases = phases
+        .iter()
+        .zip(exec_info.phase_stats.iter())
+        .map(|(phase, stats)| {
+            let secs = phase.duration.as_secs_f64();
+            let value = json!({
+                "label": phase.label,
+                "start_secs": start_secs,
+                "duration_secs": secs,
+                "start_rate": phase.start_rate,
+                "end_rate": phase.end_rate,
+                "success": stats.success_count,
+                "failure": stats.failure_count,
+                "timeout": stats.timeout_count,
+                "requests_per_sec": (stats.success_count + stats.failure_count) as f64 / secs,
+                "latency": hist_json(&stats.latency_hist),
+            });
+            start_secs += secs;
+            value
+        })
+        .collect::<Vec<_>>();
+    json!(phases)
+}
+
+fn scenarios_json(duration: Duration, exec_info: &ExecutionInfo) -> Value {
+    let secs = duration.as_secs_f64();
+    let scenarios = exec_info
+        .scenario_stats
+        .iter()
+        .map(|stats| {
+            let steps = stats
+                .steps
+                .iter()
+                .map(|(name, step)| {
+                    json!({
+                        "name": name,
+                        "passed": step.success_count,
+                        "failed": step.failure_count,
+                        "timeout": step.timeout_count,
+                        "requests_per_sec": (step.success_count + step.failure_count) as f64 / secs,
+                        "latency": hist_json(&step.latency_hist),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "name": stats.name,
+                "passed": stats.passed_count,
+                "failed": stats.failed_count,
+                "timeout": stats.timeout_count,
+                "runs_per_sec": (stats.passed_count + stats.failed_count) as f64 / secs,
+                "latency": hist_json(&stats.latency_hist),
+                "steps": steps,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!(scenarios)
+}
+
+// Throughput at the concurrency of a closed-loop run, null for open loops
+fn closed_loop_json(opt: &Opt, duration: Duration, exec_info: &ExecutionInfo) -> Value {
+    if !opt.arrival_process.is_closed() {
+        return Value::Null;
+    }
+    let total_requests = exec_info.success_count + exec_info.failure_count;
+    json!({
+        "concurrency": opt.num_conn,
+        "think_time": opt.think_time,
+        "requests_per_sec": total_requests as f64 / duration.as_secs_f64(),
+        "mean_latency_us": exec_info.latency_hist.mean(),
+    })
+}
+
+fn checks_json(checks: &[Check]) -> Value {
+    let checks = checks
+        .iter()
+        .map(|check| {
+            json!({
+                "condition": check.condition,
+                "actual": check.actual,
+                "passed": check.passed,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!(checks)
+}
+
+pub fn report_json(
+    opt: &Opt,
+    duration: Duration,
+    exec_info: &ExecutionInfo,
+    checks: &[Check],
+) -> Value {
+    let secs = duration.as_secs_f64();
+    let total_requests = exec_info.success_count + exec_info.failure_count;
+
+    let mut latency = hist_json(&exec_info.latency_hist);
+    let spectrum = exec_info
+        .latency_hist
+        .iter_quantiles(1)
+        .filter(|v| v.count_since_last_iteration() > 0)
+        .map(|v| {
+            json!({
+                "value_us": v.value_iterated_to(),
+                "percentile": v.percentile(),
+                "count": v.count_since_last_iteration(),
+            })
+        })
+        .collect::<Vec<_>>();
+    latency["spectrum"] = json!(spectrum);
+
+    let mut status_codes = Map::new();
+    for (status, count) in exec_info.status_counts.iter() {
+        status_codes.insert(status.to_string(), json!(count));
+    }
+    let mut types = Map::new();
+    for (&req_type, stats) in exec_info.type_stats.iter() {
+        types.insert(
+            req_type.to_string(),
+            json!({
+                "name": exec_info.type_names.get(&req_type),
+                "success": stats.success_count,
+                "failure": stats.failure_count,
+                "timeout": stats.timeout_count,
+                "requests_per_sec": (stats.success_count + stats.failure_count) as f64 / secs,
+                "latency": hist_json(&stats.latency_hist),
+            }),
+        );
+    }
+
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "config": opt,
+        "duration_secs": secs,
+        "summary": {
+            "requests": total_requests,
+            "sent": exec_info.request_total,
+            "success": exec_info.success_count,
+            "failure": exec_info.failure_count,
+            "requests_per_sec": total_requests as f64 / secs,
+            "bytes_sent": exec_info.bytes_sent,
+            "bytes_recv": exec_info.bytes_recv,
+            "late": exec_info.late_count,
+            "dropped": exec_info.dropped_count,
+            "success_codes": exec_info.success_codes().to_string(),
+            "status_codes": status_codes,
+            "errors": {
+                "connect": exec_info.connect_error_count,
+                "reset": exec_info.reset_count,
+                "read_closed": exec_info.read_closed_count,
+                "write_closed": exec_info.write_closed_count,
+                "timeout": exec_info.timeout_count,
+                "parse": exec_info.parse_error_count,
+                "other": exec_info.conn_error_count,
+            },
+        },
+        "latency": latency,
+        "connect_latency": hist_json(&exec_info.connect_hist),
+        "send_delay": hist_json(&exec_info.send_delay_hist),
+        "types": types,
+        "scenarios": scenarios_json(duration, exec_info),
+        "closed_loop": closed_loop_json(opt, duration, exec_info),
+        "phases": phases_json(exec_info),
+        "intervals": intervals_json(exec_info),
+        "assertions": checks_json(checks),
+    })
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Flatten JSON into `metric,value` rows, metric being the dotted key path
+fn write_csv_rows(out: &mut dyn Write, path: &str, value: &Value) -> io::Result<()> {
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter() {
+                write_csv_rows(out, &join(key), value)?;
+            }
+            Ok(())
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                write_csv_rows(out, &join(&i.to_string()), value)?;
+            }
+            Ok(())
+        }
+        Value::Null => writeln!(out, "{},", csv_field(path)),
+        Value::String(s) => writeln!(out, "{},{}", csv_field(path), csv_field(s)),
+        _ => writeln!(out, "{},{}", csv_field(path), value),
+    }
+}
+
+fn write_search_text(
+    out: &mut dyn Write,
+    opt: &Opt,
+    duration: Duration,
+    steps: &[SearchStep],
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "Throughput search @ http://{}, {} per step",
+        opt.host,
+        humantime::format_duration(duration)
+    )?;
+    if let Some(slo) = opt.slo.as_ref() {
+        writeln!(out, "  SLO: {}", slo)?;
+    }
+    writeln!(
+        out,
+        "  {:>4} {:>10} {:>10} {:>10} {:>10} {:>10}  Result",
+        "Step", "Target", "Req/s", "p50", "p99", "Errors"
+    )?;
+    for (i, step) in steps.iter().enumerate() {
+        let exec_info = &step.exec_info;
+        let hist = &exec_info.latency_hist;
+        let failed = step
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| format!("{} ({})", check.condition, check.actual))
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "  {:>4} {:>10.2} {:>10.2} {:>10} {:>10} {:>9.3}%  {}",
+            i + 1,
+            step.rate,
+            (exec_info.success_count + exec_info.failure_count) as f64 / duration.as_secs_f64(),
+            format_latency(hist.value_at_percentile(50.0)),
+            format_latency(hist.value_at_percentile(99.0)),
+            slo::error_rate(exec_info) * 100.0,
+            if step.passed {
+                "pass".to_string()
+            } else {
+                format!("fail: {}", failed.join(", "))
+            }
+        )?;
+    }
+    match search::max_sustainable_rate(steps) {
+        Some(rate) => writeln!(out, "Max sustainable rate: {:.2} req/s", rate),
+        None => writeln!(out, "No rate tried meets the SLO"),
+    }
+}
+
+pub fn search_report_json(opt: &Opt, duration: Duration, steps: &[SearchStep]) -> Value {
+    let secs = duration.as_secs_f64();
+    let steps_json = steps
+        .iter()
+        .map(|step| {
+            let exec_info = &step.exec_info;
+            json!({
+                "target_rate": step.rate,
+                "requests_per_sec": (exec_info.success_count + exec_info.failure_count) as f64 / secs,
+                "error_rate": slo::error_rate(exec_info),
+                "passed": step.passed,
+                "checks": checks_json(&step.checks),
+                "latency": hist_json(&exec_info.latency_hist),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "config": opt,
+        "duration_secs": secs,
+        "max_sustainable_rate": search::max_sustainable_rate(steps),
+        "steps": steps_json,
+    })
+}
+
+pub fn write_search_report(
+    out: &mut dyn Write,
+    format: OutputFormat,
+    opt: &Opt,
+    duration: Duration,
+    steps: &[SearchStep],
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => write_search_text(out, opt, duration, steps),
+        OutputFormat::Json => {
+            let report = search_report_json(opt, duration, steps);
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "metric,value")?;
+            write_csv_rows(out, "", &search_report_json(opt, duration, steps))
+        }
+    }
+}
+
+pub fn write_report(
+    out: &mut dyn Write,
+    format: OutputFormat,
+    opt: &Opt,
+    duration: Duration,
+    exec_info: &ExecutionInfo,
+    checks: &[Check],
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => write_text(out, opt, duration, exec_info, checks),
+        OutputFormat::Json => {
+            let report = report_json(opt, duration, exec_info, checks);
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "metric,value")?;
+            write_csv_rows(out, "", &report_json(opt, duration, exec_info, checks))
+        }
+    }
+}
+
+fn write_analysis_text(
+    out: &mut dyn Write,
+    opt: &AnalyzeOpt,
+    analysis: &Analysis,
+) -> io::Result<()> {
+    let started = UNIX_EPOCH + Duration::from_micros(analysis.start_time);
+    writeln!(
+        out,
+        "Trace {}, recorded {}",
+        opt.trace_path,
+        humantime::format_rfc3339_seconds(started)
+    )?;
+    let window = analysis.window();
+    writeln!(
+        out,
+        "  {} to {}, {}",
+        humantime::format_duration(analysis.from),
+        humantime::format_duration(Duration::from_millis(
+            (analysis.from + window).as_millis() as u64
+        )),
+        match opt.req_type {
+            Some(req_type) => format!("type {}", req_type),
+            None => "all types".to_string(),
+        }
+    )?;
+    let responses = analysis.status_counts.values().sum::<u64>();
+    writeln!(
+        out,
+        "  {} requests, {} responses ({:.2}/sec), {} sent, {} read",
+        analysis.request_count,
+        responses,
+        responses as f64 / window.as_secs_f64(),
+        format_bytes(analysis.bytes_sent as f64),
+        format_bytes(analysis.bytes_recv as f64)
+    )?;
+    if !analysis.status_counts.is_empty() {
+        let counts = analysis
+            .status_counts
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<_>>();
+        writeln!(out, "  Status codes: {}", counts.join(", "))?;
+    }
+    if !analysis.error_counts.is_empty() {
+        let counts = analysis
+            .error_counts
+            .iter()
+            .map(|(kind, count)| format!("{} {}", kind, count))
+            .collect::<Vec<_>>();
+        writeln!(out, "  Errors: {}", counts.join(", "))?;
+    }
+    if !analysis.latency_hist.is_empty() {
+        write_latency_distribution(out, &analysis.latency_hist)?;
+    }
+
+    writeln!(out, "  Latency over time")?;
+    writeln!(
+        out,
+        "  {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "Time", "Req/s", "p50", "p99", "Max", "Errors"
+    )?;
+    for (i, bucket) in analysis.buckets.iter().enumerate() {
+        let hist = &bucket.latency_hist;
+        let start = analysis.from + analysis.bucket * i as u32;
+        writeln!(
+            out,
+            "  {:>10} {:>10.2} {:>10} {:>10} {:>10} {:>10}",
+            humantime::format_duration(start).to_string(),
+            bucket.response_count as f64 / analysis.bucket.as_secs_f64(),
+            format_latency(hist.value_at_percentile(50.0)),
+            format_latency(hist.value_at_percentile(99.0)),
+            format_latency(hist.max()),
+            bucket.error_count
+        )?;
+    }
+
+    if !analysis.outliers.is_empty() {
+        writeln!(out, "  Slowest requests")?;
+        writeln!(
+            out,
+            "  {:>12} {:>6} {:>8} {:>6} {:>12} {:>10} {:>10} {:>10} {:>10}",
+            "Scheduled", "Type", "Conn", "Status", "Error", "Latency", "Delay", "Sent", "Read"
+        )?;
+        for record in analysis.outliers.iter() {
+            writeln!(
+                out,
+                "  {:>12.6} {:>6} {:>8} {:>6} {:>12} {:>10} {:>10} {:>10} {:>10}",
+                record.scheduled as f64 / 1e6,
+                record.req_type,
+                record.conn_id,
+                record.status,
+                record.error.to_string(),
+                format_latency(record.latency()),
+                format_latency(record.start.saturating_sub(record.scheduled)),
+                format_bytes(record.bytes_sent as f64),
+                format_bytes(record.bytes_recv as f64)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn analysis_json(opt: &AnalyzeOpt, analysis: &Analysis) -> Value {
+    let window = analysis.window();
+    let buckets = analysis
+        .buckets
+        .iter()
+        .enumerate()
+        .map(|(i, bucket)| {
+            json!({
+                "start_secs": (analysis.from + analysis.bucket * i as u32).as_secs_f64(),
+                "requests_per_sec": bucket.response_count as f64 / analysis.bucket.as_secs_f64(),
+                "errors": bucket.error_count,
+                "latency": hist_json(&bucket.latency_hist),
+            })
+        })
+        .collect::<Vec<_>>();
+    let outliers = analysis
+        .outliers
+        .iter()
+        .map(|record| {
+            json!({
+                "type": record.req_type,
+                "conn_id": record.conn_id,
+                "scheduled_us": record.scheduled,
+                "start_us": record.start,
+                "finish_us": record.finish,
+                "latency_us": record.latency(),
+                "status": record.status,
+                "error": record.error.to_string(),
+                "bytes_sent": record.bytes_sent,
+                "bytes_recv": record.bytes_recv,
+            })
+        })
+        .collect::<Vec<_>>();
+    let status_counts = analysis
+        .status_counts
+        .iter()
+        .map(|(status, count)| (status.to_string(), json!(count)))
+        .collect::<Map<_, _>>();
+    let started = UNIX_EPOCH + Duration::from_micros(analysis.start_time);
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "config": opt,
+        "recorded_at": humantime::format_rfc3339_seconds(started).to_string(),
+        "from_secs": analysis.from.as_secs_f64(),
+        "to_secs": (analysis.from + window).as_secs_f64(),
+        "requests": analysis.request_count,
+        "bytes_sent": analysis.bytes_sent,
+        "bytes_recv": analysis.bytes_recv,
+        "requests_per_sec": analysis.status_counts.values().sum::<u64>() as f64 / window.as_secs_f64(),
+        "status_counts": status_counts,
+        "errors": analysis.error_counts,
+        "latency": hist_json(&analysis.latency_hist),
+        "buckets": buckets,
+        "outliers": outliers,
+    })
+}
+
+pub fn write_analysis_report(
+    out: &mut dyn Write,
+    format: OutputFormat,
+    opt: &AnalyzeOpt,
+    analysis: &Analysis,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => write_analysis_text(out, opt, analysis),
+        OutputFormat::Json => {
+            let report = analysis_json(opt, analysis);
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "metric,value")?;
+            write_csv_rows(out, "", &analysis_json(opt, analysis))
+        }
+    }
+}
+
+fn format_metric(kind: MetricKind, value: f64) -> String {
+    match kind {
+        MetricKind::Latency => format_latency(value as u64).trim().to_string(),
+        MetricKind::Throughput => format!("{:.2}", value),
+        MetricKind::ErrorRate => format!("{:.3}%", value * 100.0),
+    }
+}
+
+fn write_comparison_text(
+    out: &mut dyn Write,
+    opt: &CompareOpt,
+    comparisons: &[Comparison],
+    unmatched: &[String],
+) -> io::Result<()> {
+    writeln!(out, "Comparing {} against {}", opt.candidate, opt.baseline)?;
+    writeln!(
+        out,
+        "  Tolerances: latency {} + {}, throughput {}, errors {}",
+        opt.latency_tolerance, opt.latency_slack, opt.throughput_tolerance, opt.error_tolerance
+    )?;
+    let scope_width = comparisons
+        .iter()
+        .map(|comparison| comparison.scope.len())
+        .max()
+        .unwrap_or(0)
+        .max(16);
+    writeln!(
+        out,
+        "  {:<width$} {:>8} {:>12} {:>12} {:>9}  Result",
+        "Scope",
+        "Metric",
+        "Baseline",
+        "Candidate",
+        "Change",
+        width = scope_width
+    )?;
+    for comparison in comparisons.iter() {
+        let change = if comparison.baseline == 0.0 {
+            "-".to_string()
+        } else {
+            format!(
+                "{:+.1}%",
+                (comparison.candidate / comparison.baseline - 1.0) * 100.0
+            )
+        };
+        writeln!(
+            out,
+            "  {:<width$} {:>8} {:>12} {:>12} {:>9}  {}",
+            comparison.scope,
+            comparison.metric,
+            format_metric(comparison.kind, comparison.baseline),
+            format_metric(comparison.kind, comparison.candidate),
+            change,
+            if comparison.regressed {
+                "REGRESSION"
+            } else {
+                "ok"
+            },
+            width = scope_width
+        )?;
+    }
+    if !unmatched.is_empty() {
+        writeln!(out, "  Not in both runs: {}", unmatched.join(", "))?;
+    }
+    let regressions = comparisons
+        .iter()
+        .filter(|comparison| comparison.regressed)
+        .count();
+    if regressions == 0 {
+        writeln!(out, "No regressions")
+    } else {
+        writeln!(out, "{} regressions", regressions)
+    }
+}
+
+pub fn comparison_json(
+    opt: &CompareOpt,
+    comparisons: &[Comparison],
+    unmatched: &[String],
+) -> Value {
+    let comparisons_json = comparisons
+        .iter()
+        .map(|comparison| {
+            json!({
+                "scope": comparison.scope,
+                "metric": comparison.metric,
+                "baseline": comparison.baseline,
+                "candidate": comparison.candidate,
+                "regressed": comparison.regressed,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "config": opt,
+        "regressed": comparisons.iter().any(|comparison| comparison.regressed),
+        "comparisons": comparisons_json,
+        "unmatched_types": unmatched,
+    })
+}
+
+pub fn write_comparison_report(
+    out: &mut dyn Write,
+    format: OutputFormat,
+    opt: &CompareOpt,
+    comparisons: &[Comparison],
+    unmatched: &[String],
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => write_comparison_text(out, opt, comparisons, unmatched),
+        OutputFormat::Json => {
+            let report = comparison_json(opt, comparisons, unmatched);
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "metric,value")?;
+            write_csv_rows(out, "", &comparison_json(opt, comparisons, unmatched))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec_info::RequestStats;
+    use structopt::StructOpt;
+
+    // 95 successes and 5 failures of type 0 named login, 1ms to 100ms
+    fn exec_info() -> ExecutionInfo {
+        let mut exec_info = ExecutionInfo::new(1_000_000);
+        let mut stats = RequestStats::new(1_000_000);
+        for i in 1..=100 {
+            exec_info.latency_hist.record(i * 1000).unwrap();
+            stats.latency_hist.record(i * 1000).unwrap();
+        }
+        exec_info.request_total = 100;
+        exec_info.success_count = 95;
+        exec_info.failure_count = 5;
+        exec_info.status_counts.insert(200, 95);
+        exec_info.status_counts.insert(500, 5);
+        stats.success_count = 95;
+        stats.failure_count = 5;
+        exec_info.type_stats.insert(0, stats);
+        exec_info.type_names.insert(0, "login".to_string());
+        exec_info
+    }
+
+    #[test]
+    fn reports_json() {
+        let opt = Opt::from_iter(&["flood", "script.js"]);
+        let report = report_json(&opt, Duration::from_secs(10), &exec_info(), &[]);
+        let keys = report.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                "assertions",
+                "closed_loop",
+                "config",
+                "connect_latency",
+                "duration_secs",
+                "intervals",
+                "latency",
+                "phases",
+                "scenarios",
+                "schema_version",
+                "send_delay",
+                "summary",
+                "types",
+            ]
+        );
+        assert_eq!(report["schema_version"], json!(SCHEMA_VERSION));
+        assert_eq!(report["duration_secs"], json!(10.0));
+        assert_eq!(report["summary"]["requests"], json!(100));
+        assert_eq!(report["summary"]["requests_per_sec"], json!(10.0));
+        assert_eq!(report["summary"]["status_codes"]["500"], json!(5));
+        assert_eq!(report["types"]["0"]["name"], json!("login"));
+        assert_eq!(report["types"]["0"]["latency"]["count"], json!(100));
+        assert!(report["latency"]["percentiles_us"]["p99"].is_u64());
+    }
+
+    #[test]
+    fn flattens_csv() {
+        let opt = Opt::from_iter(&["flood", "script.js"]);
+        let duration = Duration::from_secs(10);
+        let exec_info = exec_info();
+        let mut out = Vec::new();
+        write_report(&mut out, OutputFormat::Csv, &opt, duration, &exec_info, &[]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("metric,value"));
+        let rows = lines
+            .map(|line| line.split_once(',').unwrap())
+            .collect::<Vec<_>>();
+        let report = report_json(&opt, duration, &exec_info, &[]);
+        let cases = [
+            ("schema_version", SCHEMA_VERSION.to_string()),
+            ("summary.success", "95".to_string()),
+            ("summary.errors.connect", "0".to_string()),
+            ("types.0.name", "login".to_string()),
+            (
+                "types.0.latency.percentiles_us.p99",
+                report["types"]["0"]["latency"]["percentiles_us"]["p99"].to_string(),
+            ),
+            ("closed_loop", "".to_string()),
+        ];
+        for (path, value) in cases.iter() {
+            let found = rows.iter().find(|(metric, _)| metric == path);
+            assert_eq!(found, Some(&(*path, value.as_str())), "{}", path);
+        }
+        // Paths are unique, as they become the metric names
+        let mut metrics = rows.iter().map(|(metric, _)| metric).collect::<Vec<_>>();
+        metrics.sort();
+        metrics.dedup();
+        assert_eq!(metrics.len(), rows.len());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::arrival::ArrivalProcess;
+use crate::client::{Client, ThinkTime};
+use crate::data::Dataset;
+use crate::exec_info::{ExecutionInfo, IntervalStats};
+use crate::generator::{Generator, ScenarioDef};
+use crate::http::SuccessCodes;
+use crate::replay::ReplayEntry;
+use crate::report::format_latency;
+use crate::schedule::RateSchedule;
+use crate::trace::TraceSender;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Clone)]
+pub struct RunConfig {
+    pub addr: SocketAddr,
+    pub host: String,
+    pub script: String,
+    pub num_threads: usize,
+    pub num_js_threads: usize,
+    pub request_qsize: usize,
+    pub num_conn: usize,
+    // Total rate over all event loops
+    pub schedule: RateSchedule,
+    pub warmup_duration: Duration,
+    pub duration: Duration,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub arrival_process: ArrivalProcess,
+    pub think_time: ThinkTime,
+    // Requests of a replayed log, matching the times of `arrival_process`
+    pub replay: Option<Arc<Vec<ReplayEntry>>>,
+    pub success_codes: SuccessCodes,
+    // Datasets of --data, shared by every event loop
+    pub data: Arc<Vec<Dataset>>,
+    // Sampled requests are streamed here if set
+    pub trace: Option<TraceSender>,
+    pub trace_sample_ratio: f32,
+    pub report_interval: Option<Duration>,
+    // Wall clock time event loops start at once set up, right away if unset
+    pub start_at: Option<SystemTime>,
+    // Seed of every random stream of the run, from entropy if unset
+    pub seed: Option<u64>,
+}
+
+/// Seed of the independent random stream `stream` of `seed`, mixed by SplitMix64.
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    let mut z = seed.wrapping_add((stream + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Generator of the random stream `stream` of `seed`, unpredictable without a seed
+fn seeded_rng(seed: Option<u64>, stream: u64) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(derive_seed(seed, stream)),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// Run `config.num_threads` independent event loops, each with its own share of
+// connections and rate, and merge their results
+pub fn run(config: &RunConfig) -> Result<ExecutionInfo> {
+    let num_threads = config.num_threads;
+    if num_threads == 0 || config.num_conn < num_threads {
+        return Err(format!(
+            "Need at least one connection per event loop ({} connections, {} threads)",
+            config.num_conn, num_threads
+        )
+        .into());
+    }
+    // Every run reads the datasets from the start, e.g. every step of a search
+    for dataset in config.data.iter() {
+        dataset.rewind();
+    }
+    let barrier = Barrier::new(num_threads);
+    let results = thread::scope(|s| {
+        let (sender, receiver) = mpsc::channel();
+        if let Some(interval) = config.report_interval {
+            s.spawn(move || print_progress(receiver, num_threads, interval));
+        }
+        let handles = (0..num_threads)
+            .map(|i| {
+                let barrier = &barrier;
+                let sender = config.report_interval.map(|_| sender.clone());
+                s.spawn(move || run_event_loop(config, i, barrier, sender))
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+        handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result,
+                Err(_) => Err("Event loop thread panicked".into()),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut merged: Option<ExecutionInfo> = None;
+    for result in results {
+        let exec_info = result?;
+        match merged.as_mut() {
+            Some(merged) => merged.merge(exec_info),
+            None => merged = Some(exec_info),
+        }
+    }
+    Ok(merged.unwrap())
+}
+
+// Print a progress line once every event loop has finished an interval
+fn print_progress(
+    receiver: Receiver<(usize, IntervalStats)>,
+    num_threads: usize,
+    interval: Duration,
+) {
+    let mut pending = BTreeMap::<usize, (usize, IntervalStats)>::new();
+    for (index, stats) in receiver {
+        let entry = pending
+            .entry(index)
+            .or_insert_with(|| (0, IntervalStats::new()));
+        entry.0 += 1;
+        entry.1.merge(&stats);
+        if entry.0 < num_threads {
+            continue;
+        }
+        let (_, stats) = pending.remove(&index).unwrap();
+        let hist = &stats.latency_hist;
+        eprintln!(
+            "[{:>6}] {:>10.2} req/s  p50 {}  p99 {}  max {}  failures {}  timeouts {}  errors {}",
+            humantime::format_duration(interval * (index as u32 + 1)).to_string(),
+            (stats.success_count + stats.failure_count) as f64 / interval.as_secs_f64(),
+            format_latency(hist.value_at_percentile(50.0)),
+            format_latency(hist.value_at_percentile(99.0)),
+            format_latency(hist.max()),
+            stats.failure_count,
+            stats.timeout_count,
+            stats.error_count
+        );
+    }
+}
+
+fn run_event_loop(
+    config: &RunConfig,
+    index: usize,
+    barrier: &Barrier,
+    progress: Option<Sender<(usize, IntervalStats)>>,
+) -> Result<ExecutionInfo> {
+    let num_threads = config.num_threads;
+    let num_conn =
+        config.num_conn / num_threads + usize::from(index < config.num_conn % num_threads);
+    let schedule = config.schedule.scaled(1.0 / num_threads as f64);
+    // Every event loop draws from its own streams
+    let seed = config.seed.map(|seed| derive_seed(seed, index as u64));
+
+    let setup = || -> Result<(Client, BTreeMap<u32, String>, Vec<ScenarioDef>)> {
+        // Seeded runs generate requests on the event loop, as their order would
+        // otherwise depend on how JS threads race to fill the queue
+        let num_js_threads = match seed {
+            Some(_) => 0,
+            None => (config.num_js_threads / num_threads).max(1),
+        };
+        let mut generator = Generator::new(
+            &config.host,
+            num_js_threads,
+            config.request_qsize,
+            config.data.clone(),
+        );
+        if let Some(seed) = seed {
+            generator.set_seed(seed)?;
+        }
+        generator.load_user_script(&config.script)?;
+        let type_names = generator.type_names()?;
+        let scenarios = generator.scenarios()?;
+        if let Some(entries) = config.replay.as_ref() {
+            // Dealt out like the send times of the replay
+            generator.set_replay(
+                entries
+                    .iter()
+                    .skip(index)
+                    .step_by(num_threads)
+                    .cloned()
+                    .collect(),
+            )?;
+        }
+        let mut client = Client::new(&config.addr, generator);
+        client.set_connect_timeout(config.connect_timeout);
+        client.set_read_timeout(config.read_timeout);
+        client.set_write_timeout(config.write_timeout);
+        client.set_arrival_process(config.arrival_process.for_thread(index, num_threads));
+        client.set_think_time(config.think_time);
+        client.set_rng(seeded_rng(seed, 1));
+        if let Some(progress) = progress {
+            client.set_progress_sender(progress);
+        }
+        // Stagger the schedules so that loops do not send in lockstep
+        client.set_arrival_offset(index as f64 / num_threads as f64);
+        Ok((client, type_names, scenarios))
+    };
+    let setup = setup();
+    // Every loop has to reach the barrier, even the ones that failed to set up
+    barrier.wait();
+    let (mut client, type_names, scenarios) = setup?;
+    if let Some(start_at) = config.start_at {
+        match start_at.duration_since(SystemTime::now()) {
+            Ok(delay) => thread::sleep(delay),
+            Err(err) => warn!(
+                "Event loop {} started {:.1}ms late",
+                index,
+                err.duration().as_secs_f64() * 1000.0
+            ),
+        }
+    }
+    info!(
+        "Event loop {} runs {} connections at {:.2} QPS on average",
+        index,
+        num_conn,
+        schedule.mean_rate()
+    );
+
+    // Only the initial bound of the histograms, they grow to fit latencies
+    // that include time spent waiting behind the schedule for a connection
+    let hist_max = (config.read_timeout + config.write_timeout).as_micros() as u64;
+    let mut exec_info = ExecutionInfo::new(hist_max);
+    if let Some(trace) = config.trace.as_ref() {
+        exec_info.set_trace(trace.sink(), config.trace_sample_ratio, seeded_rng(seed, 2));
+    }
+    exec_info.type_names = type_names;
+    exec_info.set_scenarios(&scenarios);
+    exec_info.set_success_codes(config.success_codes.clone());
+    // Phases are reported at the total rate, closed loops have no schedule
+    if !config.arrival_process.is_closed() {
+        exec_info.set_schedule(config.schedule.clone());
+    }
+    if let Some(interval) = config.report_interval {
+        exec_info.set_report_interval(interval);
+    }
+    client.run(
+        &mut exec_info,
+        num_conn,
+        &schedule,
+        config.warmup_duration,
+        config.duration,
+    )?;
+    Ok(exec_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::collections::HashSet;
+
+    #[test]
+    fn derives_distinct_streams() {
+        let mut seeds = HashSet::new();
+        for seed in 0..64 {
+            for stream in 0..64 {
+                assert_eq!(derive_seed(seed, stream), derive_seed(seed, stream));
+                seeds.insert(derive_seed(seed, stream));
+            }
+        }
+        assert_eq!(seeds.len(), 64 * 64);
+        // Neighbouring streams differ in about half of their bits
+        let mean_flips = (0..1000)
+            .map(|stream| (derive_seed(7, stream) ^ derive_seed(7, stream + 1)).count_ones())
+            .sum::<u32>() as f64
+            / 1000.0;
+        assert!((mean_flips - 32.0).abs() < 2.0, "{}", mean_flips);
+    }
+
+    #[test]
+    fn repeats_seeded_streams() {
+        let draws = |seed, stream| {
+            let mut rng = seeded_rng(seed, stream);
+            (0..16).map(|_| rng.gen::<u64>()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(Some(42), 1), draws(Some(42), 1));
+        assert_ne!(draws(Some(42), 1), draws(Some(42), 2));
+        assert_ne!(draws(Some(42), 1), draws(Some(43), 1));
+        assert_ne!(draws(None, 1), draws(None, 1));
+    }
+}
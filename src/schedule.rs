@@ -0,0 +1,307 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A phase changes the arrival rate linearly from `start_rate` to `end_rate`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Phase {
+    pub label: String,
+    pub start_rate: f64,
+    pub end_rate: f64,
+    pub duration: Duration,
+}
+
+impl Phase {
+    pub fn mean_rate(&self) -> f64 {
+        (self.start_rate + self.end_rate) / 2.0
+    }
+}
+
+/// Arrival rate over time, parsed from phases separated by commas or newlines:
+///
+///     ramp 100->5000 over 60s, hold 5000 for 120s, step 8000 for 30s
+///
+/// Lines starting with `#` are comments. Warm-up runs at the initial rate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateSchedule {
+    phases: Vec<Phase>,
+}
+
+impl RateSchedule {
+    pub fn constant(rate: f64, duration: Duration) -> RateSchedule {
+        Self {
+            phases: vec![Phase {
+                label: format!("hold {} for {}", rate, humantime::format_duration(duration)),
+                start_rate: rate,
+                end_rate: rate,
+                duration,
+            }],
+        }
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration).sum()
+    }
+
+    pub fn mean_rate(&self) -> f64 {
+        let total: f64 = self
+            .phases
+            .iter()
+            .map(|phase| phase.mean_rate() * phase.duration.as_secs_f64())
+            .sum();
+        total / self.duration().as_secs_f64()
+    }
+
+    // Same shape at `factor` times the rate, labels are kept
+    pub fn scaled(&self, factor: f64) -> RateSchedule {
+        Self {
+            phases: self
+                .phases
+                .iter()
+                .map(|phase| Phase {
+                    label: phase.label.clone(),
+                    start_rate: phase.start_rate * factor,
+                    end_rate: phase.end_rate * factor,
+                    duration: phase.duration,
+                })
+                .collect(),
+        }
+    }
+
+    // Index of the phase running at `t` seconds after the schedule starts
+    pub fn phase_index(&self, t: f64) -> usize {
+        let mut phase_end = 0.0;
+        for (index, phase) in self.phases.iter().enumerate() {
+            phase_end += phase.duration.as_secs_f64();
+            if t < phase_end {
+                return index;
+            }
+        }
+        self.phases.len() - 1
+    }
+
+    /// Time at which `amount` more arrivals are expected after time `t`, both in
+    /// seconds relative to the schedule start. Returns None if the rate drops to
+    /// zero for good before that.
+    pub fn advance(&self, t: f64, amount: f64) -> Option<f64> {
+        let mut t = t;
+        let mut amount = amount;
+        if t < 0.0 {
+            let rate = self.phases[0].start_rate;
+            if rate > 0.0 && amount <= rate * -t {
+                return Some(t + amount / rate);
+            }
+            amount -= rate * -t;
+            t = 0.0;
+        }
+        let mut phase_start = 0.0;
+        for phase in self.phases.iter() {
+            let length = phase.duration.as_secs_f64();
+            let phase_end = phase_start + length;
+            if t < phase_end {
+                let slope = (phase.end_rate - phase.start_rate) / length;
+                let rate = phase.start_rate + slope * (t - phase_start);
+                let span = phase_end - t;
+                let available = rate * span + 0.5 * slope * span * span;
+                // Nothing to wait for, even where the rate is zero
+                if amount <= 0.0 {
+                    return Some(t);
+                }
+                if amount <= available {
+                    // Solve rate * x + slope * x^2 / 2 = amount in a numerically stable way
+                    let denom = rate + (rate * rate + 2.0 * slope * amount).max(0.0).sqrt();
+                    if denom > 0.0 {
+                        return Some(t + 2.0 * amount / denom);
+                    }
+                }
+                amount -= available;
+                t = phase_end;
+            }
+            phase_start = phase_end;
+        }
+        // The final rate is kept after the schedule ends
+        let rate = self.phases.last().unwrap().end_rate;
+        if rate > 0.0 {
+            Some(t + amount / rate)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(rate) if rate >= 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("Invalid rate: {}", s)),
+    }
+}
+
+fn parse_phase(s: &str) -> Result<Phase, String> {
+    let words = s.split_whitespace().collect::<Vec<_>>();
+    let (start_rate, end_rate, duration) = match words.as_slice() {
+        ["ramp", rates, "over", duration] => {
+            let (from, to) = rates
+                .split_once("->")
+                .ok_or_else(|| format!("Invalid ramp: {}", s))?;
+            (parse_rate(from)?, parse_rate(to)?, duration)
+        }
+        ["hold", rate, "for", duration] | ["step", rate, "for", duration] => {
+            let rate = parse_rate(rate)?;
+            (rate, rate, duration)
+        }
+        _ => return Err(format!("Invalid schedule phase: {}", s)),
+    };
+    let duration = humantime::parse_duration(duration).map_err(|err| err.to_string())?;
+    if duration.is_zero() {
+        return Err(format!("Phase must not be empty: {}", s));
+    }
+    Ok(Phase {
+        label: words.join(" "),
+        start_rate,
+        end_rate,
+        duration,
+    })
+}
+
+impl FromStr for RateSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateSchedule, String> {
+        let phases = s
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|phase| !phase.is_empty())
+            .map(parse_phase)
+            .collect::<Result<Vec<_>, _>>()?;
+        if phases.is_empty() {
+            return Err("Schedule has no phases".to_string());
+        }
+        Ok(Self { phases })
+    }
+}
+
+impl fmt::Display for RateSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self
+            .phases
+            .iter()
+            .map(|phase| phase.label.as_str())
+            .collect::<Vec<_>>();
+        write!(f, "{}", labels.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_phases() {
+        let schedule = "ramp 100->5000 over 60s, hold 5000 for 2m\n\
+                        # spike\n\
+                        step 8000 for 30s,\n"
+            .parse::<RateSchedule>()
+            .unwrap();
+        let phases = schedule
+            .phases()
+            .iter()
+            .map(|phase| {
+                (
+                    phase.label.as_str(),
+                    phase.start_rate,
+                    phase.end_rate,
+                    phase.duration.as_secs(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            vec![
+                ("ramp 100->5000 over 60s", 100.0, 5000.0, 60),
+                ("hold 5000 for 2m", 5000.0, 5000.0, 120),
+                ("step 8000 for 30s", 8000.0, 8000.0, 30),
+            ]
+        );
+        assert_eq!(schedule.duration(), Duration::from_secs(210));
+        assert_eq!(
+            schedule.mean_rate(),
+            (2550.0 * 60.0 + 5000.0 * 120.0 + 8000.0 * 30.0) / 210.0
+        );
+        assert_eq!(
+            schedule.to_string(),
+            "ramp 100->5000 over 60s, hold 5000 for 2m, step 8000 for 30s"
+        );
+        assert_eq!(schedule.phase_index(59.9), 0);
+        assert_eq!(schedule.phase_index(60.0), 1);
+        assert_eq!(schedule.phase_index(500.0), 2);
+
+        let constant = RateSchedule::constant(100.0, Duration::from_secs(10));
+        assert_eq!(constant.to_string(), "hold 100 for 10s");
+        assert_eq!(constant.mean_rate(), 100.0);
+        assert_eq!(constant.scaled(0.5).mean_rate(), 50.0);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let cases = [
+            ("", "Schedule has no phases"),
+            ("# nothing\n", "Schedule has no phases"),
+            ("ramp 100 over 60s", "Invalid ramp: ramp 100 over 60s"),
+            ("ramp 100->x over 60s", "Invalid rate: x"),
+            ("hold -5 for 10s", "Invalid rate: -5"),
+            ("hold 5 for 0s", "Phase must not be empty: hold 5 for 0s"),
+            ("hold 5 over 10s", "Invalid schedule phase: hold 5 over 10s"),
+            ("jump 5 for 10s", "Invalid schedule phase: jump 5 for 10s"),
+            ("hold 5 for 10s, step 10", "Invalid schedule phase: step 10"),
+        ];
+        for (s, message) in cases.iter() {
+            assert_eq!(s.parse::<RateSchedule>().unwrap_err(), *message);
+        }
+        assert!("hold 5 for soon".parse::<RateSchedule>().is_err());
+    }
+
+    // Uniform arrivals from `t` until `end`
+    fn arrivals(schedule: &RateSchedule, t: f64, end: f64) -> Vec<f64> {
+        let mut times = Vec::new();
+        let mut next = schedule.advance(t, 0.0);
+        while let Some(time) = next.filter(|&time| time < end) {
+            times.push(time);
+            next = schedule.advance(time, 1.0);
+        }
+        times
+    }
+
+    #[test]
+    fn advances_over_ramps() {
+        // 100 more arrivals per second every second, 5000 over the ramp
+        let up = "ramp 0->1000 over 10s".parse::<RateSchedule>().unwrap();
+        let times = arrivals(&up, 0.0, 10.0);
+        assert_eq!(times.len(), 5000);
+        // The k-th arrival is where 50 t^2 reaches k
+        for &k in [1, 50, 1250, 4999].iter() {
+            let expected = (k as f64 / 50.0).sqrt();
+            assert!((times[k] - expected).abs() < 1e-6, "{}: {}", k, times[k]);
+        }
+        // Warm-up at the initial rate of zero sends nothing before the ramp
+        assert_eq!(arrivals(&up, -5.0, 10.0), times);
+
+        let down = "ramp 1000->0 over 10s".parse::<RateSchedule>().unwrap();
+        assert_eq!(arrivals(&down, 0.0, f64::INFINITY).len(), 5001);
+
+        let phases = "ramp 100->300 over 10s, hold 300 for 5s, step 50 for 4s"
+            .parse::<RateSchedule>()
+            .unwrap();
+        assert_eq!(arrivals(&phases, 0.0, 19.0).len(), 2000 + 1500 + 200);
+        // The final rate goes on after the schedule
+        assert_eq!(phases.advance(19.0, 100.0), Some(21.0));
+        assert_eq!(phases.advance(-1.0, 50.0), Some(-0.5));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::exec_info::ExecutionInfo;
+use crate::runner::{self, Result, RunConfig};
+use crate::schedule::RateSchedule;
+use crate::slo::{Check, Slo};
+
+use std::fmt;
+use std::str::FromStr;
+
+use log::*;
+use serde::{Serialize, Serializer};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchMode {
+    // Bisect between the lowest and highest rate until within one step
+    Binary,
+    // Raise the rate by one step until the SLO is missed
+    Step,
+}
+
+impl FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SearchMode, String> {
+        match s {
+            "binary" => Ok(SearchMode::Binary),
+            "step" => Ok(SearchMode::Step),
+            _ => Err(format!("Unknown search mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SearchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchMode::Binary => write!(f, "binary"),
+            SearchMode::Step => write!(f, "step"),
+        }
+    }
+}
+
+impl Serialize for SearchMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+pub struct SearchStep {
+    pub rate: f64,
+    pub exec_info: ExecutionInfo,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+pub struct SearchOptions {
+    pub mode: SearchMode,
+    pub slo: Slo,
+    pub min_rate: f64,
+    pub max_rate: f64,
+    pub step: f64,
+}
+
+// Highest rate of a step that met the SLO
+pub fn max_sustainable_rate(steps: &[SearchStep]) -> Option<f64> {
+    steps
+        .iter()
+        .filter(|step| step.passed)
+        .map(|step| step.rate)
+        .reduce(f64::max)
+}
+
+/// Runs `config` at constant rates chosen by `options.mode`, every run is judged
+/// against `options.slo`. Steps are returned in the order they were tried.
+pub fn search(config: &RunConfig, options: &SearchOptions) -> Result<Vec<SearchStep>> {
+    if options.min_rate <= 0.0 || options.max_rate < options.min_rate || options.step <= 0.0 {
+        return Err(format!(
+            "Invalid search range {} to {} with step {}",
+            options.min_rate, options.max_rate, options.step
+        )
+        .into());
+    }
+    let mut steps = Vec::new();
+    let try_rate = |rate: f64| -> Result<bool> {
+        let step_config = RunConfig {
+            schedule: RateSchedule::constant(rate, config.duration),
+            ..config.clone()
+        };
+        let exec_info = runner::run(&step_config)?;
+        let checks = options.slo.check(&exec_info, config.duration, rate);
+        let passed = checks.iter().all(|check| check.passed);
+        let actual = checks
+            .iter()
+            .map(|check| format!("{} = {}", check.condition, check.actual))
+            .collect::<Vec<_>>();
+        info!(
+            "Search step at {:.2} QPS {}: {}",
+            rate,
+            if passed { "passed" } else { "failed" },
+            actual.join(", ")
+        );
+        steps.push(SearchStep {
+            rate,
+            exec_info,
+            checks,
+            passed,
+        });
+        Ok(passed)
+    };
+
+    probe_rates(options, try_rate)?;
+    Ok(steps)
+}
+
+// Tries rates in the order of `options.mode` until the highest one passing is
+// known within a step, or the whole range passes or fails
+fn probe_rates(
+    options: &SearchOptions,
+    mut try_rate: impl FnMut(f64) -> Result<bool>,
+) -> Result<()> {
+    match options.mode {
+        SearchMode::Step => {
+            let mut rate = options.min_rate;
+            while rate <= options.max_rate && try_rate(rate)? {
+                rate += options.step;
+            }
+        }
+        SearchMode::Binary => {
+            if try_rate(options.min_rate)? && !try_rate(options.max_rate)? {
+                let (mut low, mut high) = (options.min_rate, options.max_rate);
+                while high - low > options.step {
+                    let mid = ((low + high) / 2.0).round();
+                    if mid <= low || mid >= high {
+                        break;
+                    }
+                    if try_rate(mid)? {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(mode: SearchMode, min_rate: f64, max_rate: f64, step: f64) -> SearchOptions {
+        SearchOptions {
+            mode,
+            slo: "p99 < 1s".parse().unwrap(),
+            min_rate,
+            max_rate,
+            step,
+        }
+    }
+
+    // Rates tried against an SLO that holds up to `limit`
+    fn probe(options: &SearchOptions, limit: f64) -> Vec<(f64, bool)> {
+        let mut tried = Vec::new();
+        probe_rates(options, |rate| {
+            tried.push((rate, rate <= limit));
+            Ok(rate <= limit)
+        })
+        .unwrap();
+        tried
+    }
+
+    fn bracket(tried: &[(f64, bool)]) -> (Option<f64>, Option<f64>) {
+        let highest_passed = tried
+            .iter()
+            .filter(|(_, passed)| *passed)
+            .map(|(rate, _)| *rate)
+            .reduce(f64::max);
+        let lowest_failed = tried
+            .iter()
+            .filter(|(_, passed)| !*passed)
+            .map(|(rate, _)| *rate)
+            .reduce(f64::min);
+        (highest_passed, lowest_failed)
+    }
+
+    #[test]
+    fn bisects_within_a_step() {
+        for &limit in [100.0, 101.0, 3456.0, 5050.0, 9999.0].iter() {
+            for &step in [1.0, 10.0, 100.0, 1000.0].iter() {
+                let tried = probe(&options(SearchMode::Binary, 100.0, 10000.0, step), limit);
+                let (passed, failed) = bracket(&tried);
+                let (passed, failed) = (passed.unwrap(), failed.unwrap());
+                assert!(passed <= limit && limit < failed, "{} {}", limit, step);
+                assert!(failed - passed <= step, "{} {}: {:?}", limit, step, tried);
+                assert_eq!(&tried[..2], &[(100.0, true), (10000.0, false)]);
+            }
+        }
+    }
+
+    #[test]
+    fn steps_up_to_the_first_failure() {
+        let tried = probe(&options(SearchMode::Step, 100.0, 10000.0, 100.0), 350.0);
+        assert_eq!(
+            tried,
+            vec![(100.0, true), (200.0, true), (300.0, true), (400.0, false)]
+        );
+    }
+
+    #[test]
+    fn stops_when_the_minimum_fails() {
+        for &mode in [SearchMode::Binary, SearchMode::Step].iter() {
+            let tried = probe(&options(mode, 100.0, 10000.0, 100.0), 50.0);
+            assert_eq!(tried, vec![(100.0, false)], "{}", mode);
+        }
+    }
+
+    #[test]
+    fn stops_when_the_maximum_passes() {
+        let tried = probe(&options(SearchMode::Binary, 100.0, 10000.0, 100.0), 20000.0);
+        assert_eq!(tried, vec![(100.0, true), (10000.0, true)]);
+        let tried = probe(&options(SearchMode::Step, 100.0, 1000.0, 100.0), 20000.0);
+        assert_eq!(tried.len(), 10);
+        assert!(tried.iter().all(|&(rate, passed)| passed && rate <= 1000.0));
+    }
+}
@@ -0,0 +1,442 @@
+use crate::exec_info::ExecutionInfo;
+use crate::report::format_latency;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    Percentile(f64),
+    Mean,
+    Max,
+    ErrorRate,
+    Rps,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// One `<metric> <op> <value>` condition, e.g. `p99 < 20ms`, `errors < 0.1%` or
+/// `rps >= 0.95*target`.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    text: String,
+    metric: Metric,
+    op: Op,
+    // Microseconds for latencies, a fraction for error rates
+    threshold: f64,
+    // The threshold is a multiple of the target rate
+    per_target: bool,
+}
+
+/// Conditions that must all hold, joined by `and` or commas.
+#[derive(Clone, Debug)]
+pub struct Slo {
+    conditions: Vec<Condition>,
+}
+
+pub struct Check {
+    pub condition: String,
+    pub actual: String,
+    pub passed: bool,
+}
+
+// Non-success responses, connection errors, timeouts and requests never sent,
+// relative to everything that was attempted
+pub fn error_rate(exec_info: &ExecutionInfo) -> f64 {
+    let errors = exec_info.failure_count + exec_info.error_count() + exec_info.dropped_count;
+    let total = exec_info.success_count + errors;
+    if total == 0 {
+        0.0
+    } else {
+        errors as f64 / total as f64
+    }
+}
+
+// A fraction given as `0.001` or `0.1%`
+pub fn parse_fraction(s: &str) -> Option<f64> {
+    match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok().map(|p| p / 100.0),
+        None => s.parse::<f64>().ok(),
+    }
+}
+
+impl Metric {
+    fn parse(s: &str) -> Result<Metric, String> {
+        match s {
+            "mean" => Ok(Metric::Mean),
+            "max" => Ok(Metric::Max),
+            "errors" | "error_rate" => Ok(Metric::ErrorRate),
+            "rps" => Ok(Metric::Rps),
+            _ => match s.strip_prefix('p').map(str::parse::<f64>) {
+                Some(Ok(percentile)) if (0.0..=100.0).contains(&percentile) => {
+                    Ok(Metric::Percentile(percentile))
+                }
+                _ => Err(format!("Unknown metric: {}", s)),
+            },
+        }
+    }
+
+    fn parse_value(&self, s: &str) -> Result<f64, String> {
+        let invalid = || format!("Invalid value for {}: {}", self, s);
+        match self {
+            Metric::Percentile(_) | Metric::Mean | Metric::Max => humantime::parse_duration(s)
+                .map(|d| d.as_micros() as f64)
+                .map_err(|_| invalid()),
+            Metric::ErrorRate => parse_fraction(s).ok_or_else(invalid),
+            Metric::Rps => s.parse::<f64>().map_err(|_| invalid()),
+        }
+    }
+
+    fn value(&self, exec_info: &ExecutionInfo, duration: Duration) -> f64 {
+        let hist = &exec_info.latency_hist;
+        match *self {
+            Metric::Percentile(percentile) => hist.value_at_percentile(percentile) as f64,
+            Metric::Mean => hist.mean(),
+            Metric::Max => hist.max() as f64,
+            Metric::ErrorRate => error_rate(exec_info),
+            Metric::Rps => {
+                (exec_info.success_count + exec_info.failure_count) as f64 / duration.as_secs_f64()
+            }
+        }
+    }
+
+    fn format_value(&self, value: f64) -> String {
+        match self {
+            Metric::Percentile(_) | Metric::Mean | Metric::Max => {
+                format_latency(value as u64).trim().to_string()
+            }
+            Metric::ErrorRate => format!("{:.3}%", value * 100.0),
+            Metric::Rps => format!("{:.2}", value),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Metric::Percentile(percentile) => write!(f, "p{}", percentile),
+            Metric::Mean => write!(f, "mean"),
+            Metric::Max => write!(f, "max"),
+            Metric::ErrorRate => write!(f, "errors"),
+            Metric::Rps => write!(f, "rps"),
+        }
+    }
+}
+
+impl Condition {
+    pub fn uses_target(&self) -> bool {
+        self.per_target
+    }
+
+    // `target_rate` is the scheduled rate `target` refers to
+    pub fn check(&self, exec_info: &ExecutionInfo, duration: Duration, target_rate: f64) -> Check {
+        let actual = self.metric.value(exec_info, duration);
+        let threshold = if self.per_target {
+            self.threshold * target_rate
+        } else {
+            self.threshold
+        };
+        let passed = match self.op {
+            Op::Lt => actual < threshold,
+            Op::Le => actual <= threshold,
+            Op::Gt => actual > threshold,
+            Op::Ge => actual >= threshold,
+        };
+        Check {
+            condition: self.text.clone(),
+            actual: self.metric.format_value(actual),
+            passed,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        let s = s.trim();
+        // Two-character operators first so that `<=` is not read as `<`
+        let (pos, op, len) = [("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)]
+            .iter()
+            .find_map(|&(token, op)| s.find(token).map(|pos| (pos, op, token.len())))
+            .ok_or_else(|| format!("Missing comparison in condition: {}", s))?;
+        let metric = Metric::parse(s[..pos].trim())?;
+        let value = s[pos + len..].trim();
+        let (threshold, per_target) = match value.strip_suffix("target") {
+            Some(factor) if metric == Metric::Rps => {
+                let factor = factor.trim_end();
+                match factor.strip_suffix('*') {
+                    Some(factor) => (metric.parse_value(factor.trim())?, true),
+                    None if factor.is_empty() => (1.0, true),
+                    None => return Err(format!("Invalid value for {}: {}", metric, value)),
+                }
+            }
+            _ => (metric.parse_value(value)?, false),
+        };
+        Ok(Self {
+            text: s.split_whitespace().collect::<Vec<_>>().join(" "),
+            metric,
+            op,
+            threshold,
+            per_target,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Slo {
+    pub fn check(
+        &self,
+        exec_info: &ExecutionInfo,
+        duration: Duration,
+        target_rate: f64,
+    ) -> Vec<Check> {
+        self.conditions
+            .iter()
+            .map(|condition| condition.check(exec_info, duration, target_rate))
+            .collect()
+    }
+}
+
+impl FromStr for Slo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Slo, String> {
+        let conditions = s
+            .split(',')
+            .flat_map(|part| part.split(" and "))
+            .filter(|condition| !condition.trim().is_empty())
+            .map(str::parse::<Condition>)
+            .collect::<Result<Vec<_>, _>>()?;
+        if conditions.is_empty() {
+            return Err("SLO has no conditions".to_string());
+        }
+        Ok(Self { conditions })
+    }
+}
+
+impl fmt::Display for Slo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|condition| condition.text.as_str())
+            .collect::<Vec<_>>();
+        write!(f, "{}", conditions.join(" and "))
+    }
+}
+
+impl Serialize for Slo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_conditions() {
+        let cases = [
+            (
+                "p99 < 20ms",
+                Metric::Percentile(99.0),
+                Op::Lt,
+                20_000.0,
+                false,
+                "p99 < 20ms",
+            ),
+            (
+                "p99.9<=1s",
+                Metric::Percentile(99.9),
+                Op::Le,
+                1e6,
+                false,
+                "p99.9<=1s",
+            ),
+            (
+                "  mean   >  500us ",
+                Metric::Mean,
+                Op::Gt,
+                500.0,
+                false,
+                "mean > 500us",
+            ),
+            ("max >= 2s", Metric::Max, Op::Ge, 2e6, false, "max >= 2s"),
+            (
+                "errors < 0.1%",
+                Metric::ErrorRate,
+                Op::Lt,
+                0.001,
+                false,
+                "errors < 0.1%",
+            ),
+            (
+                "error_rate <= 0.01",
+                Metric::ErrorRate,
+                Op::Le,
+                0.01,
+                false,
+                "error_rate <= 0.01",
+            ),
+            (
+                "rps > 1000",
+                Metric::Rps,
+                Op::Gt,
+                1000.0,
+                false,
+                "rps > 1000",
+            ),
+            (
+                "rps >= 0.95*target",
+                Metric::Rps,
+                Op::Ge,
+                0.95,
+                true,
+                "rps >= 0.95*target",
+            ),
+            (
+                "rps >= 0.95 * target",
+                Metric::Rps,
+                Op::Ge,
+                0.95,
+                true,
+                "rps >= 0.95 * target",
+            ),
+            (
+                "rps > target",
+                Metric::Rps,
+                Op::Gt,
+                1.0,
+                true,
+                "rps > target",
+            ),
+        ];
+        for &(s, metric, op, threshold, per_target, text) in cases.iter() {
+            let condition = s.parse::<Condition>().unwrap();
+            assert_eq!(condition.metric, metric, "{}", s);
+            assert_eq!(condition.op, op, "{}", s);
+            assert!((condition.threshold - threshold).abs() < 1e-9, "{}", s);
+            assert_eq!(condition.uses_target(), per_target, "{}", s);
+            assert_eq!(condition.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        let cases = [
+            ("p99 20ms", "Missing comparison in condition: p99 20ms"),
+            ("latency < 1ms", "Unknown metric: latency"),
+            ("p101 < 1ms", "Unknown metric: p101"),
+            ("p99 < 20", "Invalid value for p99: 20"),
+            ("errors < lots", "Invalid value for errors: lots"),
+            ("mean < target", "Invalid value for mean: target"),
+            ("rps >= 0.95target", "Invalid value for rps: 0.95target"),
+            ("rps >= x*target", "Invalid value for rps: x"),
+        ];
+        for (s, message) in cases.iter() {
+            assert_eq!(s.parse::<Condition>().unwrap_err(), *message);
+        }
+    }
+
+    #[test]
+    fn parses_slos() {
+        let slo = "p99 < 20ms and errors < 0.1%, rps >= 0.95*target,"
+            .parse::<Slo>()
+            .unwrap();
+        assert_eq!(slo.conditions.len(), 3);
+        assert_eq!(
+            slo.to_string(),
+            "p99 < 20ms and errors < 0.1% and rps >= 0.95*target"
+        );
+        assert_eq!(
+            slo.to_string().parse::<Slo>().unwrap().to_string(),
+            slo.to_string()
+        );
+        assert_eq!("".parse::<Slo>().unwrap_err(), "SLO has no conditions");
+        assert_eq!(" , ".parse::<Slo>().unwrap_err(), "SLO has no conditions");
+        assert!("p99 < 20ms and p99".parse::<Slo>().is_err());
+    }
+
+    // 1ms to 100ms, 95 successes and 5 failures over 10s
+    fn exec_info() -> ExecutionInfo {
+        let mut exec_info = ExecutionInfo::new(1_000_000);
+        for ms in 1..=100 {
+            exec_info.latency_hist.record(ms * 1000).unwrap();
+        }
+        exec_info.success_count = 95;
+        exec_info.failure_count = 5;
+        exec_info
+    }
+
+    #[test]
+    fn checks_conditions() {
+        let exec_info = exec_info();
+        let duration = Duration::from_secs(10);
+        let cases = [
+            ("p99 < 99.5ms", true),
+            ("p99 < 98ms", false),
+            ("p50 > 49ms", true),
+            ("mean < 51ms", true),
+            ("mean < 50ms", false),
+            ("max >= 100ms", true),
+            ("max > 101ms", false),
+            ("errors <= 5%", true),
+            ("errors < 5%", false),
+            ("errors < 0.06", true),
+            ("rps >= 10", true),
+            ("rps > 10", false),
+            ("rps >= 0.9*target", true),
+            ("rps >= target", false),
+        ];
+        for &(s, passed) in cases.iter() {
+            let check = s
+                .parse::<Condition>()
+                .unwrap()
+                .check(&exec_info, duration, 11.0);
+            assert_eq!(check.passed, passed, "{} with {}", s, check.actual);
+            assert_eq!(check.condition, s);
+        }
+        let slo = "errors <= 5% and rps > 10".parse::<Slo>().unwrap();
+        let checks = slo.check(&exec_info, duration, 11.0);
+        let actual = checks
+            .iter()
+            .map(|check| (check.actual.as_str(), check.passed))
+            .collect::<Vec<_>>();
+        assert_eq!(actual, vec![("5.000%", true), ("10.00", false)]);
+    }
+
+    #[test]
+    fn counts_every_error() {
+        let mut exec_info = exec_info();
+        exec_info.timeout_count = 3;
+        exec_info.connect_error_count = 1;
+        exec_info.dropped_count = 1;
+        assert!((error_rate(&exec_info) - 10.0 / 105.0).abs() < 1e-12);
+        assert_eq!(error_rate(&ExecutionInfo::new(1000)), 0.0);
+        assert_eq!(parse_fraction("0.1%"), Some(0.001));
+        assert_eq!(parse_fraction("0.25"), Some(0.25));
+        assert_eq!(parse_fraction("x%"), None);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::exec_info::ConnError;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Trace files are a zstd stream of this header followed by fixed-size records
+const MAGIC: &[u8; 8] = b"FLOODTR\0";
+const VERSION: u32 = 1;
+const RECORD_SIZE: usize = 55;
+
+// Records are sent to the writer thread in batches of this size
+const BATCH_SIZE: usize = 1024;
+const MAX_PENDING_BATCHES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceError {
+    None,
+    Timeout,
+    Connect,
+    Reset,
+    ReadClosed,
+    WriteClosed,
+    Parse,
+    Other,
+}
+
+impl TraceError {
+    fn from_u8(value: u8) -> Option<TraceError> {
+        Some(match value {
+            0 => TraceError::None,
+            1 => TraceError::Timeout,
+            2 => TraceError::Connect,
+            3 => TraceError::Reset,
+            4 => TraceError::ReadClosed,
+            5 => TraceError::WriteClosed,
+            6 => TraceError::Parse,
+            7 => TraceError::Other,
+            _ => return None,
+        })
+    }
+}
+
+impl From<ConnError> for TraceError {
+    fn from(kind: ConnError) -> TraceError {
+        match kind {
+            ConnError::Connect => TraceError::Connect,
+            ConnError::Reset => TraceError::Reset,
+            ConnError::ReadClosed => TraceError::ReadClosed,
+            ConnError::WriteClosed => TraceError::WriteClosed,
+            ConnError::Other => TraceError::Other,
+        }
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::None => write!(f, "none"),
+            TraceError::Timeout => write!(f, "timeout"),
+            TraceError::Connect => write!(f, "connect"),
+            TraceError::Reset => write!(f, "reset"),
+            TraceError::ReadClosed => write!(f, "read_closed"),
+            TraceError::WriteClosed => write!(f, "write_closed"),
+            TraceError::Parse => write!(f, "parse"),
+            TraceError::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// One request. Times are microseconds since the end of warm-up of the event
+/// loop that sent it, status is 0 if no response was received.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub req_type: u32,
+    pub conn_id: u64,
+    pub scheduled: u64,
+    pub start: u64,
+    pub finish: u64,
+    pub status: u16,
+    pub error: TraceError,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+}
+
+impl TraceRecord {
+    // Measured from the scheduled send time, like the latencies of the report
+    pub fn latency(&self) -> u64 {
+        self.finish.saturating_sub(self.scheduled)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.req_type.to_le_bytes());
+        buf.extend_from_slice(&self.conn_id.to_le_bytes());
+        buf.extend_from_slice(&self.scheduled.to_le_bytes());
+        buf.extend_from_slice(&self.start.to_le_bytes());
+        buf.extend_from_slice(&self.finish.to_le_bytes());
+        buf.extend_from_slice(&self.status.to_le_bytes());
+        buf.push(self.error as u8);
+        buf.extend_from_slice(&self.bytes_sent.to_le_bytes());
+        buf.extend_from_slice(&self.bytes_recv.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8; RECORD_SIZE]) -> io::Result<TraceRecord> {
+        let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+        Ok(Self {
+            req_type: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            conn_id: u64_at(4),
+            scheduled: u64_at(12),
+            start: u64_at(20),
+            finish: u64_at(28),
+            status: u16::from_le_bytes(buf[36..38].try_into().unwrap()),
+            error: TraceError::from_u8(buf[38]).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "Invalid error kind in trace")
+            })?,
+            bytes_sent: u64_at(39),
+            bytes_recv: u64_at(47),
+        })
+    }
+}
+
+/// Writes trace records to a file on a background thread.
+pub struct TraceWriter {
+    sender: SyncSender<Vec<TraceRecord>>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+/// Handle to the writer that can be shared with event loops.
+#[derive(Clone)]
+pub struct TraceSender(SyncSender<Vec<TraceRecord>>);
+
+/// Batches records of one event loop for the writer thread. Batches are
+/// dropped rather than blocking the event loop when the writer falls behind.
+pub struct TraceSink {
+    sender: SyncSender<Vec<TraceRecord>>,
+    batch: Vec<TraceRecord>,
+    dropped: u64,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str) -> io::Result<TraceWriter> {
+        let f = File::create(path)?;
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_BATCHES);
+        let thread = thread::spawn(move || write_records(f, receiver));
+        Ok(Self { sender, thread })
+    }
+
+    pub fn sender(&self) -> TraceSender {
+        TraceSender(self.sender.clone())
+    }
+
+    // Waits for every sender and sink to be dropped, then completes the file
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("Trace writer thread panicked")),
+        }
+    }
+}
+
+fn write_records(f: File, receiver: Receiver<Vec<TraceRecord>>) -> io::Result<()> {
+    let mut encoder = zstd::stream::Encoder::new(
+        BufWriter::with_capacity(1024 * 1024, f),
+        /* level= */ 0,
+    )?;
+    let start_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    encoder.write_all(MAGIC)?;
+    encoder.write_all(&VERSION.to_le_bytes())?;
+    encoder.write_all(&start_time.to_le_bytes())?;
+    let mut buf = Vec::with_capacity(BATCH_SIZE * RECORD_SIZE);
+    for batch in receiver {
+        buf.clear();
+        for record in batch.iter() {
+            record.encode(&mut buf);
+        }
+        encoder.write_all(&buf)?;
+    }
+    encoder.finish()?.flush()
+}
+
+impl TraceSender {
+    pub fn sink(&self) -> TraceSink {
+        TraceSink {
+            sender: self.0.clone(),
+            batch: Vec::with_capacity(BATCH_SIZE),
+            dropped: 0,
+        }
+    }
+}
+
+impl TraceSink {
+    pub fn push(&mut self, record: TraceRecord) {
+        self.batch.push(record);
+        if self.batch.len() >= BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        match self.sender.try_send(batch) {
+            Ok(()) => {}
+            Err(TrySendError::Full(batch)) | Err(TrySendError::Disconnected(batch)) => {
+                self.dropped += batch.len() as u64;
+            }
+        }
+    }
+
+    // Records lost because the writer could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Reads back a trace file record by record.
+pub struct TraceReader {
+    decoder: zstd::stream::Decoder<'static, BufReader<File>>,
+    // Wall clock time the trace was started at, microseconds since the epoch
+    pub start_time: u64,
+}
+
+impl TraceReader {
+    pub fn open(path: &str) -> io::Result<TraceReader> {
+        let mut decoder = zstd::stream::Decoder::new(File::open(path)?)?;
+        let mut header = [0; 20];
+        decoder.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a flood trace"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported trace version {}", version),
+            ));
+        }
+        Ok(Self {
+            decoder,
+            start_time: u64::from_le_bytes(header[12..20].try_into().unwrap()),
+        })
+    }
+
+    // Whether `path` starts like a trace file, without reading it through
+    pub fn is_trace(path: &str) -> bool {
+        let mut header = [0; 8];
+        File::open(path)
+            .and_then(zstd::stream::Decoder::new)
+            .and_then(|mut decoder| decoder.read_exact(&mut header))
+            .is_ok()
+            && &header == MAGIC
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<io::Result<TraceRecord>> {
+        let mut buf = [0; RECORD_SIZE];
+        let mut filled = 0;
+        while filled < RECORD_SIZE {
+            match self.decoder.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0) => {
+                    return Some(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Truncated trace record",
+                    )))
+                }
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        Some(TraceRecord::decode(&buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("flood-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn record(i: u64) -> TraceRecord {
+        let errors = [
+            TraceError::None,
+            TraceError::Timeout,
+            TraceError::Connect,
+            TraceError::Reset,
+            TraceError::ReadClosed,
+            TraceError::WriteClosed,
+            TraceError::Parse,
+            TraceError::Other,
+        ];
+        TraceRecord {
+            req_type: (i % 3) as u32,
+            conn_id: i / 7,
+            scheduled: i * 1000,
+            start: i * 1000 + 3,
+            finish: if i == 0 { u64::MAX } else { i * 1000 + 250 },
+            status: if i % 8 == 1 { 0 } else { 200 },
+            error: errors[i as usize % errors.len()],
+            bytes_sent: i * 11,
+            bytes_recv: u64::MAX - i,
+        }
+    }
+
+    #[test]
+    fn reads_back_written_records() {
+        let path = temp_path("roundtrip.trace");
+        let writer = TraceWriter::create(&path).unwrap();
+        let mut sink = writer.sender().sink();
+        // More than a batch, so records span several of them
+        let count = BATCH_SIZE as u64 * 2 + 5;
+        for i in 0..count {
+            sink.push(record(i));
+        }
+        sink.flush();
+        assert_eq!(sink.dropped(), 0);
+        drop(sink);
+        writer.finish().unwrap();
+
+        assert!(TraceReader::is_trace(&path));
+        let reader = TraceReader::open(&path).unwrap();
+        assert!(reader.start_time > 0);
+        let records = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len() as u64, count);
+        for (i, read) in records.iter().enumerate() {
+            let written = record(i as u64);
+            assert_eq!(read.req_type, written.req_type);
+            assert_eq!(read.conn_id, written.conn_id);
+            assert_eq!(read.scheduled, written.scheduled);
+            assert_eq!(read.start, written.start);
+            assert_eq!(read.finish, written.finish);
+            assert_eq!(read.status, written.status);
+            assert_eq!(read.error, written.error);
+            assert_eq!(read.bytes_sent, written.bytes_sent);
+            assert_eq!(read.bytes_recv, written.bytes_recv);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("other.trace");
+        fs::write(
+            &path,
+            zstd::encode_all(&b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..], 0).unwrap(),
+        )
+        .unwrap();
+        assert!(!TraceReader::is_trace(&path));
+        let err = TraceReader::open(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), "Not a flood trace");
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let path = temp_path("truncated.trace");
+        let mut content = Vec::new();
+        content.extend_from_slice(MAGIC);
+        content.extend_from_slice(&VERSION.to_le_bytes());
+        content.extend_from_slice(&0u64.to_le_bytes());
+        record(1).encode(&mut content);
+        content.extend_from_slice(&[0; RECORD_SIZE - 1]);
+        fs::write(&path, zstd::encode_all(&content[..], 0).unwrap()).unwrap();
+        let mut reader = TraceReader::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().bytes_sent, 11);
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}